@@ -0,0 +1,68 @@
+//! Measures how quickly a command's output reaches a client through the daemon.
+//!
+//! Runs a command writing the given number of megabytes (256 by default) and reports the rate at
+//! which its output arrived, and in how many notices:
+//!
+//! ```sh
+//! cargo run --release --example throughput -- 512
+//! ```
+//!
+//! Like any client, it starts weaverd if it isn't running, so build that in release mode too.
+
+extern crate futures;
+extern crate tokio;
+extern crate weaver;
+
+use futures::Future;
+use std::env;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Instant;
+
+use weaver::{ServerNotice, WeaverClient, WeaverNotification, WeaverPaths};
+
+fn main() {
+    let megabytes: usize = env::args()
+        .nth(1)
+        .map(|arg| arg.parse().expect("Usage: throughput [megabytes]"))
+        .unwrap_or(256);
+    let (notifications, received) = channel();
+    let paths = WeaverPaths::resolve().expect("Failed to find weaver's files");
+    let client = WeaverClient::new("benchmark", paths, notifications);
+    let state = client.state.clone();
+    thread::spawn(move || tokio::run(client.map_err(|e| panic!("{:?}", e))));
+
+    let cmd = format!("yes 'weaver throughput benchmark' | head -c {}M", megabytes);
+    let start = Instant::now();
+    let started = state.write().unwrap().run_command(cmd);
+    let id = started.wait().expect("Failed to run the command");
+
+    let mut bytes = 0;
+    let mut notices = 0;
+    for notification in received {
+        match notification {
+            WeaverNotification::Server(msg) => match msg.notice {
+                ServerNotice::CommandOutput(i, ref output) if i == id => {
+                    bytes += output.len();
+                    notices += 1;
+                }
+                ServerNotice::CommandCompleted(i, _, _, _) if i == id => break,
+                _ => {}
+            },
+            WeaverNotification::Disconnected(reason) => panic!("{}", reason),
+            _ => {}
+        }
+    }
+
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    let mb = bytes as f64 / (1024.0 * 1024.0);
+    println!(
+        "{:.1} MB in {:.2}s, {:.1} MB/s, in {} notices of {} bytes on average",
+        mb,
+        seconds,
+        mb / seconds,
+        notices,
+        bytes / notices.max(1)
+    );
+}
@@ -0,0 +1,127 @@
+//! The command line of either binary.
+
+use super::socket::SOCKET_ENV;
+
+use std::env;
+
+/// What was given on a command line.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// Where the daemon listens, if given with `--socket`.
+    pub socket: Option<String>,
+    /// The flags given, each like `--output=12` if it takes a value.
+    pub flags: Vec<String>,
+}
+
+impl Args {
+    /// Passes on any socket path through `$WEAVER_SOCKET`, so that it also reaches clients run
+    /// from the daemon's commands.
+    pub fn export_socket(&self) {
+        if let Some(ref path) = self.socket {
+            env::set_var(SOCKET_ENV, path);
+        }
+    }
+
+    pub fn given(&self, flag: &str) -> bool {
+        self.flags.iter().any(|given| given == flag)
+    }
+}
+
+/// Parses the arguments to `program`, which may set the socket path and any of `flags`, or
+/// returns its usage if they can't be.
+///
+/// A flag that takes a value is listed like `--output ID`, and returned like `--output=12`.
+pub fn parse<I>(program: &str, flags: &[&str], args: I) -> Result<Args, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let takes_value = |name: &str| {
+        flags
+            .iter()
+            .any(|flag| flag.starts_with(name) && flag[name.len()..].starts_with(' '))
+    };
+    let usage = || {
+        let flags: Vec<String> = flags.iter().map(|flag| format!(" [{}]", flag)).collect();
+        format!("Usage: {} [--socket PATH]{}", program, flags.concat())
+    };
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let path = match arg.as_str() {
+            "--socket" => args.next(),
+            _ if arg.starts_with("--socket=") => Some(arg["--socket=".len()..].to_owned()),
+            _ if flags.contains(&arg.as_str()) => {
+                parsed.flags.push(arg);
+                continue;
+            }
+            _ if takes_value(arg.split('=').next().unwrap()) => {
+                if arg.contains('=') {
+                    parsed.flags.push(arg);
+                    continue;
+                }
+                match args.next() {
+                    Some(value) => {
+                        parsed.flags.push(format!("{}={}", arg, value));
+                        continue;
+                    }
+                    None => None,
+                }
+            }
+            _ => None,
+        };
+        match path {
+            Some(path) => parsed.socket = Some(path),
+            None => return Err(usage()),
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS: &[&str] = &["--detach", "--output ID"];
+
+    fn parse_line(line: &str) -> Result<Args, String> {
+        parse(
+            "weaverc",
+            FLAGS,
+            line.split_whitespace().map(|arg| arg.to_owned()),
+        )
+    }
+
+    #[test]
+    fn flags_and_values() {
+        let args = parse_line("--detach --output 12").unwrap();
+        assert_eq!(args.flags, vec!["--detach", "--output=12"]);
+        assert!(args.given("--detach"));
+        assert_eq!(args.socket, None);
+        let args = parse_line("--output=3").unwrap();
+        assert_eq!(args.flags, vec!["--output=3"]);
+        assert!(!args.given("--detach"));
+    }
+
+    #[test]
+    fn socket_paths() {
+        assert_eq!(
+            parse_line("--socket /tmp/a").unwrap().socket,
+            Some("/tmp/a".to_owned())
+        );
+        assert_eq!(
+            parse_line("--socket=/tmp/a --socket /tmp/b")
+                .unwrap()
+                .socket,
+            Some("/tmp/b".to_owned())
+        );
+    }
+
+    #[test]
+    fn anything_else_gets_the_usage() {
+        let usage = "Usage: weaverc [--socket PATH] [--detach] [--output ID]".to_owned();
+        assert_eq!(parse_line("--verbose"), Err(usage.clone()));
+        assert_eq!(parse_line("--output"), Err(usage.clone()));
+        assert_eq!(parse_line("--socket"), Err(usage.clone()));
+        assert_eq!(parse_line("--detach=1"), Err(usage));
+    }
+}
@@ -0,0 +1,161 @@
+//! Batching of command output, so that a chatty command sends fewer, bigger notices.
+//!
+//! The daemon holds output back until there is enough of it, or it has waited long enough, and
+//! reads bigger chunks at a time from a command that keeps filling them.
+
+use super::config::Tuning;
+use super::encoding::Utf8Decoder;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+
+/// Output read but not yet sent, in the order it was read from each source `S`.
+#[derive(Debug)]
+pub struct Batches<S> {
+    batches: Vec<(S, Vec<u8>)>,
+    len: usize,
+    /// Holds back the start of a character split between one batch and the next, for each source.
+    decoders: HashMap<S, Utf8Decoder>,
+}
+
+impl<S: Copy + Eq + Hash> Default for Batches<S> {
+    fn default() -> Self {
+        Batches {
+            batches: Vec::new(),
+            len: 0,
+            decoders: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Copy + Eq + Hash> Batches<S> {
+    /// Bytes held back.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds output read from `source`, to the last batch if that was read from there too.
+    pub fn push(&mut self, source: S, bytes: &[u8]) {
+        match self.batches.last_mut() {
+            Some(&mut (last, ref mut batch)) if last == source => batch.extend_from_slice(bytes),
+            _ => self.batches.push((source, bytes.to_vec())),
+        }
+        self.len += bytes.len();
+    }
+
+    /// Takes the output held back, a run for each time it switched source, less any character
+    /// left unfinished at the end of a source's output.
+    pub fn take(&mut self) -> Vec<(S, Vec<u8>)> {
+        self.len = 0;
+        let mut runs = vec![];
+        for (source, bytes) in mem::take(&mut self.batches) {
+            let bytes = self.decoders.entry(source).or_default().push(bytes);
+            if !bytes.is_empty() {
+                runs.push((source, bytes));
+            }
+        }
+        runs
+    }
+
+    /// Takes the last of the output, including anything that ended partway through a character.
+    pub fn finish(&mut self) -> Vec<(S, Vec<u8>)> {
+        let mut runs = self.take();
+        for (source, mut decoder) in mem::take(&mut self.decoders) {
+            let bytes = decoder.finish();
+            if !bytes.is_empty() {
+                runs.push((source, bytes));
+            }
+        }
+        runs
+    }
+}
+
+/// The size of buffer to read into next, after reading `read` bytes into one of `len`: bigger
+/// while chunks come full, and smaller once they don't.
+pub fn next_chunk_size(len: usize, read: usize, tuning: &Tuning) -> usize {
+    let max = tuning.max_chunk_size.max(tuning.chunk_size);
+    if read == len && len < max {
+        (len * 2).min(max)
+    } else if read < len / 4 && len > tuning.chunk_size {
+        (len / 2).max(tuning.chunk_size)
+    } else {
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    enum Source {
+        Out,
+        Err,
+    }
+
+    fn run(source: Source, bytes: &str) -> (Source, Vec<u8>) {
+        (source, bytes.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn runs_from_one_source_are_joined() {
+        let mut batches = Batches::default();
+        batches.push(Source::Out, b"a");
+        batches.push(Source::Out, b"b");
+        batches.push(Source::Err, b"c");
+        batches.push(Source::Out, b"d");
+        assert_eq!(batches.len(), 4);
+        assert_eq!(
+            batches.take(),
+            vec![
+                run(Source::Out, "ab"),
+                run(Source::Err, "c"),
+                run(Source::Out, "d"),
+            ]
+        );
+        assert!(batches.is_empty());
+        assert_eq!(batches.take(), vec![]);
+    }
+
+    #[test]
+    fn characters_are_never_split_between_batches() {
+        let mut batches = Batches::default();
+        let snowman = "☃".as_bytes();
+        batches.push(Source::Out, &snowman[..1]);
+        batches.push(Source::Err, b"e");
+        assert_eq!(batches.take(), vec![run(Source::Err, "e")]);
+        batches.push(Source::Out, &snowman[1..]);
+        assert_eq!(batches.take(), vec![run(Source::Out, "☃")]);
+    }
+
+    #[test]
+    fn finishing_flushes_unfinished_characters() {
+        let mut batches = Batches::default();
+        batches.push(Source::Out, b"a\xe2\x98");
+        assert_eq!(
+            batches.finish(),
+            vec![run(Source::Out, "a"), (Source::Out, b"\xe2\x98".to_vec())]
+        );
+        assert_eq!(batches.finish(), vec![]);
+    }
+
+    #[test]
+    fn chunks_grow_while_full_and_shrink_when_not() {
+        let tuning = Tuning {
+            chunk_size: 4096,
+            max_chunk_size: 16384,
+            ..Tuning::default()
+        };
+        assert_eq!(next_chunk_size(4096, 4096, &tuning), 8192);
+        assert_eq!(next_chunk_size(8192, 8192, &tuning), 16384);
+        assert_eq!(next_chunk_size(16384, 16384, &tuning), 16384);
+        assert_eq!(next_chunk_size(16384, 8000, &tuning), 16384);
+        assert_eq!(next_chunk_size(16384, 100, &tuning), 8192);
+        assert_eq!(next_chunk_size(4096, 1, &tuning), 4096);
+    }
+}
@@ -13,21 +13,85 @@ use text_ui::widget::Widget;
 use text_ui::widget::{shared, Line, Linear, Readline, Shared, Text};
 use text_ui::{text_to_lines, Event, Input, Key, Position, Size};
 
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use std::sync::mpsc::channel;
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, SystemTime};
 
 use tokio::prelude::Future;
-use weaver::{WeaverClient, WeaverCommand, WeaverNotification, WeaverState};
+use weaver::args;
+use weaver::terminal::Terminal;
+use weaver::{
+    CommandId, ExitStatus, OutputStream, RunOptions, Signal, StdinState, Truncation, WeaverClient,
+    WeaverCommand, WeaverNotification, WeaverPaths, WeaverState, WindowSize, OUTPUT_STREAMS,
+};
+
+/// Which of a command's output to show.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputView {
+    /// Both streams in the order they were written.
+    Interleaved,
+    Stdout,
+    Stderr,
+}
+
+impl OutputView {
+    fn next(self) -> Self {
+        match self {
+            OutputView::Interleaved => OutputView::Stdout,
+            OutputView::Stdout => OutputView::Stderr,
+            OutputView::Stderr => OutputView::Interleaved,
+        }
+    }
+}
+
+/// A pty command's screen, along with where in its output that starts and what was discarded,
+/// which would mean starting again if either changed.
+type CachedTerminal = ((usize, Option<Truncation>), Terminal);
 
 struct WeaverStateWidget {
     state: Shared<WeaverState>,
     selected: Option<usize>,
+    view: OutputView,
+    terminals: RwLock<HashMap<CommandId, CachedTerminal>>,
+    /// The size of terminal that fits where commands' screens were last shown.
+    terminal_size: RwLock<Option<WindowSize>>,
 }
 
 impl WeaverStateWidget {
     pub fn new(state: Shared<WeaverState>) -> Self {
         let selected = None;
-        WeaverStateWidget { state, selected }
+        let view = OutputView::Interleaved;
+        WeaverStateWidget {
+            state,
+            selected,
+            view,
+            terminals: RwLock::new(HashMap::new()),
+            terminal_size: RwLock::new(None),
+        }
+    }
+
+    /// The lines on a pty command's screen, bringing it up to date with the command's output.
+    fn screen_lines(&self, state: &WeaverState, id: CommandId, cmd: &WeaverCommand) -> Vec<String> {
+        let size = cmd.pty.unwrap_or_default();
+        let start = state
+            .loaded_output(id, OutputStream::Pty)
+            .map_or(0, |range| range.start);
+        let key = (start, cmd.truncation(OutputStream::Pty));
+        let mut terminals = self.terminals.write().unwrap();
+        let cached = terminals
+            .entry(id)
+            .or_insert_with(|| (key, Terminal::new(size)));
+        if cached.0 != key || cached.1.processed() > cmd.pty_output.len() {
+            *cached = (key, Terminal::new(size));
+        }
+        cached.1.resize(size);
+        cached.1.update(&cmd.pty_output);
+        cached.1.screen().lines()
     }
 
     pub fn find_cmd_by_index(&self, i: usize) -> Option<String> {
@@ -36,25 +100,304 @@ impl WeaverStateWidget {
             .command_history
             .iter()
             .rev()
-            .skip(i)
-            .next()
+            .nth(i)
             .map(|(_, cmd)| cmd.cmd.clone());
         rv
     }
+
+    pub fn find_cmd_id_by_index(&self, i: usize) -> Option<CommandId> {
+        let state = self.state.read().unwrap();
+        let rv = state.command_history.iter().rev().nth(i).map(|(id, _)| *id);
+        rv
+    }
+}
+
+fn status_icon(status: &Option<ExitStatus>) -> (char, &'static str) {
+    match *status {
+        None => ('…', "command.running"),
+        Some(ExitStatus::Exited(0)) => ('✔', "command.success"),
+        Some(ExitStatus::Exited(_)) => ('X', "command.failed"),
+        Some(ExitStatus::Signaled(_)) => ('⚡', "command.signaled"),
+        Some(ExitStatus::CoreDumped(_)) => ('☠', "command.signaled"),
+        Some(ExitStatus::Lost) => ('?', "command.failed"),
+        Some(ExitStatus::FailedToStart) => ('!', "command.failed"),
+    }
+}
+
+/// Like `status_icon`, but showing when a running command has its stdin open for input.
+fn command_icon(cmd: &WeaverCommand) -> (char, &'static str) {
+    match (cmd.status, cmd.stdin) {
+        (None, StdinState::Open) => ('⌨', "command.running"),
+        _ => status_icon(&cmd.status),
+    }
+}
+
+const SPARK_CHARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// A CPU sparkline of a running command's latest `samples`, followed by its current usage.
+fn sparkline(cmd: &WeaverCommand, samples: usize) -> Option<String> {
+    if cmd.status.is_some() {
+        return None;
+    }
+    let latest = cmd.samples.last()?;
+    let recent = &cmd.samples[cmd.samples.len().saturating_sub(samples)..];
+    // Scaled to one CPU, unless it has been using more
+    let peak = recent
+        .iter()
+        .fold(100.0f32, |peak, sample| peak.max(sample.cpu_percent));
+    let top = SPARK_CHARS.len() - 1;
+    let mut line: String = recent
+        .iter()
+        .map(|sample| {
+            let level = (sample.cpu_percent / peak * top as f32).round() as usize;
+            SPARK_CHARS[level.min(top)]
+        })
+        .collect();
+    line.push_str(&format!(
+        " {:.0}% {} MiB",
+        latest.cpu_percent,
+        latest.rss / 1024
+    ));
+    Some(line)
+}
+
+/// The size of terminal that fits the detail pane of a widget of `size`, which is where the
+/// whole of a command's screen is shown, below its command line and info.
+fn terminal_size(size: Size) -> WindowSize {
+    let detail_width = size.width - size.width / 2;
+    WindowSize {
+        rows: size.height.saturating_sub(2).max(1) as u16,
+        cols: detail_width.saturating_sub(1).max(1) as u16,
+    }
+}
+
+/// Wraps a stream's output to `width`, decoded however it looks to be encoded, marking where any
+/// of it was discarded by the daemon.
+fn output_lines(cmd: &WeaverCommand, stream: OutputStream, width: usize) -> Vec<String> {
+    let output = cmd.output(stream);
+    let truncation = cmd.truncation(stream);
+    let (head, tail) = match truncation {
+        Some(truncation) if truncation.offset <= output.len() => output.split_at(truncation.offset),
+        _ => (output, &[][..]),
+    };
+    let encoding = cmd.encoding(stream);
+    let mut lines = vec![];
+    if !head.is_empty() {
+        lines.extend(text_to_lines(encoding.decode(head).into_owned(), width));
+    }
+    if let Some(truncation) = truncation {
+        lines.push(truncation_marker(truncation, width));
+    }
+    if !tail.is_empty() {
+        lines.extend(text_to_lines(encoding.decode(tail).into_owned(), width));
+    }
+    lines
+}
+
+fn truncation_marker(truncation: Truncation, width: usize) -> String {
+    let marker = format!("[… {} bytes truncated …]", truncation.bytes);
+    marker.chars().take(width).collect()
+}
+
+/// Lines of output, along with the style of the stream they came from.
+type Run = (Vec<String>, &'static str);
+
+fn stream_style(stream: OutputStream) -> &'static str {
+    match stream {
+        OutputStream::Stderr => "stderr",
+        OutputStream::Stdout | OutputStream::Pty => "stdout",
+    }
+}
+
+fn push_run(runs: &mut Vec<Run>, lines: Vec<String>, style: &'static str) {
+    if lines.is_empty() {
+        return;
+    }
+    if let Some(last) = runs.last_mut() {
+        if last.1 == style {
+            last.0.extend(lines);
+            return;
+        }
+    }
+    runs.push((lines, style));
+}
+
+/// Both streams' output in the order it was written, marking where any of it was discarded by
+/// the daemon.
+fn interleaved_runs(cmd: &WeaverCommand, width: usize) -> Vec<Run> {
+    let streams = [OutputStream::Stdout, OutputStream::Stderr];
+    let encodings: Vec<_> = streams.iter().map(|&s| (s, cmd.encoding(s))).collect();
+    let mut truncations: Vec<_> = streams
+        .iter()
+        .filter_map(|&s| cmd.truncation(s).map(|t| (s, t)))
+        .collect();
+    let mut runs = vec![];
+    // Output is wrapped a run at a time, rather than a chunk at a time
+    let mut run: Option<(OutputStream, Vec<u8>)> = None;
+    let flush = |runs: &mut Vec<Run>, run: &mut Option<(OutputStream, Vec<u8>)>| {
+        if let Some((stream, output)) = run.take() {
+            let encoding = encodings.iter().find(|&&(s, _)| s == stream).unwrap().1;
+            let text = encoding.decode(&output).into_owned();
+            push_run(runs, text_to_lines(text, width), stream_style(stream));
+        }
+    };
+    for entry in cmd.timeline() {
+        if entry.stream == OutputStream::Pty {
+            continue;
+        }
+        if run
+            .as_ref()
+            .is_some_and(|&(stream, _)| stream != entry.stream)
+        {
+            flush(&mut runs, &mut run);
+        }
+        let mut output = entry.output;
+        let end = entry.offset + output.len();
+        let discarded = truncations
+            .iter()
+            .position(|&(stream, t)| stream == entry.stream && t.offset < end);
+        if let Some(i) = discarded {
+            let (stream, truncation) = truncations.remove(i);
+            let (head, tail) = output.split_at(truncation.offset.saturating_sub(entry.offset));
+            run.get_or_insert_with(|| (stream, vec![]))
+                .1
+                .extend_from_slice(head);
+            flush(&mut runs, &mut run);
+            let marker = vec![truncation_marker(truncation, width)];
+            push_run(&mut runs, marker, stream_style(stream));
+            output = tail;
+        }
+        run.get_or_insert_with(|| (entry.stream, vec![]))
+            .1
+            .extend_from_slice(output);
+    }
+    flush(&mut runs, &mut run);
+    for (stream, truncation) in truncations {
+        let marker = vec![truncation_marker(truncation, width)];
+        push_run(&mut runs, marker, stream_style(stream));
+    }
+    runs
+}
+
+/// The lines of a command's output to show, in runs styled by the stream they came from, given
+/// the lines on its screen if it's running in a pty.
+fn output_runs(cmd: &WeaverCommand, screen: &[String], view: OutputView, width: usize) -> Vec<Run> {
+    let mut runs = vec![];
+    match (view, cmd.pty) {
+        (OutputView::Stderr, _) => {}
+        (_, Some(_)) => {
+            let lines = screen
+                .iter()
+                .map(|line| line.chars().take(width).collect())
+                .collect();
+            push_run(&mut runs, lines, "stdout");
+        }
+        (OutputView::Interleaved, None) => runs = interleaved_runs(cmd, width),
+        (OutputView::Stdout, None) => {
+            let lines = output_lines(cmd, OutputStream::Stdout, width);
+            push_run(&mut runs, lines, "stdout");
+        }
+    }
+    if view == OutputView::Stderr || (view == OutputView::Interleaved && cmd.pty.is_some()) {
+        let lines = output_lines(cmd, OutputStream::Stderr, width);
+        push_run(&mut runs, lines, "stderr");
+    }
+    if let Some(ref error) = cmd.error {
+        if view != OutputView::Stdout {
+            let lines = text_to_lines(format!("weaverd: {}", error), width);
+            push_run(&mut runs, lines, "stderr");
+        }
+    }
+    runs
+}
+
+/// Adds the last `maxlines` lines of `runs` to `pane`, starting at line `offset`.
+fn push_output(
+    pane: &mut Pane,
+    runs: Vec<Run>,
+    offset: usize,
+    width: usize,
+    maxlines: usize,
+    prefix: &str,
+) {
+    let mut shown = vec![];
+    let mut remaining = maxlines;
+    for (mut lines, style) in runs.into_iter().rev() {
+        if remaining == 0 {
+            break;
+        }
+        if lines.len() > remaining {
+            lines = lines.split_off(lines.len() - remaining);
+        }
+        remaining -= lines.len();
+        shown.push((lines, style));
+    }
+    let mut line = offset;
+    for (lines, style) in shown.into_iter().rev() {
+        let textlen = lines.len();
+        pane.push_child(Pane::new_styled(
+            Position::new(1, line),
+            Size::new(width, textlen),
+            lines,
+            &format!("{}{}", prefix, style),
+        ));
+        line += textlen;
+    }
+}
+
+/// Describes the first stage of a pipeline to fail, whose status may not be the command's own.
+fn failed_stage(cmd: &WeaverCommand) -> Option<String> {
+    if cmd.stages.len() < 2 {
+        return None;
+    }
+    let (i, stage) = cmd
+        .stages
+        .iter()
+        .enumerate()
+        .find(|&(_, stage)| stage.failed())?;
+    let mut line = format!(
+        "stage {} of {} failed with status {}: {}",
+        i + 1,
+        cmd.stages.len(),
+        stage.status.map_or(0, |status| status.code()),
+        stage.cmd
+    );
+    if let Some(error) = stage.stderr.lines().last() {
+        line.push_str(&format!(" ({})", error));
+    }
+    Some(line)
+}
+
+/// A line for each stage of a command that ran more than one, with how much each piped onward.
+fn stage_lines(cmd: &WeaverCommand, width: usize) -> Vec<String> {
+    if cmd.stages.len() < 2 {
+        return vec![];
+    }
+    cmd.stages
+        .iter()
+        .map(|stage| {
+            let mut line = format!("{} {}", status_icon(&stage.status).0, stage.cmd);
+            if let Some(pid) = stage.pid {
+                line.push_str(&format!(" [{}]", pid));
+            }
+            if let Some(bytes) = stage.bytes_piped {
+                line.push_str(&format!(" | {} bytes", bytes));
+            }
+            line.chars().take(width).collect()
+        })
+        .collect()
 }
 
 fn render_command_summary(
     cmd: &WeaverCommand,
+    screen: &[String],
+    view: OutputView,
     width: usize,
     maxlines: usize,
     selected: bool,
 ) -> Pane {
     let mut pane = Pane::new_width(width);
-    let (icon, style) = match cmd.status {
-        None => ('…', "command.running"),
-        Some(0) => ('✔', "command.success"),
-        _ => ('X', "command.failed"),
-    };
+    let (icon, style) = command_icon(cmd);
     let status_pane = Pane::new_styled(
         Position::new(0, 0),
         Size::new(1, 1),
@@ -63,64 +406,104 @@ fn render_command_summary(
     );
     pane.push_child(status_pane);
     let subwidth = width - 1;
-    let command_line = text_to_lines(cmd.cmd.clone(), subwidth);
-    let pos = Position::new(1, 0);
-    let textlen = command_line.len();
-    let mut offset = textlen;
-    let size = Size::new(subwidth, textlen);
     let prefix = match selected {
         true => "selected.",
         false => "",
     };
+    let mut command_width = subwidth;
+    if let Some(spark) = sparkline(cmd, 16) {
+        let spark_width = spark.chars().count();
+        // Only when it leaves the command itself a reasonable amount of room
+        if spark_width * 2 < subwidth {
+            command_width -= spark_width + 1;
+            pane.push_child(Pane::new_styled(
+                Position::new(width - spark_width, 0),
+                Size::new(spark_width, 1),
+                vec![spark],
+                &format!("{}sparkline", prefix),
+            ));
+        }
+    }
+    let command_line = text_to_lines(cmd.cmd.clone(), command_width);
+    let pos = Position::new(1, 0);
+    let textlen = command_line.len();
+    let mut offset = textlen;
+    let size = Size::new(command_width, textlen);
     pane.push_child(Pane::new_styled(
         pos,
         size,
         command_line,
         &format!("{}command", prefix),
     ));
-    if cmd.stdout.len() > 0 {
-        let mut stdout = text_to_lines(cmd.stdout.clone(), subwidth);
-        let pos = Position::new(1, offset);
-        let mut textlen = stdout.len();
-        if textlen > maxlines {
-            stdout = stdout.split_off(textlen - maxlines);
-            textlen = maxlines;
-        }
-        offset += textlen;
-        let size = Size::new(subwidth, textlen);
+    if let Some(failure) = failed_stage(cmd) {
+        let failure: String = failure.chars().take(subwidth).collect();
         pane.push_child(Pane::new_styled(
-            pos,
-            size,
-            stdout,
-            &format!("{}stdout", prefix),
+            Position::new(1, offset),
+            Size::new(subwidth, 1),
+            vec![failure],
+            &format!("{}stderr", prefix),
         ));
+        offset += 1;
     }
-    if cmd.stderr.len() > 0 {
-        let mut stderr = text_to_lines(cmd.stderr.clone(), subwidth);
-        let pos = Position::new(1, offset);
-        let mut textlen = stderr.len();
-        if textlen > maxlines {
-            stderr = stderr.split_off(textlen - maxlines);
-            textlen = maxlines;
+    let runs = output_runs(cmd, screen, view, subwidth);
+    push_output(&mut pane, runs, offset, subwidth, maxlines, prefix);
+    pane
+}
+
+fn format_duration(d: Duration) -> String {
+    match d.as_secs() {
+        s if s >= 3600 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{}.{:02}s", s, d.subsec_nanos() / 10_000_000),
+    }
+}
+
+/// Describes when, where, and how expensively a command ran.
+fn command_info(cmd: &WeaverCommand) -> String {
+    let mut info = vec![];
+    if let Some(pid) = cmd.pid {
+        info.push(format!("pid {}", pid));
+    }
+    if let Some(ref cwd) = cmd.cwd {
+        info.push(format!("in {}", cwd.display()));
+    }
+    match cmd.stdin {
+        StdinState::Open => info.push("reading input".to_owned()),
+        StdinState::Closed => info.push("input closed".to_owned()),
+        StdinState::Null => {}
+    }
+    if let Some(started) = cmd.started {
+        if let Ok(ago) = SystemTime::now().duration_since(started) {
+            info.push(format!("started {} ago", format_duration(ago)));
         }
-        let size = Size::new(subwidth, textlen);
-        pane.push_child(Pane::new_styled(
-            pos,
-            size,
-            stderr,
-            &format!("{}stderr", prefix),
+        let end = cmd.finished.unwrap_or_else(SystemTime::now);
+        if let Ok(ran) = end.duration_since(started) {
+            info.push(format!("ran {}", format_duration(ran)));
+        }
+    }
+    if let Some(rusage) = cmd.rusage {
+        info.push(format!(
+            "cpu {} user {} sys",
+            format_duration(rusage.user_time),
+            format_duration(rusage.system_time)
+        ));
+        info.push(format!("max rss {} KiB", rusage.max_rss));
+        info.push(format!(
+            "io {} blocks in {} out",
+            rusage.blocks_read, rusage.blocks_written
         ));
     }
-    pane
+    info.join(", ")
 }
 
-fn render_command_detail(cmd: &WeaverCommand, size: Size) -> Pane {
+fn render_command_detail(
+    cmd: &WeaverCommand,
+    screen: &[String],
+    view: OutputView,
+    size: Size,
+) -> Pane {
     let mut pane = Pane::new_width(size.width);
-    let (icon, style) = match cmd.status {
-        None => ('…', "command.running"),
-        Some(0) => ('✔', "command.success"),
-        _ => ('X', "command.failed"),
-    };
+    let (icon, style) = command_icon(cmd);
     let status_pane = Pane::new_styled(
         Position::new(0, 0),
         Size::new(1, 1),
@@ -142,40 +525,38 @@ fn render_command_detail(cmd: &WeaverCommand, size: Size) -> Pane {
         command_line,
         &format!("{}command", prefix),
     ));
-    if cmd.stdout.len() > 0 {
-        let mut stdout = text_to_lines(cmd.stdout.clone(), subwidth);
+    let info = command_info(cmd);
+    if !info.is_empty() {
         let pos = Position::new(1, offset);
-        let mut textlen = stdout.len();
-        if textlen > maxlines {
-            stdout = stdout.split_off(textlen - maxlines);
-            textlen = maxlines;
-        }
+        let mut info = text_to_lines(info, subwidth);
+        info.truncate(maxlines);
+        let textlen = info.len();
         maxlines -= textlen;
         offset += textlen;
         let pane_size = Size::new(subwidth, textlen);
         pane.push_child(Pane::new_styled(
             pos,
             pane_size,
-            stdout,
-            &format!("{}stdout", prefix),
+            info,
+            &format!("{}info", prefix),
         ));
     }
-    if cmd.stderr.len() > 0 {
-        let mut stderr = text_to_lines(cmd.stderr.clone(), subwidth);
-        let pos = Position::new(1, offset);
-        let mut textlen = stderr.len();
-        if textlen > maxlines {
-            stderr = stderr.split_off(textlen - maxlines);
-            textlen = maxlines;
-        }
+    let mut stages = stage_lines(cmd, subwidth);
+    if !stages.is_empty() {
+        stages.truncate(maxlines);
+        let textlen = stages.len();
+        maxlines -= textlen;
         let pane_size = Size::new(subwidth, textlen);
         pane.push_child(Pane::new_styled(
-            pos,
+            Position::new(1, offset),
             pane_size,
-            stderr,
-            &format!("{}stderr", prefix),
+            stages,
+            &format!("{}info", prefix),
         ));
+        offset += textlen;
     }
+    let runs = output_runs(cmd, screen, view, subwidth);
+    push_output(&mut pane, runs, offset, subwidth, maxlines, prefix);
     pane
 }
 
@@ -184,18 +565,27 @@ impl Widget for WeaverStateWidget {
         let height = size.height;
         let mut ctr: usize = 0;
         let state = self.state.read().unwrap();
+        *self.terminal_size.write().unwrap() = Some(terminal_size(size));
+        self.terminals
+            .write()
+            .unwrap()
+            .retain(|id, _| state.command_history.commands.contains_key(id));
         let mut children: Vec<Pane> = vec![];
-        let mut i = 0;
         let child_width: usize = match self.selected {
             None => size.width,
             Some(_) => size.width / 2,
         };
-        for (_i, cmd) in state.command_history.iter().rev() {
+        for (i, (&id, cmd)) in state.command_history.iter().rev().enumerate() {
             let selected: bool = match self.selected {
                 None => false,
                 Some(idx) => idx == i,
             };
-            let mut child = render_command_summary(cmd, child_width, 3, selected);
+            let screen = match cmd.pty {
+                Some(_) => self.screen_lines(&state, id, cmd),
+                None => vec![],
+            };
+            let mut child =
+                render_command_summary(cmd, &screen, self.view, child_width, 6, selected);
             let offset = child.size.height;
 
             ctr += offset;
@@ -212,13 +602,13 @@ impl Widget for WeaverStateWidget {
             if selected {
                 let child_pos = Position::new(child_width, 0);
                 let child_size = Size::new(size.width - child_width, size.height);
-                let child = render_command_detail(cmd, child_size).offset(child_pos);
+                let child = render_command_detail(cmd, &screen, self.view, child_size);
+                let child = child.offset(child_pos);
                 children.push(child);
             }
             if ctr == height {
                 break;
             }
-            i += 1;
         }
         Some(children)
     }
@@ -232,6 +622,9 @@ struct WeaverTui {
     state: Shared<WeaverState>,
     statew: Shared<WeaverStateWidget>,
     show_debug: bool,
+    input_target: Option<CommandId>,
+    /// The size last given to commands running in a pty.
+    terminal_size: WindowSize,
 }
 
 impl WeaverTui {
@@ -242,6 +635,8 @@ impl WeaverTui {
         let statew = shared(WeaverStateWidget::new(state.clone()));
         //let dbgdump = shared(DbgDump::new(&state));
         let show_debug = false;
+        let input_target = None;
+        let terminal_size = WindowSize::default();
         let mut contentbox = Linear::hbox();
         //contentbox.push(&dbgdump);
         contentbox.push(&statew);
@@ -258,6 +653,8 @@ impl WeaverTui {
             state,
             statew,
             show_debug,
+            input_target,
+            terminal_size,
         }
     }
 
@@ -279,16 +676,138 @@ impl WeaverTui {
         }
     }
 
-    fn submit_input(&mut self) {
+    fn submit_input(&mut self, options: RunOptions) {
         let text = self.input.write().unwrap().finalize();
-        if text.len() > 0 {
-            self.state
-                .write()
-                .unwrap()
-                .run_command(text.clone())
-                .unwrap();
+        if let Some(id) = self.input_target {
+            let mut input = text.into_bytes();
+            input.push(b'\n');
+            let _ = self.state.write().unwrap().send_input(id, input);
+            return;
+        }
+        if !text.is_empty() {
+            // Failures are reported in the log as the daemon's replies arrive
+            let _ = self.state.write().unwrap().run_command_with(text, options);
+        }
+        self.statew.write().unwrap().selected = None;
+    }
+
+    fn selected_cmd_id(&self) -> Option<CommandId> {
+        let statew = self.statew.read().unwrap();
+        statew.selected.and_then(|i| statew.find_cmd_id_by_index(i))
+    }
+
+    fn toggle_input_target(&mut self) {
+        if let Some(id) = self.input_target.take() {
+            self.log_msg(&format!("No longer sending input to command {}", id));
+            return;
+        }
+        if let Some(id) = self.selected_cmd_id() {
+            let (running, reads_input) =
+                match self.state.read().unwrap().command_history.commands.get(&id) {
+                    Some(cmd) => (
+                        cmd.status.is_none(),
+                        cmd.pty.is_some() || cmd.stdin == StdinState::Open,
+                    ),
+                    None => (false, false),
+                };
+            if running && !reads_input {
+                self.log_msg(&format!(
+                    "Command {} isn't reading input, Alt-s runs a command that does",
+                    id
+                ));
+            } else if running {
+                self.input_target = Some(id);
+                self.input.write().unwrap().set_line("");
+                self.log_msg(&format!("Sending input to command {}", id));
+            }
+        }
+    }
+
+    fn close_input_target(&mut self) {
+        if let Some(id) = self.input_target.take() {
+            let _ = self.state.write().unwrap().close_input(id);
+            self.log_msg(&format!("Closed input to command {}", id));
+        }
+    }
+
+    /// Runs the selected command again, in the directory and environment it first ran with.
+    fn rerun_selected(&mut self) {
+        if let Some(id) = self.selected_cmd_id() {
+            let mut state = self.state.write().unwrap();
+            let rerun = state
+                .command_history
+                .commands
+                .get(&id)
+                .map(|cmd| (cmd.cmd.clone(), cmd.run_options()));
+            if let Some((cmd, options)) = rerun {
+                let _ = state.run_command_with(cmd, options);
+            }
         }
         self.statew.write().unwrap().selected = None;
+        self.input.write().unwrap().set_line("");
+    }
+
+    fn switch_output_view(&mut self) {
+        let view = {
+            let mut statew = self.statew.write().unwrap();
+            statew.view = statew.view.next();
+            statew.view
+        };
+        self.log_msg(match view {
+            OutputView::Interleaved => "Showing stdout and stderr in the order they were written",
+            OutputView::Stdout => "Showing stdout alone",
+            OutputView::Stderr => "Showing stderr alone",
+        });
+    }
+
+    /// Resizes the terminals of commands running in a pty to fit where their screens are shown,
+    /// once that has changed.
+    fn resize_terminals(&mut self) {
+        let size = match *self.statew.read().unwrap().terminal_size.read().unwrap() {
+            Some(size) => size,
+            None => return,
+        };
+        if size == self.terminal_size {
+            return;
+        }
+        self.terminal_size = size;
+        let mut state = self.state.write().unwrap();
+        let running: Vec<CommandId> = state
+            .command_history
+            .commands
+            .iter()
+            .filter(|&(_, cmd)| cmd.status.is_none() && cmd.pty.is_some_and(|pty| pty != size))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in running {
+            let _ = state.resize_pty(id, size);
+        }
+    }
+
+    fn signal_selected(&mut self, signal: Signal) {
+        if let Some(id) = self.selected_cmd_id() {
+            let _ = self.state.write().unwrap().signal_command(id, signal);
+        }
+    }
+
+    /// Fetches the rest of the selected command's output, and older history once the selection
+    /// nears the oldest command loaded.
+    fn load_selected(&mut self) {
+        const OUTPUT_CHUNK: usize = 64 * 1024;
+        const HISTORY_MARGIN: usize = 10;
+        let selected = self.statew.read().unwrap().selected;
+        let id = self.selected_cmd_id();
+        let mut state = self.state.write().unwrap();
+        if let Some(i) = selected {
+            if i + HISTORY_MARGIN >= state.command_history.commands.len() {
+                let _ = state.load_more_history();
+            }
+        }
+        if let Some(id) = id {
+            for &stream in OUTPUT_STREAMS.iter() {
+                let _ = state.load_output(id, stream, OUTPUT_CHUNK);
+            }
+        }
     }
 
     fn log_msg(&mut self, msg: &str) {
@@ -298,8 +817,23 @@ impl WeaverTui {
 
     fn input(&mut self, key: Key) {
         match key {
-            Key::Char('\n') => self.submit_input(),
+            Key::Char('\n') => self.submit_input(RunOptions::default()),
+            Key::Alt('t') => {
+                let size = self.terminal_size;
+                self.submit_input(RunOptions {
+                    pty: Some(size),
+                    ..RunOptions::default()
+                })
+            }
+            Key::Alt('s') => self.submit_input(RunOptions {
+                input: true,
+                ..RunOptions::default()
+            }),
+            Key::Alt('r') => self.rerun_selected(),
+            Key::Alt('o') => self.switch_output_view(),
             Key::Alt('\r') => self.input.write().unwrap().process_key(Key::Char('\n')),
+            Key::Ctrl('c') => self.signal_selected(Signal::Interrupt),
+            Key::Ctrl('d') => self.close_input_target(),
             Key::Up => {
                 let mut statew = self.statew.write().unwrap();
                 statew.selected = match statew.selected.take() {
@@ -309,6 +843,8 @@ impl WeaverTui {
                 if let Some(cmd) = statew.find_cmd_by_index(statew.selected.unwrap()) {
                     self.input.write().unwrap().set_line(&cmd);
                 };
+                drop(statew);
+                self.load_selected();
             }
             Key::Down => {
                 let mut statew = self.statew.write().unwrap();
@@ -323,6 +859,8 @@ impl WeaverTui {
                     },
                     None => self.input.write().unwrap().set_line(""),
                 }
+                drop(statew);
+                self.load_selected();
             }
             k => self.input.write().unwrap().process_key(k),
         }
@@ -336,6 +874,7 @@ impl App for WeaverTui {
         self.vbox.clone()
     }
     fn handle_event(&mut self, event: Event<Self::MyEvent>) -> Result<(), Option<String>> {
+        self.resize_terminals();
         match event {
             Event::InputEvent(i) => match i {
                 Input::Key(Key::Esc) => Err(None),
@@ -343,19 +882,37 @@ impl App for WeaverTui {
                     self.toggle_debug();
                     Ok(())
                 }
+                Input::Key(Key::Alt('i')) => {
+                    self.toggle_input_target();
+                    Ok(())
+                }
                 Input::Key(k) => {
                     self.input(k);
                     Ok(())
                 }
                 _ => Ok(()),
             },
+            Event::AppEvent(WeaverNotification::Disconnected(ref reason)) => {
+                Err(Some(reason.clone()))
+            }
+            Event::AppEvent(WeaverNotification::ConnectionLost(ref reason)) => {
+                self.log_msg(&format!(
+                    "Lost connection to weaverd, reconnecting: {}",
+                    reason
+                ));
+                Ok(())
+            }
+            Event::AppEvent(WeaverNotification::Reconnected) => {
+                self.log_msg("Reconnected to weaverd");
+                Ok(())
+            }
             Event::AppEvent(_) => {
                 self.log_msg(&format!("{:?}", event));
                 Ok(())
             }
         }
     }
-    fn style(&self, name: &str) -> (Option<Box<Color>>, Option<Box<Color>>) {
+    fn style(&self, name: &str) -> (Option<Box<dyn Color>>, Option<Box<dyn Color>>) {
         match name {
             "command" => (None, Some(Box::new(color::Rgb(16, 16, 32)))),
             "stderr" => (None, Some(Box::new(color::Rgb(32, 16, 16)))),
@@ -370,6 +927,11 @@ impl App for WeaverTui {
                 Some(Box::new(color::Rgb(16, 16, 32))),
             ),
             "command.failed" => (Some(Box::new(color::LightRed)), None),
+            "command.signaled" => (Some(Box::new(color::LightMagenta)), None),
+            "sparkline" => (
+                Some(Box::new(color::LightCyan)),
+                Some(Box::new(color::Rgb(16, 16, 32))),
+            ),
             "selected.command" => (
                 Some(Box::new(color::LightWhite)),
                 Some(Box::new(color::Rgb(32, 32, 128))),
@@ -378,6 +940,14 @@ impl App for WeaverTui {
                 Some(Box::new(color::LightWhite)),
                 Some(Box::new(color::Rgb(64, 16, 16))),
             ),
+            "selected.sparkline" => (
+                Some(Box::new(color::LightCyan)),
+                Some(Box::new(color::Rgb(32, 32, 128))),
+            ),
+            "selected.info" => (
+                Some(Box::new(color::LightBlack)),
+                Some(Box::new(color::Rgb(32, 32, 128))),
+            ),
             "selected.stdout" => (
                 Some(Box::new(color::LightWhite)),
                 Some(Box::new(color::Rgb(16, 64, 16))),
@@ -391,19 +961,84 @@ impl App for WeaverTui {
                 Some(Box::new(color::Rgb(16, 16, 32))),
             ),
             "selected.command.failed" => (Some(Box::new(color::LightRed)), None),
+            "selected.command.signaled" => (Some(Box::new(color::LightMagenta)), None),
             _ => (None, None),
         }
     }
 }
 
+/// Writes a command's output to stdout exactly as the command wrote it, returning the status to
+/// exit with.
+fn write_output(paths: WeaverPaths, id: &str, stream: OutputStream) -> i32 {
+    let id: CommandId = match id.parse() {
+        Ok(id) => id,
+        Err(_) => {
+            eprintln!("weaverc: {} is not a command id", id);
+            return 2;
+        }
+    };
+    let (sender, _notifications) = channel();
+    let weaver = WeaverClient::new("cli", paths, sender);
+    let state = weaver.state.clone();
+    thread::spawn(move || {
+        tokio::run(weaver.map_err(|e| panic!("Client Error: {:#?}", e)));
+    });
+    let output = state.write().unwrap().fetch_output(id, stream);
+    let (output, truncation) = match output.wait() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("weaverc: {}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = io::stdout().write_all(&output) {
+        eprintln!("weaverc: {}", e);
+        return 1;
+    }
+    match truncation {
+        Some(truncation) => {
+            eprintln!(
+                "weaverc: {} bytes of output were discarded at byte {}",
+                truncation.bytes, truncation.offset
+            );
+            1
+        }
+        None => 0,
+    }
+}
+
 fn main() {
+    let flags = &["--output ID", "--stderr", "--pty"];
+    let args = args::parse("weaverc", flags, env::args().skip(1)).unwrap_or_else(|usage| {
+        eprintln!("{}", usage);
+        process::exit(2);
+    });
+    args.export_socket();
+    let paths = WeaverPaths::resolve().unwrap_or_else(|e| {
+        eprintln!("weaverc: {}", e);
+        process::exit(1);
+    });
+    if let Some(flag) = args.flags.iter().find(|flag| flag.starts_with("--output=")) {
+        let stream = match (args.given("--stderr"), args.given("--pty")) {
+            (true, _) => OutputStream::Stderr,
+            (false, true) => OutputStream::Pty,
+            (false, false) => OutputStream::Stdout,
+        };
+        process::exit(write_output(paths, &flag["--output=".len()..], stream));
+    }
     let be = Backend::new();
     let sender = be.sender.clone();
-    let weaver = WeaverClient::new(sender);
+    let weaver = WeaverClient::new("tui", paths, sender);
     let mut app = WeaverTui::new(weaver.state.clone());
     thread::spawn(move || {
         tokio::run(weaver.map_err(|e| panic!("Client Error: {:#?}", e)));
     });
     app.log_msg("Esc to exit");
+    app.log_msg("Alt-t to run a command in a terminal");
+    app.log_msg("Alt-s to run a command that reads input sent to it with Alt-i");
+    app.log_msg("Alt-r to rerun the selected command where it first ran");
+    app.log_msg("Ctrl-C to interrupt the selected command");
+    app.log_msg("Alt-i to send input to the selected command, Ctrl-D to close it");
+    app.log_msg("Alt-o to show stdout and stderr together, or either alone");
     be.run_app(&mut app);
 }
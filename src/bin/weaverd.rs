@@ -4,42 +4,238 @@ extern crate tokio;
 extern crate tokio_io;
 //extern crate tokio_process;
 extern crate tokio_serde_msgpack;
+extern crate tokio_signal;
 extern crate tokio_threadpool;
 extern crate tokio_uds;
+#[macro_use]
 extern crate weaver;
 
+use futures::future::FlattenStream;
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::AsyncSink;
 
-use tokio::prelude::{task, Async, AsyncRead, Future, Sink, Stream};
+use tokio::prelude::{task, Async, AsyncRead, AsyncWrite, Future, Sink, Stream};
+use tokio_io::IoFuture;
 use tokio_serde_msgpack::{from_io, MsgPackReader, MsgPackWriter};
+use tokio_signal::unix::{Signal as UnixSignal, SIGHUP};
 use tokio_uds::{UnixListener, UnixStream};
 
-use std::collections::HashMap;
-use std::io::BufReader;
-use std::process::{Command, Stdio};
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use weaver::process::{stdio, Child, ChildStderr, ChildStdout};
+use tokio::timer::{Delay, Interval};
+
+use weaver::args;
+use weaver::batch::{self, Batches};
+use weaver::config::{Config, Tuning};
+use weaver::definitions::Definitions;
+use weaver::process::{
+    acquire_controlling_terminal, join_process_group, openpty, pipe, set_window_size,
+    signal_process_group, stdio, Child, ChildStderr, ChildStdin, ChildStdout, Pty,
+};
+use weaver::queue::ClientQueue;
+use weaver::retention::RetentionPolicy;
+use weaver::sampling::Sampler;
+use weaver::session::Session;
+use weaver::shell::{
+    self, Connector, Expansion, Pipeline, Redirect, RedirectOp, Script, SimpleCommand,
+};
+use weaver::socket::{check_peer, SocketLock};
+use weaver::store::HistoryStore;
 use weaver::{
-    weaver_socket_path, ClientMessage, ClientRequest, CommandHistory, CommandId, ServerMessage,
-    ServerNotice,
+    ClientInfo, ClientMessage, ClientRequest, CommandHistory, CommandId, ExitStatus, Hello,
+    PipelineStage, ResourceUsage, Response, RunOptions, ServerMessage, ServerNotice, Signal,
+    StdinState, WeaverPaths,
 };
 
 type ClientID = u32;
 
+/// A client that has completed its handshake, and so hears every notice.
+pub struct Client {
+    pub kind: String,
+    pub queue: ClientQueue,
+}
+
 pub struct ServerState {
-    pub channels: HashMap<ClientID, UnboundedSender<ServerMessage>>,
+    pub clients: HashMap<ClientID, Client>,
     pub command_history: CommandHistory,
+    pub running: HashMap<CommandId, UnboundedSender<(ClientRequest, ReplyTo)>>,
+    pub retention: RetentionPolicy,
+    pub definitions: Definitions,
+    pub config: Config,
+    /// Where `config` is read from.
+    config_path: PathBuf,
+    /// The sequence number of the last notice dispatched.
+    pub seq: u64,
+    /// The latest notices, kept for replaying to clients that missed them.
+    recent: VecDeque<ServerMessage>,
+    /// Roughly how many bytes the notices in `recent` take up.
+    recent_bytes: usize,
+    finished: HashMap<CommandId, SystemTime>,
+    store: HistoryStore,
 }
 
 impl ServerState {
-    pub fn new() -> Self {
-        let channels = HashMap::new();
-        let command_history = CommandHistory::new();
+    pub fn new(
+        store: HistoryStore,
+        command_history: CommandHistory,
+        config: Config,
+        config_path: PathBuf,
+    ) -> Self {
+        let clients = HashMap::new();
+        let running = HashMap::new();
+        // Commands without a record of when they finished get a fresh lease on life
+        let now = SystemTime::now();
+        let finished = command_history
+            .iter()
+            .filter(|&(_, cmd)| cmd.status.is_some())
+            .map(|(&id, cmd)| (id, cmd.finished.unwrap_or(now)))
+            .collect();
+        // Each run numbers its notices from a fresh block, so that a client can't mistake one
+        // run's notices for another's, even those it heard but the store never did
+        let seq = ((store.seq() >> 32) + 1) << 32;
         ServerState {
-            channels,
+            clients,
             command_history,
+            running,
+            retention: config.retention.clone(),
+            definitions: Definitions::from_config(&config),
+            config,
+            config_path,
+            seq,
+            recent: VecDeque::new(),
+            recent_bytes: 0,
+            finished,
+            store,
+        }
+    }
+
+    /// Numbers `msg`, records it in history and on disk, and sends it to every client.
+    pub fn dispatch(&mut self, mut msg: ServerMessage) {
+        self.seq += 1;
+        msg.seq = self.seq;
+        msg.time = Some(SystemTime::now());
+        match msg.notice {
+            ServerNotice::CommandCompleted(i, _, finished, _) => {
+                self.running.remove(&i);
+                self.finished.insert(i, finished);
+            }
+            ServerNotice::SpawnFailed(i, _) => {
+                self.running.remove(&i);
+                self.finished.insert(i, SystemTime::now());
+            }
+            ServerNotice::CommandsExpired(ref ids) => {
+                for i in ids {
+                    self.finished.remove(i);
+                }
+            }
+            _ => {}
+        }
+        if let Err(e) = self.store.append(&msg) {
+            log!(Error, "Failed to persist {:?}: {}", msg, e);
+        }
+        self.command_history.do_update(msg.clone());
+        for client in self.clients.values() {
+            client.queue.broadcast(msg.clone(), &self.config.clients);
+        }
+        self.recent_bytes += msg.notice.size();
+        self.recent.push_back(msg.clone());
+        while self.recent_bytes > self.config.tuning.replay_bytes {
+            match self.recent.pop_front() {
+                Some(old) => self.recent_bytes -= old.notice.size(),
+                None => break,
+            }
+        }
+
+        // Truncate immediately, so that later output is measured against the truncated length
+        let truncations = match msg.notice {
+            ServerNotice::CommandOutput(i, _)
+            | ServerNotice::CommandErr(i, _)
+            | ServerNotice::CommandPtyOutput(i, _) => match self.command_history.commands.get(&i) {
+                Some(cmd) => self.retention.enforce_command(i, cmd),
+                None => vec![],
+            },
+            _ => vec![],
+        };
+        for notice in truncations {
+            self.dispatch(ServerMessage::new(0, notice));
+        }
+    }
+
+    /// The notices dispatched after `seq`, if they are all still kept.
+    pub fn replay(&self, seq: u64) -> Option<Vec<ServerMessage>> {
+        let oldest = self.recent.front().map_or(self.seq + 1, |msg| msg.seq);
+        if seq > self.seq || seq + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.recent
+                .iter()
+                .filter(|msg| msg.seq > seq)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let notices = self
+            .retention
+            .enforce_history(&self.command_history, &self.finished);
+        for notice in notices {
+            self.dispatch(ServerMessage::new(0, notice));
+        }
+
+        // Leave plenty of room for appends between compactions
+        let limit = 2 * self.retention.max_history_output as u64 + 1024 * 1024;
+        if self.store.size() > limit {
+            if let Err(e) = self.store.compact(&self.command_history) {
+                log!(Error, "Failed to compact command history: {}", e);
+            }
+        }
+    }
+
+    /// The processes of each running command, to sample.
+    pub fn running_pids(&self) -> Vec<(CommandId, Vec<u32>)> {
+        // Every stage of a pipeline runs at once
+        self.command_history
+            .iter()
+            .filter(|&(_, cmd)| cmd.status.is_none())
+            .map(|(&id, cmd)| {
+                let pids: Vec<u32> = cmd
+                    .stages
+                    .iter()
+                    .filter(|stage| stage.status.is_none())
+                    .filter_map(|stage| stage.pid)
+                    .collect();
+                (id, pids)
+            })
+            .filter(|(_, pids)| !pids.is_empty())
+            .collect()
+    }
+
+    /// Rereads the config file, keeping the current config if it can't be read.
+    pub fn reload_config(&mut self) -> Result<(), String> {
+        let path = &self.config_path;
+        let config = Config::load(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.retention = config.retention.clone();
+        self.definitions = Definitions::from_config(&config);
+        self.config = config;
+        log!(Info, "Reloaded {}", path.display());
+        Ok(())
+    }
+
+    pub fn flush(&mut self) {
+        if let Err(e) = self.store.flush() {
+            log!(Error, "Failed to persist command history: {}", e);
         }
     }
 }
@@ -47,55 +243,401 @@ impl ServerState {
 pub struct ClientConn<'a> {
     id: ClientID,
     broadcast: UnboundedSender<ServerMessage>,
-    pub chan_send: UnboundedSender<ServerMessage>,
-    chan_recv: UnboundedReceiver<ServerMessage>,
+    reload: UnboundedSender<ReplyTo>,
+    pub queue: ClientQueue,
     socket_tx: MsgPackWriter<UnixStream, ServerMessage>,
     socket_rx: MsgPackReader<'a, UnixStream, ClientMessage>,
     state: Arc<RwLock<ServerState>>,
     overflow: Option<ServerMessage>,
+    hello: Option<Hello>,
+    closing: bool,
+    session: Session,
+    /// The sequence number of the last notice sent to the client.
+    last_seq: u64,
 }
 
 fn send_notice(chan: &UnboundedSender<ServerMessage>, id: u32, notice: ServerNotice) {
-    println!("{:#?}", notice);
-    let msg = ServerMessage { id, notice };
+    log!(Trace, "{:#?}", notice);
+    let msg = ServerMessage::new(id, notice);
     let _ = chan.unbounded_send(msg);
 }
 
+/// Sends a notice to a single client.
+fn send_to_client(queue: &ClientQueue, id: u32, notice: ServerNotice) {
+    log!(Debug, "{:#?}", notice);
+    queue.send(ServerMessage::new(id, notice));
+}
+
+/// Where to send the reply to a client's request.
+pub struct ReplyTo {
+    id: u32,
+    queue: ClientQueue,
+}
+
+impl ReplyTo {
+    pub fn send(self, result: Result<Response, String>) {
+        send_to_client(&self.queue, self.id, ServerNotice::Reply(result));
+    }
+}
+
 impl<'a> ClientConn<'a> {
     pub fn new(
         id: ClientID,
         socket: UnixStream,
         state: Arc<RwLock<ServerState>>,
         broadcast: UnboundedSender<ServerMessage>,
+        reload: UnboundedSender<ReplyTo>,
     ) -> Self {
         let overflow = None;
         let (socket_rx, socket_tx): (
             MsgPackReader<UnixStream, ClientMessage>,
             MsgPackWriter<UnixStream, ServerMessage>,
         ) = from_io(socket);
-        let (chan_send, chan_recv): (
-            UnboundedSender<ServerMessage>,
-            UnboundedReceiver<ServerMessage>,
-        ) = unbounded();
-
-        state
-            .write()
-            .unwrap()
-            .channels
-            .insert(id, chan_send.clone());
+        let queue = ClientQueue::new();
+        let hello = None;
+        let closing = false;
+        let mut session = Session::new();
+        session.env.extend(state.read().unwrap().config.env.clone());
 
         ClientConn {
             id,
             state,
             broadcast,
-            chan_send,
-            chan_recv,
+            reload,
+            queue,
             socket_rx,
             socket_tx,
             overflow,
+            hello,
+            closing,
+            session,
+            last_seq: 0,
+        }
+    }
+
+    fn handshake(&mut self, id: u32, hello: Hello) {
+        if let Err(reason) = hello.check_version() {
+            return self.disconnect(id, reason);
+        }
+        log!(
+            Info,
+            "Client {} is a {} client with features {:?}",
+            self.id,
+            hello.kind,
+            hello.features
+        );
+        send_to_client(&self.queue, id, ServerNotice::Hello(Hello::new("weaverd")));
+        // Only now start broadcasting, so the client hears nothing it can't decode
+        let client = Client {
+            kind: hello.kind.clone(),
+            queue: self.queue.clone(),
+        };
+        let mut state = self.state.write().unwrap();
+        state.clients.insert(self.id, client);
+        self.last_seq = state.seq;
+        drop(state);
+        self.hello = Some(hello);
+    }
+
+    /// Numbers a message meant for this client alone after the last notice sent before it.
+    fn stamp(&mut self, mut msg: ServerMessage) -> ServerMessage {
+        if msg.notice.is_broadcast() {
+            self.last_seq = self.last_seq.max(msg.seq);
+        } else {
+            msg.seq = self.last_seq;
+        }
+        msg
+    }
+
+    /// Tells the client why it is being disconnected, and stops listening to it.
+    fn disconnect(&mut self, id: u32, reason: String) {
+        log!(Info, "Disconnecting client {}: {}", self.id, reason);
+        self.state.write().unwrap().clients.remove(&self.id);
+        send_to_client(&self.queue, id, ServerNotice::Incompatible(reason));
+        self.closing = true;
+    }
+
+    /// Runs `script` against the session itself if it is nothing but a builtin or assignments,
+    /// so that it has a lasting effect, returning its output or error.
+    fn run_session_builtin(
+        &mut self,
+        script: &Script,
+        definitions: &Definitions,
+    ) -> Option<Result<String, String>> {
+        if script.pipelines.len() != 1 || script.pipelines[0].1.stages.len() != 1 {
+            return None;
+        }
+        let cmd = &script.pipelines[0].1.stages[0];
+        if !cmd.redirects.is_empty() {
+            return None;
+        }
+        let (words, assignments) = {
+            let expansion = Expansion {
+                env: &self.session.env,
+                cwd: &self.session.cwd,
+                status: 0,
+                args: &[],
+            };
+            let words: Vec<String> = cmd
+                .words
+                .iter()
+                .flat_map(|word| expansion.expand(word))
+                .collect();
+            let assignments: Vec<(String, String)> = cmd
+                .assignments
+                .iter()
+                .map(|(name, value)| (name.clone(), expansion.expand_text(value)))
+                .collect();
+            (words, assignments)
+        };
+        match (words.is_empty(), assignments.is_empty()) {
+            (true, _) => {
+                self.session.env.extend(assignments);
+                Some(Ok(String::new()))
+            }
+            // Functions take precedence over builtins of the same name
+            (false, true) if !definitions.is_function(&words[0]) => {
+                self.session.run_builtin(&words)
+            }
+            (false, _) => None,
+        }
+    }
+
+    fn run_command(&mut self, req_id: u32, c: String, options: RunOptions, reply: ReplyTo) {
+        // Syntax errors are found before anything runs, so that a script never half runs
+        let (definitions, shell, tuning) = {
+            let state = self.state.read().unwrap();
+            let config = &state.config;
+            (
+                state.definitions.clone(),
+                config.shell.clone(),
+                config.tuning,
+            )
+        };
+        let script = shell::parse(&c).map(|script| definitions.expand_aliases(script));
+        let (cwd, env) = self.session.resolve(&options);
+        let builtin = match script {
+            Ok(ref script) => self.run_session_builtin(script, &definitions),
+            Err(_) => None,
+        };
+        // A configured shell runs everything the session can't, whatever the syntax
+        let script = match shell {
+            Some(shell) => Script::command(vec![shell, "-c".to_owned(), c.clone()]),
+            None => match script {
+                Ok(script) => script,
+                // Nothing ran, so there is no command to record it against
+                Err(e) => return reply.send(Err(e.to_string())),
+            },
+        };
+        let (control_send, control_recv) = unbounded();
+        // Announcing the command under the same lock that assigns its id keeps concurrent
+        // requests from being assigned the same one
+        let mut state = self.state.write().unwrap();
+        let cmd_idx = state.command_history.next_index();
+        let notices = vec![
+            ServerNotice::CommandStarted(cmd_idx, c, SystemTime::now()),
+            ServerNotice::CommandEnvironment(cmd_idx, cwd.clone(), env.clone()),
+        ];
+        for notice in notices {
+            state.dispatch(ServerMessage::new(req_id, notice));
+        }
+
+        if let Some(result) = builtin {
+            let (output, status) = match result {
+                Ok(output) => (ServerNotice::CommandOutput(cmd_idx, output.into_bytes()), 0),
+                Err(e) => {
+                    let error = format!("{}\n", e).into_bytes();
+                    (ServerNotice::CommandErr(cmd_idx, error), 1)
+                }
+            };
+            state.dispatch(ServerMessage::new(req_id, output));
+            state.dispatch(ServerMessage::new(
+                req_id,
+                ServerNotice::CommandCompleted(
+                    cmd_idx,
+                    ExitStatus::Exited(status),
+                    SystemTime::now(),
+                    None,
+                ),
+            ));
+            reply.send(Ok(Response::CommandStarted(cmd_idx)));
+            return;
+        }
+
+        state.running.insert(cmd_idx, control_send);
+        if let Some(size) = options.pty {
+            state.dispatch(ServerMessage::new(
+                req_id,
+                ServerNotice::PtyResized(cmd_idx, size),
+            ));
+        }
+        drop(state);
+
+        let run_command = RunningCommand::new(
+            script,
+            Session { cwd, env },
+            definitions,
+            tuning,
+            self.broadcast.clone(),
+            control_recv,
+            req_id,
+            cmd_idx,
+            &options,
+        );
+        match run_command {
+            Ok(run_command) => {
+                if run_command.stdin.is_some() {
+                    self.state.write().unwrap().dispatch(ServerMessage::new(
+                        req_id,
+                        ServerNotice::StdinChanged(cmd_idx, StdinState::Open),
+                    ));
+                }
+                reply.send(Ok(Response::CommandStarted(cmd_idx)));
+                tokio::spawn(run_command);
+            }
+            Err(e) => {
+                let error = format!("Failed to start command {}: {}", cmd_idx, e);
+                self.state.write().unwrap().dispatch(ServerMessage::new(
+                    req_id,
+                    ServerNotice::SpawnFailed(cmd_idx, e.to_string()),
+                ));
+                reply.send(Err(error));
+            }
+        }
+    }
+
+    pub fn handle_msg(&mut self, msg: ClientMessage) {
+        if self.hello.is_none() {
+            match msg.request {
+                ClientRequest::Hello(hello) => self.handshake(msg.id, hello),
+                _ => self.disconnect(msg.id, "Expected a hello first".to_owned()),
+            }
+            return;
+        }
+        let reply = ReplyTo {
+            id: msg.id,
+            queue: self.queue.clone(),
+        };
+        match msg.request {
+            ClientRequest::Hello(_) => reply.send(Err("Already said hello".to_owned())),
+            ClientRequest::RunCommand(c, options) => self.run_command(msg.id, c, options, reply),
+            ClientRequest::Signal(cmd_idx, signal) => {
+                self.forward_to_command(cmd_idx, ClientRequest::Signal(cmd_idx, signal), reply)
+            }
+            ClientRequest::SendInput(cmd_idx, input) => {
+                self.forward_to_command(cmd_idx, ClientRequest::SendInput(cmd_idx, input), reply)
+            }
+            ClientRequest::CloseInput(cmd_idx) => {
+                self.forward_to_command(cmd_idx, ClientRequest::CloseInput(cmd_idx), reply)
+            }
+            ClientRequest::ResizePty(cmd_idx, size) => {
+                self.forward_to_command(cmd_idx, ClientRequest::ResizePty(cmd_idx, size), reply)
+            }
+            ClientRequest::ListCommands(before, limit, bytes) => {
+                // Replying while holding the lock keeps the page consistent with the notices
+                // already queued for this client
+                let state = self.state.read().unwrap();
+                let (page, more) = state.command_history.page(before, limit, bytes);
+                reply.send(Ok(Response::CommandsPage(page, more)));
+            }
+            ClientRequest::Resync(held, running, bytes) => {
+                // As with a page of history, this must be consistent with the queued notices
+                let state = self.state.read().unwrap();
+                let commands = &state.command_history.commands;
+                let newest = held.iter().cloned().max().unwrap_or(0);
+                // Anything newer than the client's newest command is new to it, running or not
+                let changed = running
+                    .into_iter()
+                    .filter(|&id| id <= newest)
+                    .filter_map(|id| commands.get(&id).map(|cmd| (id, cmd)))
+                    .chain(commands.range(newest + 1..).map(|(&id, cmd)| (id, cmd)))
+                    .map(|(id, cmd)| {
+                        let (cmd, offsets) = cmd.tail(bytes);
+                        (id, cmd, offsets)
+                    })
+                    .collect();
+                let expired = held
+                    .into_iter()
+                    .filter(|id| !commands.contains_key(id))
+                    .collect();
+                reply.send(Ok(Response::Resynced(changed, expired)));
+            }
+            ClientRequest::Replay(seq) => {
+                // Holding the lock keeps new notices from being dispatched in the middle
+                let state = self.state.read().unwrap();
+                match state.replay(seq) {
+                    Some(notices) => {
+                        for msg in notices {
+                            self.queue.send(msg);
+                        }
+                        reply.send(Ok(Response::Done));
+                    }
+                    None => reply.send(Err(format!("Notices since {} are no longer kept", seq))),
+                }
+            }
+            ClientRequest::ListClients => {
+                let clients = self
+                    .state
+                    .read()
+                    .unwrap()
+                    .clients
+                    .iter()
+                    .map(|(&id, client)| ClientInfo {
+                        id,
+                        kind: client.kind.clone(),
+                        queue: client.queue.stats(),
+                    })
+                    .collect();
+                reply.send(Ok(Response::Clients(clients)));
+            }
+            ClientRequest::Define(definition) => {
+                let result = self.state.write().unwrap().definitions.define(definition);
+                reply.send(result.map(|()| Response::Done));
+            }
+            ClientRequest::ListDefinitions => {
+                let definitions = self.state.read().unwrap().definitions.list();
+                reply.send(Ok(Response::Definitions(definitions)));
+            }
+            ClientRequest::RemoveDefinition(kind, name) => {
+                let result = self.state.write().unwrap().definitions.remove(kind, &name);
+                reply.send(result.map(|()| Response::Done));
+            }
+            // The server itself reloads, since it owns the socket
+            ClientRequest::ReloadConfig => {
+                if let Err(e) = self.reload.unbounded_send(reply) {
+                    e.into_inner()
+                        .send(Err("The server is shutting down".to_owned()));
+                }
+            }
+            ClientRequest::FetchOutput(cmd_idx, stream, start, end) => {
+                let state = self.state.read().unwrap();
+                let result = match state.command_history.commands.get(&cmd_idx) {
+                    Some(cmd) => {
+                        let (start, data) = cmd.output_range(stream, start, end);
+                        let truncation = cmd.truncation(stream);
+                        Ok(Response::OutputRange(
+                            cmd_idx, stream, start, data, truncation,
+                        ))
+                    }
+                    None => Err(format!("No such command: {}", cmd_idx)),
+                };
+                reply.send(result);
+            }
+        }
+    }
+
+    fn forward_to_command(&self, cmd_idx: CommandId, request: ClientRequest, reply: ReplyTo) {
+        let control = self.state.read().unwrap().running.get(&cmd_idx).cloned();
+        let unsent = match control {
+            Some(control) => control
+                .unbounded_send((request, reply))
+                .map_err(|e| e.into_inner().1),
+            None => Err(reply),
+        };
+        if let Err(reply) = unsent {
+            reply.send(Err("Command is not running".to_owned()));
         }
     }
-    pub fn handle_msg(&mut self, _msg: ClientMessage) {}
 }
 
 impl<'a> Future for ClientConn<'a> {
@@ -108,44 +650,71 @@ impl<'a> Future for ClientConn<'a> {
             }
         }
 
-        if self.overflow == None {
-            const LINES_PER_TICK: usize = 10;
-            for i in 0..LINES_PER_TICK {
-                match self.chan_recv.poll().unwrap() {
+        // Leave messages in the queue, where its policy applies to them, until the socket has
+        // taken those already sent
+        let writable = !matches!(self.socket_tx.poll_complete(), Ok(Async::NotReady));
+
+        let mut drained = false;
+        if self.overflow.is_none() && writable {
+            let per_tick = self.state.read().unwrap().config.tuning.messages_per_tick;
+            for i in 0..per_tick {
+                match self.queue.poll() {
                     Async::Ready(Some(msg)) => {
+                        if msg.notice == ServerNotice::FellBehind {
+                            log!(
+                                Warn,
+                                "Client {} fell behind, so it was told to catch up",
+                                self.id
+                            );
+                        }
+                        let msg = self.stamp(msg);
                         if let Ok(AsyncSink::NotReady(msg)) = self.socket_tx.start_send(msg) {
                             self.overflow = Some(msg);
                         }
-                        if i + 1 == LINES_PER_TICK {
+                        if i + 1 == per_tick {
                             task::current().notify();
                         }
                     }
-                    _ => break,
+                    Async::Ready(None) => {
+                        log!(
+                            Warn,
+                            "Disconnecting client {}: it fell too far behind",
+                            self.id
+                        );
+                        return Ok(Async::Ready(()));
+                    }
+                    Async::NotReady => {
+                        drained = true;
+                        break;
+                    }
                 }
             }
         }
 
-        let _ = self.socket_tx.poll_complete();
-
-        while let Async::Ready(msg) = self.socket_rx.poll().unwrap() {
-            if let Some(msg) = msg {
-                println!("{:#?}", msg);
-                let req_id = msg.id;
-                let broadcast = self.broadcast.clone();
-                match msg.request {
-                    ClientRequest::RunCommand(c) => {
-                        let cmd_idx = self.state.write().unwrap().command_history.next_index();
-                        let mut cmd = Command::new("bash");
-                        cmd.arg("-c").arg(&c);
-                        send_notice(&broadcast, req_id, ServerNotice::CommandStarted(cmd_idx, c));
+        let flushed = self.socket_tx.poll_complete();
 
-                        let run_command = RunningCommand::new(cmd, broadcast, req_id, cmd_idx);
+        // Hang up once everything queued, including the reason for hanging up, has been sent
+        if self.closing {
+            return match flushed {
+                Ok(Async::Ready(())) if drained && self.overflow.is_none() => Ok(Async::Ready(())),
+                Ok(_) => Ok(Async::NotReady),
+                Err(_) => Ok(Async::Ready(())),
+            };
+        }
 
-                        tokio::spawn(run_command);
-                    }
+        loop {
+            match self.socket_rx.poll() {
+                Ok(Async::Ready(Some(msg))) => {
+                    log!(Debug, "{:#?}", msg);
+                    self.handle_msg(msg);
                 }
-            } else {
-                return Ok(Async::Ready(()));
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+                Err(e) => self.disconnect(0, format!("Could not decode request: {:?}", e)),
+            }
+            if self.closing {
+                task::current().notify();
+                break;
             }
         }
         Ok(Async::NotReady)
@@ -154,113 +723,877 @@ impl<'a> Future for ClientConn<'a> {
 
 impl<'a> Drop for ClientConn<'a> {
     fn drop(&mut self) {
-        self.state.write().unwrap().channels.remove(&self.id);
+        self.state.write().unwrap().clients.remove(&self.id);
     }
 }
 
+enum Stage {
+    Running(Child),
+    Exited(ExitStatus),
+}
+
+/// Copies one stage's output to the next stage's input, so that the bytes in between can be
+/// counted.
+struct Relay {
+    stage: usize,
+    from: ChildStdout,
+    to: ChildStdin,
+    buf: Vec<u8>,
+    chunk: Vec<u8>,
+    per_tick: usize,
+    bytes: u64,
+    reported: u64,
+}
+
+impl Relay {
+    /// Copies whatever it can, returning false once either stage has closed its end.
+    fn poll(&mut self) -> bool {
+        for _ in 0..self.per_tick {
+            // The next stage exiting should close the pipe for the previous one, like a shell's
+            if poll_write_all(&mut self.to, &mut self.buf).is_err() {
+                return false;
+            }
+            if !self.buf.is_empty() {
+                return true;
+            }
+            match self.from.poll_read(&mut self.chunk) {
+                Ok(Async::Ready(0)) | Err(_) => return false,
+                Ok(Async::Ready(size)) => {
+                    self.buf.extend_from_slice(&self.chunk[..size]);
+                    self.bytes += size as u64;
+                }
+                Ok(Async::NotReady) => return true,
+            }
+        }
+        task::current().notify();
+        true
+    }
+}
+
+/// A script that a function call interrupted, to be resumed when the function returns.
+struct Frame {
+    pipelines: VecDeque<(Connector, Pipeline)>,
+    args: Vec<String>,
+}
+
+/// How deeply functions may call each other, which stops a function that calls itself forever.
+const MAX_FUNCTION_DEPTH: usize = 100;
+
+/// Runs a command's script one pipeline at a time, with every process sharing the command's
+/// stdio, except for the pipes between stages and, outside of a pty, each one's own stderr.
 // XXX Maybe refactor this into Stream<ServerMessage>?
 pub struct RunningCommand {
-    child: Child,
+    pipelines: VecDeque<(Connector, Pipeline)>,
+    session: Session,
+    definitions: Definitions,
+    tuning: Tuning,
+    /// The positional parameters of the function being run, if any.
+    args: Vec<String>,
+    frames: Vec<Frame>,
+    /// The stages of the pipeline being run, along with their index among all of the command's.
+    stages: Vec<(usize, Stage)>,
+    stage_count: usize,
+    /// Each stage's own stderr, when not running in a pty.
+    stage_errs: Vec<(usize, BufReader<ChildStderr>)>,
+    relays: Vec<Relay>,
+    /// The exit status of the last pipeline to finish.
+    status: ExitStatus,
+    /// The script's exit status once it has finished, while the last of its output is read.
+    finished: Option<ExitStatus>,
+    rusage: Option<ResourceUsage>,
+    interrupted: bool,
+    /// The child ends of the command's stdin, stdout and stderr, held until the script finishes.
+    child_stdio: Vec<File>,
     broadcast: UnboundedSender<ServerMessage>,
-    stdout: BufReader<ChildStdout>,
-    stderr: BufReader<ChildStderr>,
+    control: UnboundedReceiver<(ClientRequest, ReplyTo)>,
+    /// The pipe to the command's stdin, if it was run to read input and that's still open.
+    stdin: Option<ChildStdin>,
+    stdin_closed: bool,
+    pty: Option<Pty>,
+    input: Vec<u8>,
+    input_replies: Vec<ReplyTo>,
+    close_input: bool,
+    stdout: Option<BufReader<ChildStdout>>,
+    stderr: Option<BufReader<ChildStderr>>,
     buf: Vec<u8>,
+    batches: Batches<Source>,
+    /// Wakes the command to send its output once that has been held back for long enough.
+    batch_timer: Option<Delay>,
     request_id: u32,
     command_id: CommandId,
 }
 
+/// Where a command's output was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Source {
+    Stdout,
+    Stderr,
+    Pty,
+    /// A stage's own stderr, by its index among the command's stages.
+    StageErr(usize),
+}
+
 // XXX Use tokio-process once fixed: https://github.com/alexcrichton/tokio-process/issues/29
 impl RunningCommand {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        mut cmd: Command,
+        script: Script,
+        session: Session,
+        definitions: Definitions,
+        tuning: Tuning,
         broadcast: UnboundedSender<ServerMessage>,
+        control: UnboundedReceiver<(ClientRequest, ReplyTo)>,
         request_id: u32,
         command_id: CommandId,
-    ) -> Self {
-        let mut child = cmd.stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("Failed to execute child process");
-
-        let stdout = child.stdout.take().unwrap();
-        let stdout = stdio(stdout).unwrap();
-        let stdout = BufReader::new(stdout);
-
-        let stderr = child.stderr.take().unwrap();
-        let stderr = stdio(stderr).unwrap();
-        let stderr = BufReader::new(stderr);
-
-        let child = Child::new(child);
-
-        const CHUNK_SIZE: usize = 1024;
-        let buf = vec![0; CHUNK_SIZE];
-        RunningCommand {
-            child,
+        options: &RunOptions,
+    ) -> io::Result<Self> {
+        let (stdin, stdout, stderr, pty, child_stdio) = match options.pty {
+            Some(size) => {
+                let (master, slave) = openpty(size)?;
+                let child_stdio = vec![slave.try_clone()?, slave.try_clone()?, slave];
+                (None, None, None, Some(stdio(master)?), child_stdio)
+            }
+            None => {
+                // Unless asked for input, anything reading stdin should see the end of it at
+                // once rather than wait for input nobody is going to send
+                let (stdin_read, stdin_write) = match options.input {
+                    true => {
+                        let (read, write) = pipe()?;
+                        (read, Some(stdio(write)?))
+                    }
+                    false => (File::open("/dev/null")?, None),
+                };
+                let (stdout_read, stdout_write) = pipe()?;
+                let (stderr_read, stderr_write) = pipe()?;
+                (
+                    stdin_write,
+                    Some(BufReader::new(stdio(stdout_read)?)),
+                    Some(BufReader::new(stdio(stderr_read)?)),
+                    None,
+                    vec![stdin_read, stdout_write, stderr_write],
+                )
+            }
+        };
+
+        let buf = vec![0; tuning.chunk_size];
+        let input = Vec::new();
+        let input_replies = Vec::new();
+        let close_input = false;
+        Ok(RunningCommand {
+            pipelines: script.pipelines.into_iter().collect(),
+            session,
+            definitions,
+            tuning,
+            args: Vec::new(),
+            frames: Vec::new(),
+            stages: Vec::new(),
+            stage_count: 0,
+            stage_errs: Vec::new(),
+            relays: Vec::new(),
+            status: ExitStatus::Exited(0),
+            finished: None,
+            rusage: None,
+            interrupted: false,
+            child_stdio,
             broadcast,
+            control,
+            stdin,
+            stdin_closed: false,
+            pty,
+            input,
+            input_replies,
+            close_input,
             stdout,
             stderr,
             buf,
+            batches: Batches::default(),
+            batch_timer: None,
             request_id,
             command_id,
+        })
+    }
+
+    /// Runs the rest of the script, until it has finished.
+    fn poll_script(&mut self) -> Async<ExitStatus> {
+        loop {
+            let mut running = false;
+            for &mut (index, ref mut stage) in self.stages.iter_mut() {
+                let status = match *stage {
+                    Stage::Running(ref mut child) => match child.poll() {
+                        Ok(Async::Ready(status)) => {
+                            if let Some(rusage) = child.resource_usage() {
+                                self.rusage
+                                    .get_or_insert_with(ResourceUsage::default)
+                                    .add(&rusage);
+                            }
+                            status
+                        }
+                        Ok(Async::NotReady) => {
+                            running = true;
+                            continue;
+                        }
+                        Err(e) => {
+                            log!(Error, "Lost track of command {}: {}", self.command_id, e);
+                            ExitStatus::Lost
+                        }
+                    },
+                    Stage::Exited(_) => continue,
+                };
+                send_notice(
+                    &self.broadcast,
+                    self.request_id,
+                    ServerNotice::StageExited(self.command_id, index, status),
+                );
+                *stage = Stage::Exited(status);
+            }
+            if running {
+                return Async::NotReady;
+            }
+
+            // A pipeline's status is its last stage's
+            if let Some(&(_, Stage::Exited(status))) = self.stages.last() {
+                self.status = status;
+            }
+            self.stages.clear();
+            if self.interrupted {
+                return Async::Ready(self.status);
+            }
+            let (connector, pipeline) = match self.pipelines.pop_front() {
+                Some(next) => next,
+                None => match self.frames.pop() {
+                    // The function's status is that of the last pipeline it ran
+                    Some(frame) => {
+                        self.pipelines = frame.pipelines;
+                        self.args = frame.args;
+                        continue;
+                    }
+                    None => return Async::Ready(self.status),
+                },
+            };
+            if connector.should_run(self.status.success()) {
+                self.start_pipeline(&pipeline);
+            }
+        }
+    }
+
+    fn start_pipeline(&mut self, pipeline: &Pipeline) {
+        // Like a shell, builtins in a larger pipeline run as if in a subshell
+        let alone = pipeline.stages.len() == 1;
+        if alone && self.call_function(&pipeline.stages[0]) {
+            return;
+        }
+        let mut stdin = None;
+        // Like a shell's job, each pipeline gets a process group of its own, led by its first
+        // process, so that signals reach everything it starts
+        let mut group = 0;
+        for (i, cmd) in pipeline.stages.iter().enumerate() {
+            let index = self.stage_count;
+            self.stage_count += 1;
+            let piped = i + 1 < pipeline.stages.len();
+            let stdio = match self.stage_stdio(index, stdin.take(), piped) {
+                Ok((stdio, next_stdin)) => {
+                    stdin = next_stdin;
+                    Ok(stdio)
+                }
+                Err(e) => Err(e),
+            };
+            let (words, stage) = self.start_stage(cmd, stdio, alone, i == 0, group);
+            let pid = match stage {
+                Stage::Running(ref child) => Some(child.id()),
+                Stage::Exited(_) => None,
+            };
+            if let (0, Some(pid)) = (group, pid) {
+                group = pid;
+            }
+            let mut notices = vec![ServerNotice::StageStarted(
+                self.command_id,
+                index,
+                PipelineStage::new(words, pid, piped),
+            )];
+            if let Stage::Exited(status) = stage {
+                notices.push(ServerNotice::StageExited(self.command_id, index, status));
+            }
+            for notice in notices {
+                send_notice(&self.broadcast, self.request_id, notice);
+            }
+            self.stages.push((index, stage));
+        }
+    }
+
+    /// Starts running the body of the function `cmd` calls, if it calls one without redirecting
+    /// it or setting variables for it, returning whether it did.
+    fn call_function(&mut self, cmd: &SimpleCommand) -> bool {
+        if !cmd.assignments.is_empty() || !cmd.redirects.is_empty() {
+            return false;
+        }
+        let args: Vec<String> = {
+            let expansion = self.expansion();
+            cmd.words
+                .iter()
+                .flat_map(|word| expansion.expand(word))
+                .collect()
+        };
+        let body = match args
+            .first()
+            .and_then(|name| self.definitions.function(name))
+        {
+            Some(body) => body,
+            None => return false,
+        };
+        if self.frames.len() >= MAX_FUNCTION_DEPTH {
+            let _ = writeln!(
+                self.child_stdio[2],
+                "weaverd: functions nested more than {} deep",
+                MAX_FUNCTION_DEPTH
+            );
+            self.status = ExitStatus::Exited(1);
+            return true;
+        }
+        self.frames.push(Frame {
+            pipelines: mem::replace(&mut self.pipelines, body.pipelines.into_iter().collect()),
+            args: mem::replace(&mut self.args, args),
+        });
+        true
+    }
+
+    fn expansion(&self) -> Expansion<'_> {
+        Expansion {
+            env: &self.session.env,
+            cwd: &self.session.cwd,
+            status: self.status.code(),
+            args: &self.args,
         }
     }
+
+    /// The stdio for stage `index`, connected to the previous stage's output if there is one,
+    /// and through a relay to a pipe whose read end is returned for the next stage if `piped`.
+    fn stage_stdio(
+        &mut self,
+        index: usize,
+        stdin: Option<File>,
+        piped: bool,
+    ) -> io::Result<(Vec<File>, Option<File>)> {
+        let mut stdio = self
+            .child_stdio
+            .iter()
+            .map(|file| file.try_clone())
+            .collect::<io::Result<Vec<_>>>()?;
+        if let Some(stdin) = stdin {
+            stdio[0] = stdin;
+        }
+        // In a pty, stderr has to stay the terminal
+        if self.pty.is_none() {
+            let (read, write) = pipe()?;
+            stdio[2] = write;
+            self.stage_errs
+                .push((index, BufReader::new(weaver::process::stdio(read)?)));
+        }
+        let mut next_stdin = None;
+        if piped {
+            let (from, write) = pipe()?;
+            let (read, to) = pipe()?;
+            stdio[1] = write;
+            next_stdin = Some(read);
+            self.relays.push(Relay {
+                stage: index,
+                from: weaver::process::stdio(from)?,
+                to: weaver::process::stdio(to)?,
+                buf: Vec::new(),
+                chunk: vec![0; self.tuning.chunk_size],
+                per_tick: self.tuning.messages_per_tick,
+                bytes: 0,
+                reported: 0,
+            });
+        }
+        Ok((stdio, next_stdin))
+    }
+
+    /// Starts a stage in the process group `group`, or a new one if that's 0, returning its
+    /// expanded words along with it.
+    fn start_stage(
+        &mut self,
+        cmd: &SimpleCommand,
+        stdio: io::Result<Vec<File>>,
+        alone: bool,
+        leader: bool,
+        group: u32,
+    ) -> (String, Stage) {
+        let (words, assignments, targets) = {
+            let expansion = self.expansion();
+            let words: Vec<String> = cmd
+                .words
+                .iter()
+                .flat_map(|word| expansion.expand(word))
+                .collect();
+            let assignments: Vec<(String, String)> = cmd
+                .assignments
+                .iter()
+                .map(|(name, value)| (name.clone(), expansion.expand_text(value)))
+                .collect();
+            let targets: Vec<String> = cmd
+                .redirects
+                .iter()
+                .map(|redirect| expansion.expand_text(&redirect.target))
+                .collect();
+            (words, assignments, targets)
+        };
+        let description = match words.is_empty() {
+            true => assignments
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join(" "),
+            false => words.join(" "),
+        };
+        let mut stdio = match stdio {
+            Ok(stdio) => stdio,
+            Err(e) => {
+                let _ = writeln!(self.child_stdio[2], "weaverd: {}", e);
+                return (description, Stage::Exited(ExitStatus::Exited(1)));
+            }
+        };
+        for (redirect, target) in cmd.redirects.iter().zip(targets) {
+            if let Err(e) = apply_redirect(&mut stdio, redirect, &target, &self.session.cwd) {
+                let _ = writeln!(stdio[2], "weaverd: {}", e);
+                return (description, Stage::Exited(ExitStatus::Exited(1)));
+            }
+        }
+
+        if words.is_empty() {
+            if alone {
+                self.session.env.extend(assignments);
+            }
+            return (description, Stage::Exited(ExitStatus::Exited(0)));
+        }
+        if self.definitions.is_function(&words[0]) {
+            let _ = writeln!(
+                stdio[2],
+                "weaverd: {}: functions can't be piped, redirected or given variables",
+                words[0]
+            );
+            return (description, Stage::Exited(ExitStatus::Exited(1)));
+        }
+        let builtin = match alone {
+            true => self.session.run_builtin(&words),
+            false => self.session.clone().run_builtin(&words),
+        };
+        if let Some(result) = builtin {
+            let written = match result {
+                Ok(ref output) => stdio[1].write_all(output.as_bytes()),
+                Err(ref e) => writeln!(stdio[2], "{}", e),
+            };
+            let status = match (result, written) {
+                (Ok(_), Ok(())) => 0,
+                _ => 1,
+            };
+            return (description, Stage::Exited(ExitStatus::Exited(status)));
+        }
+
+        let mut errors = match stdio[2].try_clone() {
+            Ok(errors) => errors,
+            Err(e) => {
+                log!(
+                    Error,
+                    "Failed to start a stage of command {}: {}",
+                    self.command_id,
+                    e
+                );
+                return (description, Stage::Exited(ExitStatus::Exited(126)));
+            }
+        };
+        let mut process = Command::new(&words[0]);
+        process
+            .args(&words[1..])
+            .current_dir(&self.session.cwd)
+            .env_clear()
+            .envs(&self.session.env)
+            .envs(assignments);
+        // Both only make async-signal-safe calls, as anything run between fork and exec must
+        if leader && self.pty.is_some() {
+            let tty = self.child_stdio[0].as_raw_fd();
+            unsafe { process.pre_exec(move || acquire_controlling_terminal(tty)) };
+        } else {
+            unsafe { process.pre_exec(move || join_process_group(group)) };
+        }
+        let stderr = stdio.pop().unwrap();
+        let stdout = stdio.pop().unwrap();
+        let stdin = stdio.pop().unwrap();
+        process
+            .stdin(Stdio::from(stdin))
+            .stdout(Stdio::from(stdout))
+            .stderr(Stdio::from(stderr));
+        let stage = match process.spawn() {
+            Ok(child) => {
+                send_notice(
+                    &self.broadcast,
+                    self.request_id,
+                    ServerNotice::ProcessSpawned(self.command_id, child.id()),
+                );
+                Stage::Running(Child::new(child))
+            }
+            Err(e) => {
+                let (status, error) = match e.kind() {
+                    io::ErrorKind::NotFound => (127, "command not found".to_owned()),
+                    _ => (126, e.to_string()),
+                };
+                let _ = writeln!(errors, "weaverd: {}: {}", words[0], error);
+                Stage::Exited(ExitStatus::Exited(status))
+            }
+        };
+        (description, stage)
+    }
+
+    /// Sends `signal` to the process group of every stage still running, so that whatever they
+    /// started gets it too.  Returns whether any were signalled, along with why any weren't.
+    fn signal(&mut self, signal: Signal) -> (bool, Vec<String>) {
+        // The rest of the script shouldn't run after it has been asked to stop
+        match signal {
+            Signal::Interrupt | Signal::Terminate | Signal::Kill => self.interrupted = true,
+            Signal::Stop | Signal::Continue => {}
+        }
+        let mut groups = vec![];
+        let mut errors = vec![];
+        for &(index, ref stage) in &self.stages {
+            if let Stage::Running(ref child) = *stage {
+                match child.process_group() {
+                    Ok(pgid) => {
+                        if !groups.contains(&pgid) {
+                            groups.push(pgid);
+                        }
+                    }
+                    Err(e) => errors.push(format!("stage {}: {}", index + 1, e)),
+                }
+            }
+        }
+        let mut delivered = false;
+        for pgid in groups {
+            match signal_process_group(pgid, signal.as_raw()) {
+                Ok(()) => delivered = true,
+                Err(e) => errors.push(format!("process group {}: {}", pgid, e)),
+            }
+        }
+        (delivered, errors)
+    }
 }
 
-impl Future for RunningCommand {
-    type Item = ();
-    type Error = ();
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        const CHUNKS_PER_TICK: usize = 10;
-        for i in 0..CHUNKS_PER_TICK {
-            match self.stdout.poll_read(&mut self.buf).unwrap() {
-                Async::Ready(size) => {
-                    if size > 0 {
-                        let text = String::from_utf8_lossy(&self.buf[..size]).to_string();
+fn apply_redirect(
+    stdio: &mut [File],
+    redirect: &Redirect,
+    target: &str,
+    cwd: &Path,
+) -> Result<(), String> {
+    let fd = redirect.fd as usize;
+    if fd >= stdio.len() {
+        return Err(format!(
+            "{}: only stdin, stdout and stderr can be redirected",
+            fd
+        ));
+    }
+    let file = match redirect.op {
+        RedirectOp::Read => File::open(cwd.join(target)),
+        RedirectOp::Write => File::create(cwd.join(target)),
+        RedirectOp::Append => OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(cwd.join(target)),
+        RedirectOp::Duplicate => match target.parse::<usize>() {
+            Ok(other) if other < stdio.len() => stdio[other].try_clone(),
+            _ => return Err(format!("{}: bad file descriptor", target)),
+        },
+    };
+    stdio[fd] = file.map_err(|e| format!("{}: {}", target, e))?;
+    Ok(())
+}
+
+fn poll_write_all<W: AsyncWrite>(writer: &mut W, buf: &mut Vec<u8>) -> io::Result<()> {
+    while !buf.is_empty() {
+        match writer.poll_write(buf)? {
+            Async::Ready(size) => {
+                let _ = buf.drain(..size);
+            }
+            Async::NotReady => break,
+        }
+    }
+    Ok(())
+}
+
+impl RunningCommand {
+    fn handle_control(&mut self) {
+        while let Async::Ready(Some((request, reply))) = self.control.poll().unwrap() {
+            match request {
+                ClientRequest::Signal(_, signal) => {
+                    let (delivered, errors) = self.signal(signal);
+                    if delivered {
                         send_notice(
                             &self.broadcast,
-                            self.request_id,
-                            ServerNotice::CommandOutput(self.command_id, text),
+                            reply.id,
+                            ServerNotice::SignalDelivered(self.command_id, signal),
                         );
                     }
-                    if i + 1 == CHUNKS_PER_TICK {
-                        task::current().notify();
+                    reply.send(match (delivered, errors.is_empty()) {
+                        (_, false) => Err(errors.join("; ")),
+                        (true, true) => Ok(Response::Done),
+                        (false, true) => Err("No process is running".to_owned()),
+                    });
+                }
+                ClientRequest::SendInput(_, input) => {
+                    if self.stdin.is_some() || self.pty.is_some() {
+                        self.input.extend(input);
+                        self.input_replies.push(reply);
+                    } else if self.stdin_closed {
+                        reply.send(Err("Input is already closed".to_owned()));
+                    } else {
+                        reply.send(Err("Command was not run to read input".to_owned()));
                     }
                 }
-                _ => break,
+                ClientRequest::CloseInput(_) => match self.pty {
+                    // ^D, the terminal's end-of-file character
+                    Some(_) => {
+                        self.input.push(4);
+                        self.input_replies.push(reply);
+                    }
+                    None if self.stdin.is_some() => {
+                        self.close_input = true;
+                        self.input_replies.push(reply);
+                    }
+                    None => reply.send(Ok(Response::Done)),
+                },
+                ClientRequest::ResizePty(_, size) => {
+                    let result = match self.pty {
+                        Some(ref pty) => set_window_size(pty.get_ref().as_raw_fd(), size),
+                        None => Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Command is not running in a pty",
+                        )),
+                    };
+                    match result {
+                        Ok(()) => {
+                            send_notice(
+                                &self.broadcast,
+                                reply.id,
+                                ServerNotice::PtyResized(self.command_id, size),
+                            );
+                            reply.send(Ok(Response::Done));
+                        }
+                        Err(e) => reply.send(Err(e.to_string())),
+                    }
+                }
+                _ => reply.send(Err("Not a request for a running command".to_owned())),
             }
         }
+    }
 
-        for i in 0..CHUNKS_PER_TICK {
-            match self.stderr.poll_read(&mut self.buf).unwrap() {
-                Async::Ready(size) => {
-                    if size > 0 {
-                        let text = String::from_utf8_lossy(&self.buf[..size]).to_string();
-                        send_notice(
-                            &self.broadcast,
-                            self.request_id,
-                            ServerNotice::CommandErr(self.command_id, text),
-                        );
-                    }
-                    if i + 1 == CHUNKS_PER_TICK {
+    fn write_input(&mut self) {
+        let result = match (&mut self.stdin, &mut self.pty) {
+            (&mut Some(ref mut stdin), _) => poll_write_all(stdin, &mut self.input),
+            (_, &mut Some(ref mut pty)) => poll_write_all(pty, &mut self.input),
+            _ => return,
+        };
+        if let Err(e) = result {
+            self.input.clear();
+            self.close_stdin();
+            for reply in self.input_replies.drain(..) {
+                reply.send(Err(e.to_string()));
+            }
+            return;
+        }
+        if self.input.is_empty() {
+            if self.close_input {
+                self.close_input = false;
+                self.close_stdin();
+            }
+            for reply in self.input_replies.drain(..) {
+                reply.send(Ok(Response::Done));
+            }
+        }
+    }
+
+    fn close_stdin(&mut self) {
+        if self.stdin.take().is_some() {
+            self.stdin_closed = true;
+            send_notice(
+                &self.broadcast,
+                self.request_id,
+                ServerNotice::StdinChanged(self.command_id, StdinState::Closed),
+            );
+        }
+    }
+
+    /// Fails any requests still waiting on the command, which has exited.
+    fn reject_pending(&mut self) {
+        for reply in self.input_replies.drain(..) {
+            reply.send(Err("Command exited before reading its input".to_owned()));
+        }
+        self.control.close();
+        while let Ok(Async::Ready(Some((_, reply)))) = self.control.poll() {
+            reply.send(Err("Command is not running".to_owned()));
+        }
+    }
+
+    /// Reads available output from `reader` into its batch, returning false once it has been
+    /// exhausted.
+    fn read_output<R: AsyncRead>(&mut self, reader: &mut R, source: Source) -> bool {
+        let per_tick = self.tuning.messages_per_tick;
+        for i in 0..per_tick {
+            match reader.poll_read(&mut self.buf) {
+                Ok(Async::Ready(0)) => return false,
+                Ok(Async::Ready(size)) => {
+                    self.batch_output(source, size);
+                    self.resize_buffer(size);
+                    if i + 1 == per_tick {
                         task::current().notify();
                     }
                 }
-                _ => break,
+                Ok(Async::NotReady) => break,
+                // A pty master reports EIO once the slave side has been closed
+                Err(_) => return false,
             }
         }
+        true
+    }
+
+    /// Adds the `size` bytes just read to the output waiting to be sent, sending it once there
+    /// is enough.
+    fn batch_output(&mut self, source: Source, size: usize) {
+        if self.batches.is_empty() {
+            let window = Duration::from_millis(self.tuning.batch_ms);
+            self.batch_timer = Some(Delay::new(Instant::now() + window));
+        }
+        self.batches.push(source, &self.buf[..size]);
+        if self.batches.len() >= self.tuning.batch_bytes {
+            self.send_output();
+        }
+    }
+
+    fn resize_buffer(&mut self, size: usize) {
+        let len = batch::next_chunk_size(self.buf.len(), size, &self.tuning);
+        self.buf.resize(len, 0);
+    }
+
+    /// Sends the output held back once it has waited long enough.
+    fn poll_batches(&mut self) {
+        let due = match self.batch_timer {
+            Some(ref mut timer) => timer.poll().map(|ready| ready.is_ready()).unwrap_or(true),
+            None => false,
+        };
+        if due {
+            self.send_output();
+        }
+    }
+
+    /// Sends all of the output held back, a notice for each run of it read from the same place.
+    fn send_output(&mut self) {
+        for (source, bytes) in self.batches.take() {
+            self.send_notices(source, bytes);
+        }
+        self.batch_timer = None;
+    }
 
-        return match self.child.poll().unwrap() {
-            Async::Ready(status) => {
+    /// Sends the last of the output, including anything that ended partway through a character.
+    fn finish_output(&mut self) {
+        for (source, bytes) in self.batches.finish() {
+            self.send_notices(source, bytes);
+        }
+        self.batch_timer = None;
+    }
+
+    fn send_notices(&self, source: Source, bytes: Vec<u8>) {
+        let id = self.command_id;
+        let notices = match source {
+            Source::Stdout => vec![ServerNotice::CommandOutput(id, bytes)],
+            Source::Stderr => vec![ServerNotice::CommandErr(id, bytes)],
+            Source::Pty => vec![ServerNotice::CommandPtyOutput(id, bytes)],
+            Source::StageErr(index) => {
+                let text = String::from_utf8_lossy(&bytes).to_string();
+                vec![
+                    ServerNotice::CommandErr(id, bytes),
+                    ServerNotice::StageErr(id, index, text),
+                ]
+            }
+        };
+        for notice in notices {
+            send_notice(&self.broadcast, self.request_id, notice);
+        }
+    }
+}
+
+impl Future for RunningCommand {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        self.handle_control();
+        self.write_input();
+        if self.finished.is_none() {
+            if let Async::Ready(status) = self.poll_script() {
+                // Our copies would otherwise keep the output from ever reaching its end
+                self.child_stdio.clear();
+                self.finished = Some(status);
+            }
+        }
+
+        if let Some(mut stdout) = self.stdout.take() {
+            if self.read_output(&mut stdout, Source::Stdout) {
+                self.stdout = Some(stdout);
+            }
+        }
+
+        if let Some(mut stderr) = self.stderr.take() {
+            if self.read_output(&mut stderr, Source::Stderr) {
+                self.stderr = Some(stderr);
+            }
+        }
+
+        if let Some(mut pty) = self.pty.take() {
+            if self.read_output(&mut pty, Source::Pty) {
+                self.pty = Some(pty);
+            }
+        }
+
+        for (index, mut stderr) in mem::take(&mut self.stage_errs) {
+            if self.read_output(&mut stderr, Source::StageErr(index)) {
+                self.stage_errs.push((index, stderr));
+            }
+        }
+        self.poll_batches();
+
+        for mut relay in mem::take(&mut self.relays) {
+            let open = relay.poll();
+            if relay.bytes != relay.reported {
+                relay.reported = relay.bytes;
                 send_notice(
                     &self.broadcast,
                     self.request_id,
-                    ServerNotice::CommandCompleted(self.command_id, status.code().unwrap()),
+                    ServerNotice::StagePiped(self.command_id, relay.stage, relay.bytes),
                 );
+            }
+            if open {
+                self.relays.push(relay);
+            }
+        }
+
+        let drained = self.stdout.is_none()
+            && self.stderr.is_none()
+            && self.pty.is_none()
+            && self.stage_errs.is_empty()
+            && self.relays.is_empty();
+        match self.finished {
+            Some(status) if drained => {
+                self.finish_output();
+                send_notice(
+                    &self.broadcast,
+                    self.request_id,
+                    ServerNotice::CommandCompleted(
+                        self.command_id,
+                        status,
+                        SystemTime::now(),
+                        self.rusage,
+                    ),
+                );
+                self.reject_pending();
                 Ok(Async::Ready(()))
             }
             _ => Ok(Async::NotReady),
-        };
+        }
     }
 }
 
@@ -268,29 +1601,103 @@ pub struct WeaverServer {
     state: Arc<RwLock<ServerState>>,
     broadcast_recv: UnboundedReceiver<ServerMessage>,
     broadcast_send: UnboundedSender<ServerMessage>,
+    reload_recv: UnboundedReceiver<ReplyTo>,
+    reload_send: UnboundedSender<ReplyTo>,
+    sighup: FlattenStream<IoFuture<UnixSignal>>,
     listener: UnixListener,
+    lock: SocketLock,
     next_client_id: ClientID,
+    gc_interval: Interval,
+    sample_interval: Option<Interval>,
+    sample_period: u64,
+    sampler: Sampler,
+}
+
+/// Samples running commands' resource usage every `period` milliseconds, unless it is 0.
+fn sample_interval(period: u64) -> Option<Interval> {
+    match period {
+        0 => None,
+        _ => Some(Interval::new(Instant::now(), Duration::from_millis(period))),
+    }
 }
 
 impl WeaverServer {
-    pub fn new() -> Self {
-        let socketpath = weaver_socket_path();
-        let _ = std::fs::remove_file(&socketpath);
+    /// Listens on the socket that `lock` holds.
+    pub fn new(config: Config, lock: SocketLock, paths: &WeaverPaths) -> io::Result<Self> {
+        let sample_period = config.tuning.sample_interval_ms;
 
-        let state = Arc::new(RwLock::new(ServerState::new()));
+        let (store, command_history) = HistoryStore::open(&paths.history)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", paths.history.display(), e)))?;
+        let state = ServerState::new(store, command_history, config, paths.config.clone());
+        let state = Arc::new(RwLock::new(state));
         let (broadcast_send, broadcast_recv): (
             UnboundedSender<ServerMessage>,
             UnboundedReceiver<ServerMessage>,
         ) = unbounded();
-        let listener = UnixListener::bind(socketpath).unwrap();
+        let (reload_send, reload_recv): (UnboundedSender<ReplyTo>, UnboundedReceiver<ReplyTo>) =
+            unbounded();
+        let sighup = UnixSignal::new(SIGHUP).flatten_stream();
+        let listener = lock.bind()?;
         let next_client_id = 1;
+        let gc_period = Duration::from_secs(10);
+        let gc_interval = Interval::new(Instant::now() + gc_period, gc_period);
+        let sample_interval = sample_interval(sample_period);
 
-        WeaverServer {
+        Ok(WeaverServer {
             state,
             broadcast_recv,
             broadcast_send,
+            reload_recv,
+            reload_send,
+            sighup,
             listener,
+            lock,
             next_client_id,
+            gc_interval,
+            sample_interval,
+            sample_period,
+            sampler: Sampler::new(),
+        })
+    }
+
+    /// Rereads the config file, moving the socket and restarting sampling if they've changed.
+    fn reload_config(&mut self) -> Result<(), String> {
+        let mut state = self.state.write().unwrap();
+        state.reload_config()?;
+
+        let sample_period = state.config.tuning.sample_interval_ms;
+        if sample_period != self.sample_period {
+            self.sample_interval = sample_interval(sample_period);
+            self.sample_period = sample_period;
+        }
+
+        let socket_path = state.config.socket_path();
+        if socket_path != self.lock.path() {
+            let (lock, listener) = SocketLock::acquire(&socket_path)
+                .and_then(|lock| lock.bind().map(|listener| (lock, listener)))
+                .map_err(|e| {
+                    format!(
+                        "Reloaded config, but failed to listen on {}: {}",
+                        socket_path.display(),
+                        e
+                    )
+                })?;
+            let _ = fs::remove_file(self.lock.path());
+            self.lock = lock;
+            self.listener = listener;
+        }
+        Ok(())
+    }
+
+    /// Samples running commands' resource usage.  Reading every process's stats takes a while,
+    /// so it's done without holding the state's lock, which every client needs.
+    fn sample_resources(&mut self) {
+        let running = self.state.read().unwrap().running_pids();
+        let samples = self.sampler.sample(&running);
+        let mut state = self.state.write().unwrap();
+        for (id, sample) in samples {
+            let notice = ServerNotice::ResourceSampled(id, sample);
+            state.dispatch(ServerMessage::new(0, notice));
         }
     }
 
@@ -305,47 +1712,51 @@ impl Future for WeaverServer {
     type Item = ();
     type Error = ();
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        const LINES_PER_TICK: usize = 10;
-        for i in 0..LINES_PER_TICK {
-            match self.listener.poll_accept().unwrap() {
-                Async::Ready((socket, _addr)) => {
-                    let client = ClientConn::new(
-                        self.next_client_id(),
-                        socket,
-                        self.state.clone(),
-                        self.broadcast_send.clone(),
-                    );
-                    send_notice(
-                        &client.chan_send,
-                        0,
-                        ServerNotice::CommandsBulk(
-                            self.state
-                                .read()
-                                .unwrap()
-                                .command_history
-                                .clone()
-                                .into_iter()
-                                .collect(),
-                        ),
-                    );
-                    tokio::spawn(client);
-                    if i + 1 == LINES_PER_TICK {
+        while let Ok(Async::Ready(Some(_))) = self.sighup.poll() {
+            if let Err(e) = self.reload_config() {
+                log!(Error, "Failed to reload config: {}", e);
+            }
+        }
+        while let Async::Ready(Some(reply)) = self.reload_recv.poll().unwrap() {
+            let result = self.reload_config();
+            reply.send(result.map(|()| Response::Done));
+        }
+
+        let per_tick = self.state.read().unwrap().config.tuning.messages_per_tick;
+        for i in 0..per_tick {
+            match self.listener.poll_accept() {
+                Ok(Async::Ready((socket, _addr))) => {
+                    match check_peer(&socket) {
+                        Ok(()) => {
+                            let client = ClientConn::new(
+                                self.next_client_id(),
+                                socket,
+                                self.state.clone(),
+                                self.broadcast_send.clone(),
+                                self.reload_send.clone(),
+                            );
+                            tokio::spawn(client);
+                        }
+                        Err(e) => log!(Warn, "Refusing a connection: {}", e),
+                    }
+                    if i + 1 == per_tick {
                         task::current().notify();
                     }
                 }
-                _ => break,
+                Ok(Async::NotReady) => break,
+                // Most likely out of file descriptors, which the clients we have may yet free up
+                Err(e) => {
+                    log!(Error, "Failed to accept connection: {}", e);
+                    break;
+                }
             }
         }
 
-        for i in 0..LINES_PER_TICK {
+        for i in 0..per_tick {
             match self.broadcast_recv.poll().unwrap() {
                 Async::Ready(Some(msg)) => {
-                    let mut state = self.state.write().unwrap();
-                    state.command_history.do_update(msg.clone());
-                    for (_id, chan) in &state.channels {
-                        chan.unbounded_send(msg.clone()).unwrap();
-                    }
-                    if i + 1 == LINES_PER_TICK {
+                    self.state.write().unwrap().dispatch(msg);
+                    if i + 1 == per_tick {
                         task::current().notify();
                     }
                 }
@@ -353,12 +1764,69 @@ impl Future for WeaverServer {
             }
         }
 
+        let mut sample = false;
+        if let Some(ref mut interval) = self.sample_interval {
+            while let Async::Ready(Some(_)) = interval.poll().unwrap() {
+                sample = true;
+            }
+        }
+        if sample {
+            self.sample_resources();
+        }
+
+        let mut state = self.state.write().unwrap();
+        while let Async::Ready(Some(_)) = self.gc_interval.poll().unwrap() {
+            state.collect_garbage();
+        }
+        state.flush();
+
         Ok(Async::NotReady)
     }
 }
 
+/// Starts listening, detaching first if asked to.
+fn start(detach: bool, paths: &WeaverPaths) -> io::Result<WeaverServer> {
+    let path = &paths.config;
+    let config = Config::load(path).unwrap_or_else(|e| {
+        log!(Warn, "Ignoring {}: {}", path.display(), e);
+        Config::default()
+    });
+    // Only the daemon holding the lock may touch the history, and failing to get it is best
+    // reported before detaching
+    let socket_path = config.socket_path();
+    let mut lock = SocketLock::acquire(&socket_path).map_err(|e| match e.kind() {
+        io::ErrorKind::AddrInUse => e,
+        kind => io::Error::new(kind, format!("{}: {}", socket_path.display(), e)),
+    })?;
+    if detach {
+        weaver::process::detach(&paths.log)?;
+        lock.record_pid()?;
+        if let Some(home) = env::home_dir() {
+            env::set_current_dir(home)?;
+        }
+    }
+    WeaverServer::new(config, lock, paths)
+}
+
 fn main() {
-    let server = WeaverServer::new();
+    let args = args::parse("weaverd", &["--detach", "-v", "-vv"], env::args().skip(1))
+        .unwrap_or_else(|usage| {
+            eprintln!("{}", usage);
+            process::exit(2);
+        });
+    args.export_socket();
+    let verbosity = args.flags.iter().map(|flag| match flag.as_str() {
+        "-v" => 1,
+        "-vv" => 2,
+        _ => 0,
+    });
+    weaver::log::set_verbosity(verbosity.sum());
+    let detach = args.given("--detach");
+    let server = WeaverPaths::resolve()
+        .and_then(|paths| start(detach, &paths))
+        .unwrap_or_else(|e| {
+            eprintln!("weaverd: {}", e);
+            process::exit(1);
+        });
     tokio::run(server);
-    println!("Hello, world!");
 }
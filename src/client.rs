@@ -1,27 +1,115 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::error::Error;
 use std::fmt;
+use std::fs::OpenOptions;
 use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use futures::sync::mpsc::SendError as FutureSendError;
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
 use futures::AsyncSink;
 
 use tokio::prelude::{task, Async, Future, Sink, Stream};
+use tokio::timer::Delay;
 use tokio_serde_msgpack::{from_io, DecodeError, MsgPackReader, MsgPackWriter};
 use tokio_uds::UnixStream;
 
-use super::{weaver_socket_path, ClientMessage, ClientRequest, CommandHistory, ServerMessage};
+use super::{
+    ClientInfo, ClientMessage, ClientRequest, CommandHistory, CommandId, Definition,
+    DefinitionKind, Hello, OutputOffsets, OutputStream, Response, RunOptions, ServerMessage,
+    ServerNotice, Signal, Truncation, WeaverCommand, WeaverPaths, WindowSize, OUTPUT_STREAMS,
+};
+
+/// How many commands to request in each page of history.
+pub const HISTORY_PAGE_SIZE: usize = 50;
+/// How much of each stream to include with a page of history.
+pub const HISTORY_TAIL_BYTES: usize = 4096;
 
 #[derive(Debug, PartialEq)]
 pub enum WeaverNotification {
     Updated,
     Server(ServerMessage),
+    /// The connection to the daemon has ended, for the given reason.
+    Disconnected(String),
+    /// The connection to the daemon was lost, for the given reason, and is being reestablished.
+    ConnectionLost(String),
+    /// The connection is back, and the history will shortly catch up with what was missed.
+    Reconnected,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The connection to the daemon was lost before it replied.
+    Disconnected,
+    Failed(String),
+    UnexpectedResponse(Response),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RequestError::Disconnected => write!(f, "Disconnected from weaver daemon"),
+            RequestError::Failed(ref e) => write!(f, "{}", e),
+            RequestError::UnexpectedResponse(ref r) => write!(f, "Unexpected response {:?}", r),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn description(&self) -> &str {
+        "weaver request failed"
+    }
+}
+
+/// Resolves to the daemon's reply to a request.
+pub struct ResponseFuture {
+    reply: oneshot::Receiver<Result<Response, String>>,
+}
+
+impl Future for ResponseFuture {
+    type Item = Response;
+    type Error = RequestError;
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        match self.reply.poll() {
+            Ok(Async::Ready(Ok(response))) => Ok(Async::Ready(response)),
+            Ok(Async::Ready(Err(e))) => Err(RequestError::Failed(e)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(RequestError::Disconnected),
+        }
+    }
+}
+
+/// The part of a stream held locally, in the daemon's offsets.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoadedRange {
+    pub start: usize,
+    pub end: usize,
 }
 
 pub struct WeaverState {
     pub command_history: CommandHistory,
     pub commands_tx: UnboundedSender<ClientMessage>,
+    /// The daemon's half of the handshake, once it has been received.
+    pub server: Option<Hello>,
+    /// Whether the daemon has commands older than any loaded so far.
+    pub more_history: bool,
+    kind: String,
+    /// The sequence number of the last notice applied, once there is one to continue from.
+    last_seq: Option<u64>,
+    history_request: Option<u32>,
+    resync_request: Option<u32>,
+    replay_request: Option<u32>,
+    loaded: HashMap<(CommandId, OutputStream), LoadedRange>,
+    output_requests: HashMap<u32, (CommandId, OutputStream)>,
+    pending: HashMap<u32, oneshot::Sender<Result<Response, String>>>,
     msgcounter: u32,
 }
 
@@ -34,66 +122,526 @@ impl fmt::Debug for WeaverState {
 }
 
 impl WeaverState {
-    pub fn new(commands_tx: UnboundedSender<ClientMessage>) -> Self {
+    pub fn new(kind: &str, commands_tx: UnboundedSender<ClientMessage>) -> Self {
         let command_history = CommandHistory::new();
         let msgcounter = 0;
         WeaverState {
             commands_tx,
             command_history,
+            server: None,
+            more_history: true,
+            kind: kind.to_owned(),
+            last_seq: None,
+            history_request: None,
+            resync_request: None,
+            replay_request: None,
+            loaded: HashMap::new(),
+            output_requests: HashMap::new(),
+            pending: HashMap::new(),
             msgcounter,
         }
     }
-    pub fn run_command(&mut self, cmd: String) -> Result<(), FutureSendError<ClientMessage>> {
-        let request = ClientRequest::RunCommand(cmd);
+
+    /// Requests the page of history preceding the oldest command loaded so far.
+    pub fn load_more_history(&mut self) -> Result<(), FutureSendError<ClientMessage>> {
+        if let Some(msg) = self.history_message() {
+            let id = msg.id;
+            self.commands_tx.unbounded_send(msg)?;
+            self.history_request = Some(id);
+        }
+        Ok(())
+    }
+
+    /// The request for the next page of history, unless one is already waiting for a reply or
+    /// there is none; the caller notes it in `history_request` once it's on its way.
+    fn history_message(&mut self) -> Option<ClientMessage> {
+        if self.history_request.is_some() || !self.more_history {
+            return None;
+        }
+        let before = self.command_history.commands.keys().next().cloned();
+        let request = ClientRequest::ListCommands(before, HISTORY_PAGE_SIZE, HISTORY_TAIL_BYTES);
+        Some(self.next_message(request))
+    }
+
+    /// The messages to start a new connection with, before any other requests.  The first
+    /// connection loads the latest history, and later ones catch up on whatever was missed.
+    fn connection_messages(&mut self) -> Vec<ClientMessage> {
+        let hello = ClientRequest::Hello(Hello::new(&self.kind));
+        let mut messages = vec![self.next_message(hello)];
+        messages.extend(self.catch_up_messages());
+        messages
+    }
+
+    /// Requests whatever was missed while not hearing from the daemon: the notices since the
+    /// last one applied if it still has them, or else the commands that changed.
+    fn catch_up_messages(&mut self) -> Vec<ClientMessage> {
+        let mut messages = vec![];
+        if self.command_history.commands.is_empty() {
+            self.more_history = true;
+            if let Some(msg) = self.history_message() {
+                self.history_request = Some(msg.id);
+                messages.push(msg);
+            }
+        }
+        if self.replay_request.is_some() || self.resync_request.is_some() {
+            return messages;
+        }
+        if let Some(seq) = self.last_seq {
+            let msg = self.next_message(ClientRequest::Replay(seq));
+            self.replay_request = Some(msg.id);
+            messages.push(msg);
+        } else if !self.command_history.commands.is_empty() {
+            let held = self.command_history.commands.keys().cloned().collect();
+            let running = self
+                .command_history
+                .commands
+                .iter()
+                .filter(|&(_, cmd)| cmd.status.is_none())
+                .map(|(&i, _)| i)
+                .collect();
+            let request = ClientRequest::Resync(held, running, HISTORY_TAIL_BYTES);
+            let msg = self.next_message(request);
+            self.resync_request = Some(msg.id);
+            messages.push(msg);
+        }
+        messages
+    }
+
+    fn catch_up(&mut self) {
+        for msg in self.catch_up_messages() {
+            let _ = self.commands_tx.unbounded_send(msg);
+        }
+    }
+
+    /// Fails the requests sent over a connection that was lost, up to and including `last_sent`.
+    fn connection_lost(&mut self, last_sent: u32) {
+        self.server = None;
+        self.pending.retain(|&id, _| id > last_sent);
+        self.output_requests.retain(|&id, _| id > last_sent);
+        // Whichever connection these were sent on, the next one asks again
+        self.history_request = None;
+        self.resync_request = None;
+        self.replay_request = None;
+    }
+
+    /// Requests up to `bytes` of a stream's output preceding what has been loaded so far.
+    pub fn load_output(
+        &mut self,
+        id: CommandId,
+        stream: OutputStream,
+        bytes: usize,
+    ) -> Result<(), FutureSendError<ClientMessage>> {
+        let start = match self.loaded.get(&(id, stream)) {
+            Some(range) if range.start > 0 => range.start,
+            _ => return Ok(()),
+        };
+        if self.output_requests.values().any(|&r| r == (id, stream)) {
+            return Ok(());
+        }
+        let request = ClientRequest::FetchOutput(id, stream, start.saturating_sub(bytes), start);
+        let msg_id = self.send_message(request)?;
+        self.output_requests.insert(msg_id, (id, stream));
+        Ok(())
+    }
+
+    pub fn loaded_output(&self, id: CommandId, stream: OutputStream) -> Option<LoadedRange> {
+        self.loaded.get(&(id, stream)).cloned()
+    }
+
+    /// Fetches all of a stream's output that the daemon still has, exactly as the command wrote
+    /// it, along with where any of it was discarded.
+    pub fn fetch_output(
+        &mut self,
+        id: CommandId,
+        stream: OutputStream,
+    ) -> impl Future<Item = (Vec<u8>, Option<Truncation>), Error = RequestError> {
+        self.send_request(ClientRequest::FetchOutput(id, stream, 0, usize::MAX))
+            .and_then(|response| match response {
+                Response::OutputRange(_, _, _, data, truncation) => Ok((data, truncation)),
+                response => Err(RequestError::UnexpectedResponse(response)),
+            })
+    }
+
+    pub fn run_command(
+        &mut self,
+        cmd: String,
+    ) -> impl Future<Item = CommandId, Error = RequestError> {
+        self.run_command_with(cmd, RunOptions::default())
+    }
+
+    pub fn run_pty_command(
+        &mut self,
+        cmd: String,
+        size: WindowSize,
+    ) -> impl Future<Item = CommandId, Error = RequestError> {
+        let options = RunOptions {
+            pty: Some(size),
+            ..RunOptions::default()
+        };
+        self.run_command_with(cmd, options)
+    }
+
+    /// Runs `cmd`, resolving to the id the daemon assigned it once it has started.
+    pub fn run_command_with(
+        &mut self,
+        cmd: String,
+        options: RunOptions,
+    ) -> impl Future<Item = CommandId, Error = RequestError> {
+        let request = ClientRequest::RunCommand(cmd, options);
+        self.send_request(request).and_then(command_started)
+    }
+
+    pub fn resize_pty(&mut self, id: CommandId, size: WindowSize) -> ResponseFuture {
+        let request = ClientRequest::ResizePty(id, size);
         self.send_request(request)
     }
 
-    pub fn send_request(
+    pub fn signal_command(&mut self, id: CommandId, signal: Signal) -> ResponseFuture {
+        let request = ClientRequest::Signal(id, signal);
+        self.send_request(request)
+    }
+
+    /// Resolves once the daemon has written all of `input` to the command.
+    pub fn send_input(&mut self, id: CommandId, input: Vec<u8>) -> ResponseFuture {
+        let request = ClientRequest::SendInput(id, input);
+        self.send_request(request)
+    }
+
+    pub fn close_input(&mut self, id: CommandId) -> ResponseFuture {
+        let request = ClientRequest::CloseInput(id);
+        self.send_request(request)
+    }
+
+    /// Defines an alias or function for every client, replacing any with the same name.
+    pub fn define(&mut self, definition: Definition) -> ResponseFuture {
+        self.send_request(ClientRequest::Define(definition))
+    }
+
+    pub fn list_definitions(
+        &mut self,
+    ) -> impl Future<Item = Vec<Definition>, Error = RequestError> {
+        self.send_request(ClientRequest::ListDefinitions)
+            .and_then(|response| match response {
+                Response::Definitions(definitions) => Ok(definitions),
+                response => Err(RequestError::UnexpectedResponse(response)),
+            })
+    }
+
+    /// Lists the clients connected to the daemon, with how far behind each one is.
+    pub fn list_clients(&mut self) -> impl Future<Item = Vec<ClientInfo>, Error = RequestError> {
+        self.send_request(ClientRequest::ListClients)
+            .and_then(|response| match response {
+                Response::Clients(clients) => Ok(clients),
+                response => Err(RequestError::UnexpectedResponse(response)),
+            })
+    }
+
+    pub fn remove_definition(&mut self, kind: DefinitionKind, name: String) -> ResponseFuture {
+        self.send_request(ClientRequest::RemoveDefinition(kind, name))
+    }
+
+    /// Has the daemon reread its config file.
+    pub fn reload_config(&mut self) -> ResponseFuture {
+        self.send_request(ClientRequest::ReloadConfig)
+    }
+
+    pub fn send_request(&mut self, request: ClientRequest) -> ResponseFuture {
+        let (reply_tx, reply) = oneshot::channel();
+        // If the request can't be sent, dropping `reply_tx` resolves the future as disconnected
+        if let Ok(id) = self.send_message(request) {
+            self.pending.insert(id, reply_tx);
+        }
+        ResponseFuture { reply }
+    }
+
+    /// Sends a request whose reply is handled by `do_update` rather than a `ResponseFuture`.
+    fn send_message(
         &mut self,
         request: ClientRequest,
-    ) -> Result<(), FutureSendError<ClientMessage>> {
+    ) -> Result<u32, FutureSendError<ClientMessage>> {
+        let msg = self.next_message(request);
+        let id = msg.id;
+        self.commands_tx.unbounded_send(msg)?;
+        Ok(id)
+    }
+
+    fn next_message(&mut self, request: ClientRequest) -> ClientMessage {
         self.msgcounter += 1;
-        let id = self.msgcounter;
-        let msg = ClientMessage { id, request };
-        self.commands_tx.unbounded_send(msg)
+        ClientMessage {
+            id: self.msgcounter,
+            request,
+        }
     }
 
     fn do_update(&mut self, msg: ServerMessage) -> Option<WeaverNotification> {
+        use ServerNotice::*;
+        if msg.notice.is_broadcast() {
+            match self.last_seq {
+                // Already applied, as when replayed after arriving anyway
+                Some(last) if msg.seq <= last => return None,
+                // Missed some, which replaying will resend along with this one
+                Some(last) if msg.seq - msg.coalesced != last + 1 => {
+                    self.catch_up();
+                    return None;
+                }
+                _ => self.last_seq = Some(msg.seq),
+            }
+        }
+        // Commands we haven't loaded will arrive with their output so far in a later page.
+        if let Some(i) = msg.notice.command_id() {
+            let started = matches!(msg.notice, CommandStarted(..));
+            if !started && !self.command_history.commands.contains_key(&i) {
+                return None;
+            }
+        }
+        let time = msg.time;
+        let notice = match msg.notice {
+            Reply(result) => {
+                self.handle_reply(msg.id, msg.seq, result);
+                return Some(WeaverNotification::Updated);
+            }
+            ServerNotice::Hello(hello) => {
+                self.server = Some(hello);
+                // A reconnected client continues from where it was instead
+                if self.last_seq.is_none() {
+                    self.last_seq = Some(msg.seq);
+                }
+                return None;
+            }
+            Incompatible(reason) => return Some(WeaverNotification::Disconnected(reason)),
+            FellBehind => {
+                self.catch_up();
+                return None;
+            }
+            OutputTruncated(i, stream, offset, bytes) => {
+                match self.translate_truncation(i, stream, offset, bytes) {
+                    Some(notice) => notice,
+                    None => return Some(WeaverNotification::Updated),
+                }
+            }
+            notice => notice,
+        };
+        match notice {
+            CommandStarted(i, _, _) => {
+                for &stream in OUTPUT_STREAMS.iter() {
+                    self.loaded.insert((i, stream), LoadedRange::default());
+                }
+            }
+            CommandOutput(i, ref bytes) => self.extend_loaded(i, OutputStream::Stdout, bytes.len()),
+            CommandErr(i, ref bytes) => self.extend_loaded(i, OutputStream::Stderr, bytes.len()),
+            CommandPtyOutput(i, ref bytes) => self.extend_loaded(i, OutputStream::Pty, bytes.len()),
+            CommandsExpired(ref ids) => {
+                for &i in ids {
+                    for &stream in OUTPUT_STREAMS.iter() {
+                        self.loaded.remove(&(i, stream));
+                    }
+                }
+            }
+            CommandsBulk(ref cmds) => {
+                for &(i, ref cmd) in cmds {
+                    for &stream in OUTPUT_STREAMS.iter() {
+                        let end = cmd.output_len(stream);
+                        self.loaded
+                            .insert((i, stream), LoadedRange { start: 0, end });
+                    }
+                }
+            }
+            _ => {}
+        }
+        let mut msg = ServerMessage::new(msg.id, notice);
+        msg.time = time;
         self.command_history.do_update(msg);
         Some(WeaverNotification::Updated)
     }
+
+    /// Handles the reply to a request, which is up to date with the notice numbered `seq`.
+    fn handle_reply(&mut self, id: u32, seq: u64, result: Result<Response, String>) {
+        if self.history_request == Some(id) {
+            self.history_request = None;
+        }
+        if self.resync_request == Some(id) {
+            self.resync_request = None;
+            if result.is_ok() {
+                self.last_seq = Some(seq);
+            }
+        }
+        if self.replay_request == Some(id) {
+            self.replay_request = None;
+            // Replayed notices arrive before the reply, so only a failure needs handling
+            if result.is_err() {
+                self.last_seq = None;
+                self.catch_up();
+            }
+        }
+        self.output_requests.remove(&id);
+        if let Some(reply_tx) = self.pending.remove(&id) {
+            let _ = reply_tx.send(result);
+            return;
+        }
+        match result {
+            Ok(Response::CommandsPage(page, more)) => self.merge_page(page, more),
+            Ok(Response::Resynced(page, expired)) => self.resync(page, expired),
+            Ok(Response::OutputRange(i, stream, start, data, truncation)) => {
+                self.merge_output(i, stream, start, data, truncation)
+            }
+            _ => {}
+        }
+    }
+
+    fn merge_page(&mut self, page: Vec<(CommandId, WeaverCommand, OutputOffsets)>, more: bool) {
+        self.more_history = more;
+        for (i, cmd, offsets) in page {
+            // Anything started since the page was built is already more current than it.
+            if self.command_history.commands.contains_key(&i) {
+                continue;
+            }
+            self.insert_command(i, cmd, offsets);
+        }
+    }
+
+    /// Replaces whatever changed while disconnected with the daemon's current version.
+    fn resync(
+        &mut self,
+        page: Vec<(CommandId, WeaverCommand, OutputOffsets)>,
+        expired: Vec<CommandId>,
+    ) {
+        for &i in &expired {
+            for &stream in OUTPUT_STREAMS.iter() {
+                self.loaded.remove(&(i, stream));
+            }
+        }
+        self.command_history.do_update(ServerMessage::new(
+            0,
+            ServerNotice::CommandsExpired(expired),
+        ));
+        for (i, cmd, offsets) in page {
+            self.insert_command(i, cmd, offsets);
+        }
+    }
+
+    fn insert_command(&mut self, i: CommandId, cmd: WeaverCommand, offsets: OutputOffsets) {
+        for &stream in OUTPUT_STREAMS.iter() {
+            let start = offsets.get(stream);
+            let end = start + cmd.output_len(stream);
+            self.loaded.insert((i, stream), LoadedRange { start, end });
+        }
+        self.command_history.commands.insert(i, cmd);
+    }
+
+    fn merge_output(
+        &mut self,
+        i: CommandId,
+        stream: OutputStream,
+        start: usize,
+        data: Vec<u8>,
+        truncation: Option<Truncation>,
+    ) {
+        let range = match self.loaded.get_mut(&(i, stream)) {
+            Some(range) => range,
+            None => return,
+        };
+        // Output truncated after the request was sent leaves a gap, so it is refetched instead.
+        if start + data.len() != range.start {
+            return;
+        }
+        let cmd = self.command_history.commands.get_mut(&i).unwrap();
+        cmd.prepend_output(stream, &data);
+        range.start = start;
+        if cmd.truncation(stream).is_none() {
+            if let Some(t) = truncation {
+                if t.offset >= start && t.offset <= start + data.len() {
+                    let offset = t.offset - start;
+                    let bytes = t.bytes;
+                    cmd.set_truncation(stream, Truncation { offset, bytes });
+                }
+            }
+        }
+    }
+
+    /// Moves the loaded range of a stream to account for output discarded by the daemon, and
+    /// returns a notice discarding whatever part of that output is held locally.
+    fn translate_truncation(
+        &mut self,
+        i: CommandId,
+        stream: OutputStream,
+        offset: usize,
+        bytes: usize,
+    ) -> Option<ServerNotice> {
+        let range = self.loaded.get_mut(&(i, stream))?;
+        let removed_start = offset.max(range.start);
+        let removed_end = (offset + bytes).min(range.end);
+        let local_start = range.start;
+        if offset + bytes <= range.start {
+            range.start -= bytes;
+        } else if offset < range.start {
+            range.start = offset;
+        }
+        range.end = range.end.saturating_sub(bytes);
+        if removed_end > removed_start {
+            Some(ServerNotice::OutputTruncated(
+                i,
+                stream,
+                removed_start - local_start,
+                removed_end - removed_start,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn extend_loaded(&mut self, i: CommandId, stream: OutputStream, len: usize) {
+        if let Some(range) = self.loaded.get_mut(&(i, stream)) {
+            range.end += len;
+        }
+    }
+}
+
+fn command_started(response: Response) -> Result<CommandId, RequestError> {
+    match response {
+        Response::CommandStarted(id) => Ok(id),
+        response => Err(RequestError::UnexpectedResponse(response)),
+    }
 }
 
 pub struct WeaverClient<'a> {
+    paths: WeaverPaths,
     commands_rx: UnboundedReceiver<ClientMessage>,
     connection: WeaverClientConnectionState<'a>,
     pub state: Arc<RwLock<WeaverState>>,
     pub notifications: Sender<WeaverNotification>,
     overflow: Option<ClientMessage>,
+    /// The id of the last message handed to the socket, which a lost connection takes with it.
+    last_sent: u32,
+    /// The messages starting the current connection, sent before any others.
+    handshake: Option<VecDeque<ClientMessage>>,
+    /// Whether the client has lost a connection before, and so has to catch up on this one.
+    reconnecting: bool,
 }
 
 impl<'a> WeaverClient<'a> {
-    pub fn new(notifications: Sender<WeaverNotification>) -> Self {
+    /// Connects to the daemon, identifying as a client of the given kind, and starts the daemon
+    /// if it isn't running.  A lost connection is reestablished as soon as the daemon is back.
+    pub fn new(kind: &str, paths: WeaverPaths, notifications: Sender<WeaverNotification>) -> Self {
         let (commands_tx, commands_rx): (
             UnboundedSender<ClientMessage>,
             UnboundedReceiver<ClientMessage>,
         ) = unbounded();
 
-        let state = Arc::new(RwLock::new(WeaverState::new(commands_tx)));
-        let overflow = None;
-
-        let socketpath = weaver_socket_path();
-        let socket = UnixStream::connect(socketpath);
-        let connection = WeaverClientConnectionState::Pending(Box::new(socket));
+        let state = Arc::new(RwLock::new(WeaverState::new(kind, commands_tx)));
+        let connection = WeaverClientConnectionState::connect(&paths, Retry::First);
         //let socket_tx = socket_tx.sink_map_err(|e| println!("Send Err: {:#?}", e));
         //let socket_rx = socket_rx.map_err(|e| panic!("Decode Error: {:#?}", e));
 
         WeaverClient {
+            paths,
             commands_rx,
             connection,
             notifications,
             state,
-            overflow,
+            overflow: None,
+            last_sent: 0,
+            handshake: None,
+            reconnecting: false,
         }
     }
 
@@ -102,10 +650,90 @@ impl<'a> WeaverClient<'a> {
         command_history.do_update(msg);
         Some(WeaverNotification::Updated)
     }
+
+    /// Fails whatever was sent over the connection, and starts trying to reconnect.
+    fn lose_connection(&mut self, reason: String) -> Result<Async<()>, DecodeError> {
+        self.state.write().unwrap().connection_lost(self.last_sent);
+        self.handshake = None;
+        self.reconnecting = true;
+        self.connection = WeaverClientConnectionState::reconnect();
+        let _ = self
+            .notifications
+            .send(WeaverNotification::ConnectionLost(reason));
+        self.poll()
+    }
+}
+
+/// Starts sending `msg`, handing it back if the socket isn't ready for it.
+fn start_send(
+    socket_tx: &mut MsgPackWriter<UnixStream, ClientMessage>,
+    msg: ClientMessage,
+) -> Option<ClientMessage> {
+    match socket_tx.start_send(msg) {
+        Ok(AsyncSink::NotReady(msg)) => Some(msg),
+        // A message that failed to send is as good as lost with the connection
+        Ok(AsyncSink::Ready) | Err(_) => None,
+    }
+}
+
+/// How long to wait for a daemon we started to begin listening.
+const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(5);
+const DAEMON_START_POLL: Duration = Duration::from_millis(50);
+/// How long to wait before reconnecting, doubling after each failed attempt up to the maximum.
+const RECONNECT_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_MAX: Duration = Duration::from_secs(5);
+
+/// Starts a detached daemon, preferring one installed alongside this program.
+fn start_daemon(paths: &WeaverPaths) -> io::Result<()> {
+    let program = match env::current_exe() {
+        Ok(ref exe) if exe.with_file_name("weaverd").is_file() => exe.with_file_name("weaverd"),
+        _ => PathBuf::from("weaverd"),
+    };
+    // Anything it says before detaching belongs in the same log as everything after
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(&paths.log)?;
+    let mut daemon = Command::new(program)
+        .arg("--detach")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()?;
+    // It only lingers long enough to take the socket's lock before detaching, but that's still
+    // too long to wait for while polling, so it's reaped on a thread of its own instead.  It fails
+    // if another client has just started a daemon, which is as good as succeeding.
+    thread::Builder::new()
+        .name("weaverd-start".to_owned())
+        .spawn(move || daemon.wait())?;
+    Ok(())
+}
+
+/// What to do when an attempt to connect fails.
+#[derive(Clone, Copy)]
+enum Retry {
+    /// Start the daemon, unless it's running and something else is wrong.
+    First,
+    /// Keep trying until the given time, for the daemon we started to begin listening.
+    Starting(Instant),
+    /// Keep trying forever, having last waited this long, for a daemon we were connected to.
+    Backoff(Duration),
 }
 
+/// The two halves of a connection to the daemon.
+type Halves<'s, 'a> = (
+    &'s mut MsgPackReader<'a, UnixStream, ServerMessage>,
+    &'s mut MsgPackWriter<UnixStream, ClientMessage>,
+);
+
 enum WeaverClientConnectionState<'a> {
-    Pending(Box<Future<Item = UnixStream, Error = io::Error> + Send>),
+    Pending(
+        Box<dyn Future<Item = UnixStream, Error = io::Error> + Send>,
+        Retry,
+    ),
+    /// Waiting to try connecting again.
+    Waiting(Delay, Retry),
     Connected(
         MsgPackReader<'a, UnixStream, ServerMessage>,
         MsgPackWriter<UnixStream, ClientMessage>,
@@ -114,30 +742,68 @@ enum WeaverClientConnectionState<'a> {
 }
 
 impl<'a> WeaverClientConnectionState<'a> {
+    fn connect(paths: &WeaverPaths, retry: Retry) -> Self {
+        let socket = UnixStream::connect(&paths.socket);
+        WeaverClientConnectionState::Pending(Box::new(socket), retry)
+    }
+
+    fn reconnect() -> Self {
+        let delay = Delay::new(Instant::now() + RECONNECT_MIN);
+        WeaverClientConnectionState::Waiting(delay, Retry::Backoff(RECONNECT_MIN))
+    }
+
     pub fn try_connect(
         &mut self,
-    ) -> Result<
-        Async<(
-            &mut MsgPackReader<'a, UnixStream, ServerMessage>,
-            &mut MsgPackWriter<UnixStream, ClientMessage>,
-        )>,
-        &io::Error,
-    > {
+        paths: &WeaverPaths,
+    ) -> Result<Async<Halves<'_, 'a>>, &io::Error> {
         use self::WeaverClientConnectionState::*;
         match self {
-            Pending(socket) => match socket.poll() {
+            Pending(socket, retry) => match socket.poll() {
                 Ok(Async::Ready(socket)) => {
-                    let (mut reader, mut writer): (
+                    let (reader, writer): (
                         MsgPackReader<UnixStream, ServerMessage>,
                         MsgPackWriter<UnixStream, ClientMessage>,
                     ) = from_io(socket);
                     *self = Connected(reader, writer);
-                    self.try_connect()
+                    self.try_connect(paths)
                 }
-                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
                 Err(err) => {
-                    *self = Failed(err);
-                    self.try_connect()
+                    // Nothing is listening, as opposed to something going wrong with the daemon
+                    let missing = matches!(
+                        err.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                    );
+                    let now = Instant::now();
+                    *self = match *retry {
+                        Retry::First if missing => match start_daemon(paths) {
+                            Ok(()) => {
+                                let retry = Retry::Starting(now + DAEMON_START_TIMEOUT);
+                                Waiting(Delay::new(now + DAEMON_START_POLL), retry)
+                            }
+                            Err(err) => Failed(err),
+                        },
+                        Retry::First => Failed(err),
+                        Retry::Starting(deadline) if now < deadline => {
+                            Waiting(Delay::new(now + DAEMON_START_POLL), *retry)
+                        }
+                        Retry::Starting(_) => Failed(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("weaverd didn't start; see {}", paths.log.display()),
+                        )),
+                        Retry::Backoff(wait) => {
+                            let wait = (wait * 2).min(RECONNECT_MAX);
+                            Waiting(Delay::new(now + wait), Retry::Backoff(wait))
+                        }
+                    };
+                    self.try_connect(paths)
+                }
+            },
+            Waiting(delay, retry) => match delay.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => {
+                    *self = WeaverClientConnectionState::connect(paths, *retry);
+                    self.try_connect(paths)
                 }
             },
             Connected(reader, writer) => Ok(Async::Ready((reader, writer))),
@@ -150,27 +816,56 @@ impl<'a> Future for WeaverClient<'a> {
     type Item = ();
     type Error = DecodeError;
     fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        let connection = &mut self.connection;
-        let (socket_rx, socket_tx) = match connection.try_connect() {
+        let (socket_rx, socket_tx) = match self.connection.try_connect(&self.paths) {
             Ok(Async::Ready(pair)) => pair,
             Ok(Async::NotReady) => return Ok(Async::NotReady),
-            Err(err) => panic!("Could not connect to weaver daemon: {:#?}", err),
+            Err(err) => {
+                let reason = format!("Could not connect to weaver daemon: {}", err);
+                let _ = self
+                    .notifications
+                    .send(WeaverNotification::Disconnected(reason));
+                return Ok(Async::Ready(()));
+            }
         };
 
-        if let Some(msg) = self.overflow.take() {
-            if let Ok(AsyncSink::NotReady(msg)) = socket_tx.start_send(msg) {
-                self.overflow = Some(msg);
+        if self.handshake.is_none() {
+            let messages = self.state.write().unwrap().connection_messages();
+            self.handshake = Some(messages.into_iter().collect());
+            if self.reconnecting {
+                let _ = self.notifications.send(WeaverNotification::Reconnected);
+            }
+        }
+
+        // The handshake's ids are newer than the requests queued before it, so they don't count
+        // towards `last_sent`, and the handshake requests are retried by the next connection.
+        let handshake = self.handshake.as_mut().unwrap();
+        while let Some(msg) = handshake.pop_front() {
+            if let Some(msg) = start_send(socket_tx, msg) {
+                handshake.push_front(msg);
+                break;
+            }
+        }
+        if handshake.is_empty() {
+            if let Some(msg) = self.overflow.take() {
+                let id = msg.id;
+                self.overflow = start_send(socket_tx, msg);
+                if self.overflow.is_none() {
+                    self.last_sent = id;
+                }
             }
         }
 
-        if self.overflow == None {
+        if handshake.is_empty() && self.overflow.is_none() {
             const LINES_PER_TICK: usize = 10;
             for i in 0..LINES_PER_TICK {
                 match self.commands_rx.poll().unwrap() {
                     Async::Ready(Some(msg)) => {
-                        if let Ok(AsyncSink::NotReady(msg)) = socket_tx.start_send(msg) {
+                        let id = msg.id;
+                        if let Some(msg) = start_send(socket_tx, msg) {
                             self.overflow = Some(msg);
+                            break;
                         }
+                        self.last_sent = id;
                         if i + 1 == LINES_PER_TICK {
                             task::current().notify();
                         }
@@ -182,19 +877,82 @@ impl<'a> Future for WeaverClient<'a> {
 
         let _ = socket_tx.poll_complete();
 
-        while let Async::Ready(msg) = socket_rx.poll()? {
-            if let Some(msg) = msg {
-                self.notifications
-                    .send(WeaverNotification::Server(msg.clone()))
-                    .unwrap();
-                match self.state.write().unwrap().do_update(msg) {
-                    Some(notification) => self.notifications.send(notification).unwrap(),
-                    None => {}
-                };
-            } else {
-                return Ok(Async::Ready(()));
-            }
+        loop {
+            let msg = match socket_rx.poll() {
+                Ok(Async::Ready(Some(msg))) => msg,
+                Ok(Async::Ready(None)) => {
+                    return self.lose_connection("The daemon hung up".to_owned());
+                }
+                Ok(Async::NotReady) => break,
+                Err(e) => {
+                    let reason = format!("Could not understand weaver daemon: {:?}", e);
+                    // Before the handshake, this is most likely a daemon from before handshakes
+                    let greeted = self.state.read().unwrap().server.is_some();
+                    if greeted {
+                        return self.lose_connection(reason);
+                    }
+                    let _ = self
+                        .notifications
+                        .send(WeaverNotification::Disconnected(reason));
+                    return Ok(Async::Ready(()));
+                }
+            };
+            self.notifications
+                .send(WeaverNotification::Server(msg.clone()))
+                .unwrap();
+            match self.state.write().unwrap().do_update(msg) {
+                Some(WeaverNotification::Disconnected(reason)) => {
+                    let _ = self
+                        .notifications
+                        .send(WeaverNotification::Disconnected(reason));
+                    return Ok(Async::Ready(()));
+                }
+                Some(notification) => self.notifications.send(notification).unwrap(),
+                None => {}
+            };
         }
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incompatible_daemons_disconnect() {
+        let (tx, _rx) = unbounded();
+        let mut state = WeaverState::new("test", tx);
+        let reason = "weaverd speaks protocol version 1".to_owned();
+        let msg = ServerMessage::new(1, ServerNotice::Incompatible(reason.clone()));
+        assert_eq!(
+            state.do_update(msg),
+            Some(WeaverNotification::Disconnected(reason))
+        );
+        assert_eq!(state.server, None);
+    }
+
+    #[test]
+    fn daemons_that_greet_are_remembered() {
+        let (tx, _rx) = unbounded();
+        let mut state = WeaverState::new("test", tx);
+        let mut msg = ServerMessage::new(1, ServerNotice::Hello(Hello::new("weaverd")));
+        msg.seq = 7;
+        assert_eq!(state.do_update(msg), None);
+        assert!(state.server.as_ref().unwrap().supports("replay"));
+        assert_eq!(state.last_seq, Some(7));
+    }
+
+    #[test]
+    fn history_is_asked_for_again_after_a_failed_send() {
+        let (tx, rx) = unbounded();
+        let mut state = WeaverState::new("test", tx);
+        drop(rx);
+        assert!(state.load_more_history().is_err());
+        assert_eq!(state.history_request, None);
+        let (tx, _rx) = unbounded();
+        state.commands_tx = tx;
+        state.load_more_history().unwrap();
+        assert!(state.history_request.is_some());
+    }
+}
@@ -0,0 +1,126 @@
+//! The daemon's configuration, read from `$XDG_CONFIG_HOME/weaver/config.toml`.
+//!
+//! Every setting is optional.  The daemon reads the file when it starts, and again on `SIGHUP` or
+//! a client's `ReloadConfig` request:
+//!
+//! ```toml
+//! socket = "/tmp/weaver.socket"
+//! shell = "bash"
+//!
+//! [env]
+//! PAGER = "cat"
+//!
+//! [retention]
+//! max_command_output = 1048576
+//! max_age = 86400
+//!
+//! [tuning]
+//! chunk_size = 4096
+//! batch_ms = 20
+//!
+//! [clients]
+//! limit = 256
+//! max_bytes = 4194304
+//! when_full = "resync"
+//!
+//! [aliases]
+//! ll = "ls -l"
+//!
+//! [functions]
+//! lsgrep = 'ls -l | grep "$1"'
+//! ```
+//!
+//! Reloading replaces any aliases and functions that clients defined since, while the
+//! environment only applies to clients that connect afterwards.
+
+use super::queue::QueuePolicy;
+use super::retention::RetentionPolicy;
+use super::sampling;
+use super::socket;
+use super::toml;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    /// Where the daemon listens, unless `$WEAVER_SOCKET` says otherwise.
+    pub socket: Option<PathBuf>,
+    /// A shell to run commands with `-c`, instead of the built-in one.  Aliases and functions
+    /// only apply to the built-in shell, though `cd` and `export` still update the session.
+    pub shell: Option<String>,
+    /// Variables set for every client's session, over those the daemon inherited.
+    pub env: BTreeMap<String, String>,
+    pub retention: RetentionPolicy,
+    pub tuning: Tuning,
+    /// How much is queued for a client that isn't keeping up, and what happens after that.
+    pub clients: QueuePolicy,
+    pub aliases: BTreeMap<String, String>,
+    pub functions: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Tuning {
+    /// Bytes read from a command's output at a time, to begin with.
+    pub chunk_size: usize,
+    /// Bytes read at a time from a command that keeps filling its chunks, which grow up to this.
+    pub max_chunk_size: usize,
+    /// Bytes of a command's output gathered before it is sent, in as few notices as possible.
+    pub batch_bytes: usize,
+    /// Milliseconds that output is held back in case more follows, or 0 to send it as read.
+    pub batch_ms: u64,
+    /// Chunks of output, or messages to and from clients, handled before giving others a turn.
+    pub messages_per_tick: usize,
+    /// Milliseconds between samples of running commands' resource usage, or 0 for none.
+    pub sample_interval_ms: u64,
+    /// Bytes of recent notices kept for clients that missed some, which otherwise have to resync.
+    pub replay_bytes: usize,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            chunk_size: 4096,
+            max_chunk_size: 65536,
+            batch_bytes: 65536,
+            batch_ms: 10,
+            messages_per_tick: 10,
+            sample_interval_ms: sampling::DEFAULT_INTERVAL_MS,
+            replay_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`, which need not exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let mut text = String::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_string(&mut text)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e),
+        };
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if config.tuning.chunk_size == 0 || config.tuning.messages_per_tick == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk_size and messages_per_tick must be at least 1",
+            ));
+        }
+        Ok(config)
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        match (env::var_os(socket::SOCKET_ENV), &self.socket) {
+            (Some(socket), _) => PathBuf::from(socket),
+            (None, Some(socket)) => socket.clone(),
+            (None, None) => socket::default_path(),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Aliases and functions, which the daemon expands in every command it runs.
+//!
+//! An alias stands for a single pipeline, and replaces its name wherever that starts a command.
+//! A function stands for a whole script, run with the arguments it was called with as `$1`
+//! onwards.  Both are kept as the text they were defined with, which is checked for syntax errors
+//! when they are defined.
+//!
+//! The daemon starts with the `[aliases]` and `[functions]` of its config file, and clients may
+//! change them while it runs.
+
+use super::config::Config;
+use super::shell::{self, Pipeline, Script, SimpleCommand};
+use super::{Definition, DefinitionKind};
+
+use std::collections::BTreeMap;
+
+#[derive(Clone, Debug, Default)]
+pub struct Definitions {
+    aliases: BTreeMap<String, String>,
+    functions: BTreeMap<String, String>,
+}
+
+impl Definitions {
+    /// The aliases and functions in `config`.
+    pub fn from_config(config: &Config) -> Definitions {
+        let mut definitions = Definitions::default();
+        let aliases = config.aliases.iter().map(|d| (DefinitionKind::Alias, d));
+        let functions = config
+            .functions
+            .iter()
+            .map(|d| (DefinitionKind::Function, d));
+        for (kind, (name, body)) in aliases.chain(functions) {
+            let definition = Definition {
+                kind,
+                name: name.clone(),
+                body: body.clone(),
+            };
+            // One bad definition shouldn't take all the others with it
+            if let Err(e) = definitions.define(definition) {
+                log!(Warn, "Ignoring a definition in the config file: {}", e);
+            }
+        }
+        definitions
+    }
+
+    /// Adds `definition`, replacing any of the same kind and name.
+    pub fn define(&mut self, definition: Definition) -> Result<(), String> {
+        if !shell::is_name(&definition.name) {
+            return Err(format!("{}: not a valid name", definition.name));
+        }
+        let script =
+            shell::parse(&definition.body).map_err(|e| format!("{}: {}", definition.name, e))?;
+        let map = match definition.kind {
+            DefinitionKind::Alias => {
+                if script.pipelines.len() != 1 {
+                    return Err(format!(
+                        "{}: an alias must be a single pipeline, so use a function instead",
+                        definition.name
+                    ));
+                }
+                &mut self.aliases
+            }
+            DefinitionKind::Function => &mut self.functions,
+        };
+        map.insert(definition.name, definition.body);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, kind: DefinitionKind, name: &str) -> Result<(), String> {
+        let map = match kind {
+            DefinitionKind::Alias => &mut self.aliases,
+            DefinitionKind::Function => &mut self.functions,
+        };
+        match map.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(format!("{}: not defined", name)),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Definition> {
+        let aliases = self.aliases.iter().map(|d| (DefinitionKind::Alias, d));
+        let functions = self.functions.iter().map(|d| (DefinitionKind::Function, d));
+        aliases
+            .chain(functions)
+            .map(|(kind, (name, body))| Definition {
+                kind,
+                name: name.clone(),
+                body: body.clone(),
+            })
+            .collect()
+    }
+
+    /// The script a function stands for, with aliases already expanded.
+    pub fn function(&self, name: &str) -> Option<Script> {
+        let script = shell::parse(self.functions.get(name)?).ok()?;
+        Some(self.expand_aliases(script))
+    }
+
+    pub fn is_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Replaces every alias that starts a command in `script` with the pipeline it stands for.
+    pub fn expand_aliases(&self, mut script: Script) -> Script {
+        for &mut (_, ref mut pipeline) in script.pipelines.iter_mut() {
+            let stages = pipeline.stages.drain(..).collect::<Vec<_>>();
+            for stage in stages {
+                pipeline.stages.extend(self.expand_stage(stage, &[]));
+            }
+        }
+        script
+    }
+
+    /// Expands the alias starting `cmd`, and any starting the commands it expands to, other than
+    /// those already being expanded.
+    fn expand_stage(&self, cmd: SimpleCommand, expanding: &[&str]) -> Vec<SimpleCommand> {
+        let name = match cmd.literal_name() {
+            Some(name) => name,
+            None => return vec![cmd],
+        };
+        if expanding.contains(&name.as_str()) {
+            return vec![cmd];
+        }
+        let pipeline = match self.alias(&name) {
+            Some(pipeline) => pipeline,
+            None => return vec![cmd],
+        };
+
+        let mut expanding = expanding.to_vec();
+        expanding.push(&name);
+        let mut stages = pipeline.stages;
+        // Anything else written with the alias belongs at the start or end of what it stands for
+        let last = stages.len() - 1;
+        let mut assignments = cmd.assignments;
+        assignments.append(&mut stages[0].assignments);
+        stages[0].assignments = assignments;
+        stages[last].words.extend(cmd.words.into_iter().skip(1));
+        stages[last].redirects.extend(cmd.redirects);
+        stages
+            .into_iter()
+            .flat_map(|stage| self.expand_stage(stage, &expanding))
+            .collect()
+    }
+
+    fn alias(&self, name: &str) -> Option<Pipeline> {
+        let script = shell::parse(self.aliases.get(name)?).ok()?;
+        match script.pipelines.into_iter().next() {
+            Some((_, ref pipeline)) if pipeline.stages.is_empty() => None,
+            next => next.map(|(_, pipeline)| pipeline),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(kind: DefinitionKind, name: &str, body: &str) -> Definition {
+        Definition {
+            kind,
+            name: name.to_owned(),
+            body: body.to_owned(),
+        }
+    }
+
+    fn aliases(aliases: &[(&str, &str)]) -> Definitions {
+        let mut definitions = Definitions::default();
+        for &(name, body) in aliases {
+            definitions
+                .define(definition(DefinitionKind::Alias, name, body))
+                .unwrap();
+        }
+        definitions
+    }
+
+    fn expand(definitions: &Definitions, input: &str) -> Script {
+        definitions.expand_aliases(shell::parse(input).unwrap())
+    }
+
+    fn parse(input: &str) -> Script {
+        shell::parse(input).unwrap()
+    }
+
+    #[test]
+    fn aliases_expand_where_they_start_a_command() {
+        let definitions = aliases(&[("ll", "ls -l")]);
+        assert_eq!(expand(&definitions, "ll /tmp"), parse("ls -l /tmp"));
+        assert_eq!(
+            expand(&definitions, "cd && ll | ll"),
+            parse("cd && ls -l | ls -l")
+        );
+        assert_eq!(expand(&definitions, "echo ll"), parse("echo ll"));
+        assert_eq!(expand(&definitions, "'ll'"), parse("'ll'"));
+    }
+
+    #[test]
+    fn aliases_may_refer_to_themselves_and_each_other() {
+        let definitions = aliases(&[("ls", "ls -G"), ("ll", "ls -l")]);
+        assert_eq!(expand(&definitions, "ls"), parse("ls -G"));
+        assert_eq!(expand(&definitions, "ll"), parse("ls -G -l"));
+
+        let definitions = aliases(&[("a", "b x"), ("b", "a y")]);
+        assert_eq!(expand(&definitions, "a"), parse("a y x"));
+        assert_eq!(expand(&definitions, "b"), parse("b x y"));
+    }
+
+    #[test]
+    fn assignments_go_first_and_arguments_last() {
+        let definitions = aliases(&[("e", "A=1 env"), ("p", "sort | uniq -c")]);
+        assert_eq!(expand(&definitions, "B=2 e -i"), parse("B=2 A=1 env -i"));
+        assert_eq!(
+            expand(&definitions, "X=1 p -n > out"),
+            parse("X=1 sort | uniq -c -n > out")
+        );
+    }
+
+    #[test]
+    fn functions_have_their_aliases_expanded() {
+        let mut definitions = aliases(&[("ls", "ls -G")]);
+        definitions
+            .define(definition(DefinitionKind::Function, "f", "cd $1; ls"))
+            .unwrap();
+        assert!(definitions.is_function("f"));
+        assert_eq!(definitions.function("f"), Some(parse("cd $1; ls -G")));
+        assert_eq!(definitions.function("ls"), None);
+    }
+
+    #[test]
+    fn aliases_must_be_a_single_pipeline() {
+        let mut definitions = Definitions::default();
+        for body in &["cd; ls", "cd && ls"] {
+            assert_eq!(
+                definitions.define(definition(DefinitionKind::Alias, "a", body)),
+                Err("a: an alias must be a single pipeline, so use a function instead".to_owned())
+            );
+            assert!(definitions
+                .define(definition(DefinitionKind::Function, "a", body))
+                .is_ok());
+        }
+        assert_eq!(definitions.list().len(), 1);
+    }
+
+    #[test]
+    fn definitions_need_a_valid_name_and_body() {
+        let mut definitions = Definitions::default();
+        for name in &["", "1a", "a-b", "a b"] {
+            assert_eq!(
+                definitions.define(definition(DefinitionKind::Alias, name, "ls")),
+                Err(format!("{}: not a valid name", name))
+            );
+        }
+        assert!(definitions
+            .define(definition(
+                DefinitionKind::Function,
+                "f",
+                "echo 'unfinished"
+            ))
+            .is_err());
+        assert!(definitions.list().is_empty());
+    }
+
+    #[test]
+    fn definitions_can_be_replaced_and_removed() {
+        let mut definitions = aliases(&[("ll", "ls -l"), ("ll", "ls -la")]);
+        assert_eq!(
+            definitions.list(),
+            vec![definition(DefinitionKind::Alias, "ll", "ls -la")]
+        );
+        assert_eq!(
+            definitions.remove(DefinitionKind::Function, "ll"),
+            Err("ll: not defined".to_owned())
+        );
+        definitions.remove(DefinitionKind::Alias, "ll").unwrap();
+        assert_eq!(expand(&definitions, "ll"), parse("ll"));
+    }
+}
@@ -0,0 +1,250 @@
+//! Making sense of command output, which is kept exactly as the command wrote it.
+//!
+//! Output is only ever bytes, and needn't be text at all.  The daemon splits it into notices at
+//! character boundaries where it is UTF-8, so that each one can be decoded alone, and clients
+//! guess how a stream is encoded when they have to show it.
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::str;
+
+/// Bytes from the start of a stream looked at to guess its encoding.
+const SAMPLE_BYTES: usize = 8192;
+
+/// Bytes of binary output shown, as a hex dump.
+const DUMP_BYTES: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    /// Text, but not UTF-8, so shown a byte per character as ISO-8859-1.
+    Latin1,
+    Binary,
+}
+
+impl Encoding {
+    /// Guesses how `bytes` are encoded from their start.
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        let sample = &bytes[..bytes.len().min(SAMPLE_BYTES)];
+        let control = sample.iter().filter(|&&b| is_binary_control(b)).count();
+        if sample.contains(&0) || control * 10 > sample.len() {
+            return Encoding::Binary;
+        }
+        // Only a stray invalid sequence or two in otherwise well-formed UTF-8 is still UTF-8
+        let (mut invalid, mut multibyte) = (0, 0);
+        let mut rest = sample;
+        loop {
+            let (valid, skip) = match str::from_utf8(rest) {
+                Ok(text) => (text, None),
+                Err(e) => (
+                    str::from_utf8(&rest[..e.valid_up_to()]).unwrap(),
+                    e.error_len().map(|len| e.valid_up_to() + len),
+                ),
+            };
+            multibyte += valid.chars().filter(|c| c.len_utf8() > 1).count();
+            match skip {
+                Some(skip) => {
+                    invalid += 1;
+                    rest = &rest[skip..];
+                }
+                // Either the end, or a character cut off by the end of the sample
+                None => break,
+            }
+        }
+        match invalid <= multibyte {
+            true => Encoding::Utf8,
+            false => Encoding::Latin1,
+        }
+    }
+
+    /// `bytes` as text to show.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        match *self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes),
+            Encoding::Latin1 => Cow::Owned(bytes.iter().map(|&b| b as char).collect()),
+            Encoding::Binary => Cow::Owned(hex_dump(bytes)),
+        }
+    }
+}
+
+/// Control characters that text wouldn't use, unlike tabs, newlines or terminal escapes.
+fn is_binary_control(b: u8) -> bool {
+    (b < 0x20 && !b"\t\n\r\x08\x0c\x1b".contains(&b)) || b == 0x7f
+}
+
+/// The start of `bytes` as lines of offset, hex and printable ASCII, like `hexdump -C`.
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes[..bytes.len().min(DUMP_BYTES)].chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x} ", i * 16);
+        for b in line {
+            let _ = write!(dump, " {:02x}", b);
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| match (b' '..=b'~').contains(&b) {
+                true => b as char,
+                false => '.',
+            })
+            .collect();
+        let _ = writeln!(
+            dump,
+            "{:pad$}  |{}|",
+            "",
+            ascii,
+            pad = (16 - line.len()) * 3
+        );
+    }
+    if bytes.len() > DUMP_BYTES {
+        let _ = writeln!(dump, "[… {} more bytes …]", bytes.len() - DUMP_BYTES);
+    }
+    dump
+}
+
+/// Whether `offset` is not partway through a UTF-8 character in `bytes`, which it is taken to
+/// be wherever a continuation byte follows it.
+pub fn is_char_boundary(bytes: &[u8], offset: usize) -> bool {
+    match bytes.get(offset) {
+        Some(&b) => b & 0xc0 != 0x80,
+        None => true,
+    }
+}
+
+/// How many bytes at the end of `bytes` start a UTF-8 character without finishing it.
+fn incomplete_suffix(bytes: &[u8]) -> usize {
+    for back in 1..bytes.len().min(3) + 1 {
+        let b = bytes[bytes.len() - back];
+        if b & 0xc0 == 0x80 {
+            continue;
+        }
+        let len = match (!b).leading_zeros() {
+            2 => 2,
+            3 => 3,
+            4 => 4,
+            _ => 1,
+        };
+        return if len > back { back } else { 0 };
+    }
+    0
+}
+
+/// Splits a stream of output into pieces that never end partway through a UTF-8 character,
+/// holding back the start of one until the rest of it arrives.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    partial: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Returns whatever was held back followed by `bytes`, less any character they leave
+    /// unfinished.
+    pub fn push(&mut self, bytes: Vec<u8>) -> Vec<u8> {
+        let mut bytes = match self.partial.is_empty() {
+            true => bytes,
+            false => {
+                let mut partial = self.partial.split_off(0);
+                partial.extend_from_slice(&bytes);
+                partial
+            }
+        };
+        let end = bytes.len() - incomplete_suffix(&bytes);
+        self.partial = bytes.split_off(end);
+        bytes
+    }
+
+    /// Returns whatever was held back, as the stream has ended.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.partial.split_off(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `bytes` through a decoder in pieces of `size`, returning every piece it gives back.
+    fn split(bytes: &[u8], size: usize) -> Vec<Vec<u8>> {
+        let mut decoder = Utf8Decoder::default();
+        let mut pieces: Vec<Vec<u8>> = bytes
+            .chunks(size)
+            .map(|chunk| decoder.push(chunk.to_vec()))
+            .collect();
+        pieces.push(decoder.finish());
+        pieces
+    }
+
+    #[test]
+    fn unfinished_characters() {
+        assert_eq!(incomplete_suffix(b"abc"), 0);
+        assert_eq!(incomplete_suffix("é".as_bytes()), 0);
+        assert_eq!(incomplete_suffix(&"é".as_bytes()[..1]), 1);
+        assert_eq!(incomplete_suffix(&"☃".as_bytes()[..2]), 2);
+        assert_eq!(incomplete_suffix(&"𝄞".as_bytes()[..3]), 3);
+        assert_eq!(incomplete_suffix(b"a\xf0\x9d\x84\x9e"), 0);
+        // Stray continuation bytes finish nothing, and aren't held back
+        assert_eq!(incomplete_suffix(b"\x80\x80\x80\x80"), 0);
+        assert_eq!(incomplete_suffix(b""), 0);
+    }
+
+    #[test]
+    fn pieces_end_on_character_boundaries() {
+        let text = "a é ☃ 𝄞 z".as_bytes();
+        for size in 1..text.len() + 1 {
+            let pieces = split(text, size);
+            for piece in &pieces {
+                assert!(
+                    str::from_utf8(piece).is_ok(),
+                    "{:?} in pieces of {}",
+                    piece,
+                    size
+                );
+            }
+            assert_eq!(pieces.concat(), text);
+        }
+    }
+
+    #[test]
+    fn binary_output_passes_through() {
+        let bytes: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+        for &size in &[1, 7, 1024] {
+            assert_eq!(split(&bytes, size).concat(), bytes);
+        }
+    }
+
+    #[test]
+    fn finishing_returns_what_was_held_back() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.push(b"ok\xe2\x98".to_vec()), b"ok");
+        assert_eq!(decoder.finish(), b"\xe2\x98");
+        assert_eq!(decoder.finish(), b"");
+        assert_eq!(decoder.push(b"\x83".to_vec()), b"\x83");
+    }
+
+    #[test]
+    fn boundaries() {
+        let text = "a☃".as_bytes();
+        assert!(is_char_boundary(text, 0));
+        assert!(is_char_boundary(text, 1));
+        assert!(!is_char_boundary(text, 2));
+        assert!(!is_char_boundary(text, 3));
+        assert!(is_char_boundary(text, 4));
+    }
+
+    #[test]
+    fn detecting_encodings() {
+        assert_eq!(Encoding::detect(b"plain text\n"), Encoding::Utf8);
+        assert_eq!(
+            Encoding::detect("caf\u{e9} \u{2603}".as_bytes()),
+            Encoding::Utf8
+        );
+        assert_eq!(Encoding::detect(b"caf\xe9 cr\xe8me"), Encoding::Latin1);
+        assert_eq!(
+            Encoding::detect(b"\x7fELF\x02\x01\x01\x00"),
+            Encoding::Binary
+        );
+        assert_eq!(Encoding::Latin1.decode(b"caf\xe9"), "caf\u{e9}");
+        assert!(Encoding::Binary
+            .decode(b"\x00AB")
+            .starts_with("00000000  00 41 42"));
+    }
+}
@@ -2,43 +2,160 @@
 extern crate serde_derive;
 extern crate futures;
 extern crate libc;
+extern crate rmp_serde;
 extern crate serde;
 extern crate tokio;
 extern crate tokio_io;
 extern crate tokio_serde_msgpack;
 extern crate tokio_signal;
 extern crate tokio_uds;
+extern crate toml;
 
-use std::collections::BTreeMap;
-use std::iter::FromIterator;
+use std::collections::{btree_map, BTreeMap};
+use std::mem;
+use std::time::{Duration, SystemTime};
 
+#[macro_use]
+pub mod log;
+
+pub mod args;
+pub mod batch;
 pub mod client;
-pub use client::{WeaverClient, WeaverNotification, WeaverState};
+pub use client::{RequestError, ResponseFuture, WeaverClient, WeaverNotification, WeaverState};
+pub use encoding::Encoding;
 
+pub mod config;
+pub mod definitions;
+pub mod encoding;
 pub mod process;
+pub mod queue;
+pub mod retention;
+pub mod sampling;
+pub mod session;
+pub mod shell;
+pub mod socket;
+pub mod store;
+pub mod terminal;
 
 pub type CommandId = u32;
 
+/// The most commands the daemon sends in a page of history.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct CommandHistory {
     pub commands: BTreeMap<CommandId, WeaverCommand>,
     next_index: CommandId,
 }
 
+impl Default for CommandHistory {
+    fn default() -> Self {
+        CommandHistory::new()
+    }
+}
+
+impl IntoIterator for CommandHistory {
+    type Item = (CommandId, WeaverCommand);
+    type IntoIter = btree_map::IntoIter<CommandId, WeaverCommand>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.commands.into_iter()
+    }
+}
+
 impl CommandHistory {
     pub fn do_update(&mut self, msg: ServerMessage) {
         use ServerNotice::*;
-        match msg.notice {
-            CommandStarted(i, cmd) => {
-                let _ = self.commands.insert(i, WeaverCommand::new(cmd));
+        let time = msg.time;
+        let notice = match msg.notice {
+            CommandStarted(i, cmd, started) => {
+                let mut cmd = WeaverCommand::new(cmd);
+                cmd.started = Some(started);
+                let _ = self.commands.insert(i, cmd);
+                self.next_index = self.next_index.max(i + 1);
+                return;
+            }
+            CommandsExpired(ids) => {
+                for i in ids {
+                    self.commands.remove(&i);
+                }
+                return;
             }
-            CommandOutput(i, text) => self.commands.get_mut(&i).unwrap().stdout.push_str(&text),
-            CommandErr(i, text) => self.commands.get_mut(&i).unwrap().stderr.push_str(&text),
-            CommandCompleted(i, rv) => self.commands.get_mut(&i).unwrap().status = Some(rv),
             CommandsBulk(cmds) => {
-                let mut bulk = BTreeMap::from_iter(cmds.into_iter());
+                let mut bulk: BTreeMap<_, _> = cmds.into_iter().collect();
                 self.commands.append(&mut bulk);
+                if let Some(&i) = self.commands.keys().next_back() {
+                    self.next_index = self.next_index.max(i + 1);
+                }
+                return;
             }
+            notice => notice,
+        };
+        // Everything else is about a command that has already started, and is skipped if this
+        // history doesn't have it, such as when it has since expired.
+        let cmd = match notice.command_id().and_then(|i| self.commands.get_mut(&i)) {
+            Some(cmd) => cmd,
+            None => return,
+        };
+        match notice {
+            CommandOutput(_, bytes) => cmd.push_output(OutputStream::Stdout, bytes, time),
+            CommandErr(_, bytes) => cmd.push_output(OutputStream::Stderr, bytes, time),
+            CommandPtyOutput(_, bytes) => cmd.push_output(OutputStream::Pty, bytes, time),
+            PtyResized(_, size) => cmd.pty = Some(size),
+            CommandCompleted(_, rv, finished, rusage) => {
+                cmd.status = Some(rv);
+                cmd.finished = Some(finished);
+                cmd.rusage = rusage;
+                if cmd.stdin == StdinState::Open {
+                    cmd.stdin = StdinState::Closed;
+                }
+            }
+            ProcessSpawned(_, pid) => cmd.pid = Some(pid),
+            StageStarted(_, index, stage) => {
+                if index == cmd.stages.len() {
+                    cmd.stages.push(stage);
+                }
+            }
+            StageErr(_, stage, text) => {
+                if let Some(stage) = cmd.stages.get_mut(stage) {
+                    stage.push_stderr(&text);
+                }
+            }
+            StagePiped(_, stage, bytes) => {
+                if let Some(stage) = cmd.stages.get_mut(stage) {
+                    stage.bytes_piped = Some(bytes);
+                }
+            }
+            StageExited(_, stage, rv) => {
+                if let Some(stage) = cmd.stages.get_mut(stage) {
+                    stage.status = Some(rv);
+                }
+            }
+            ResourceSampled(_, sample) => {
+                if cmd.samples.len() >= MAX_SAMPLES {
+                    cmd.samples.remove(0);
+                }
+                cmd.samples.push(sample);
+            }
+            CommandEnvironment(_, cwd, env) => {
+                cmd.cwd = Some(cwd);
+                cmd.env = env;
+            }
+            SpawnFailed(_, error) => {
+                cmd.status = Some(ExitStatus::FailedToStart);
+                cmd.error = Some(error);
+            }
+            StdinChanged(_, stdin) => cmd.stdin = stdin,
+            OutputTruncated(_, stream, offset, bytes) => cmd.truncate_output(stream, offset, bytes),
+            SignalDelivered(_, _)
+            | CommandStarted(_, _, _)
+            | CommandsExpired(_)
+            | CommandsBulk(_)
+            // Replies to a single client's requests, which `WeaverState` merges in itself
+            | Reply(_)
+            | ServerNotice::Hello(_)
+            | Incompatible(_)
+            | FellBehind => {}
         };
     }
 
@@ -55,8 +172,31 @@ impl CommandHistory {
         self.commands.iter()
     }
 
-    pub fn into_iter(self) -> impl Iterator<Item = (CommandId, WeaverCommand)> {
-        self.commands.into_iter()
+    /// Up to `limit` commands older than `before`, newest first, with the last `bytes` of their
+    /// output, and whether there are older commands still.  No more than `MAX_PAGE_SIZE` are
+    /// returned, however many are asked for.
+    pub fn page(
+        &self,
+        before: Option<CommandId>,
+        limit: usize,
+        bytes: usize,
+    ) -> (Vec<(CommandId, WeaverCommand, OutputOffsets)>, bool) {
+        let limit = limit.min(MAX_PAGE_SIZE);
+        let commands = match before {
+            Some(before) => self.commands.range(..before),
+            None => self.commands.range::<CommandId, _>(..),
+        };
+        let mut page: Vec<_> = commands
+            .rev()
+            .take(limit.saturating_add(1))
+            .map(|(&id, cmd)| {
+                let (cmd, offsets) = cmd.tail(bytes);
+                (id, cmd, offsets)
+            })
+            .collect();
+        let more = page.len() > limit;
+        page.truncate(limit);
+        (page, more)
     }
 
     pub fn next_index(&mut self) -> CommandId {
@@ -66,30 +206,729 @@ impl CommandHistory {
     }
 }
 
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct WeaverCommand {
     pub cmd: String,
-    pub stdout: String,
-    pub stderr: String,
-    pub status: Option<i32>,
+    /// Output exactly as the command wrote it, which needn't be text.
+    #[serde(with = "output_bytes")]
+    pub stdout: Vec<u8>,
+    #[serde(with = "output_bytes")]
+    pub stderr: Vec<u8>,
+    #[serde(with = "optional_enum")]
+    pub status: Option<ExitStatus>,
+    pub pty: Option<WindowSize>,
+    #[serde(with = "output_bytes")]
+    pub pty_output: Vec<u8>,
+    pub stdout_truncated: Option<Truncation>,
+    pub stderr_truncated: Option<Truncation>,
+    pub pty_truncated: Option<Truncation>,
+    /// Why the daemon couldn't run the command, if it couldn't.
+    pub error: Option<String>,
+    pub cwd: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+    pub started: Option<SystemTime>,
+    pub finished: Option<SystemTime>,
+    pub pid: Option<u32>,
+    pub rusage: Option<ResourceUsage>,
+    /// The most recent live samples taken while the command was running.
+    pub samples: Vec<ResourceSample>,
+    /// Every simple command run so far, in the order they were started.
+    pub stages: Vec<PipelineStage>,
+    /// The command's output as runs of one stream at a time, in the order they were written.
+    #[serde(default)]
+    pub chunks: Vec<OutputChunk>,
+    #[serde(default)]
+    pub stdin: StdinState,
 }
 
 impl WeaverCommand {
     pub fn new(cmd: String) -> Self {
         WeaverCommand {
             cmd,
-            stdout: String::new(),
-            stderr: String::new(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            status: None,
+            pty: None,
+            pty_output: Vec::new(),
+            stdout_truncated: None,
+            stderr_truncated: None,
+            pty_truncated: None,
+            error: None,
+            cwd: None,
+            env: BTreeMap::new(),
+            started: None,
+            finished: None,
+            pid: None,
+            rusage: None,
+            samples: Vec::new(),
+            stages: Vec::new(),
+            chunks: Vec::new(),
+            stdin: StdinState::Null,
+        }
+    }
+
+    /// Options to run this command again exactly as it was run before.
+    pub fn run_options(&self) -> RunOptions {
+        RunOptions {
+            pty: self.pty,
+            cwd: self.cwd.clone(),
+            env: self.env.clone(),
+            clear_env: true,
+            input: self.stdin != StdinState::Null,
+        }
+    }
+
+    pub fn output(&self, stream: OutputStream) -> &[u8] {
+        match stream {
+            OutputStream::Stdout => &self.stdout,
+            OutputStream::Stderr => &self.stderr,
+            OutputStream::Pty => &self.pty_output,
+        }
+    }
+
+    pub fn output_len(&self, stream: OutputStream) -> usize {
+        self.output(stream).len()
+    }
+
+    /// Appends output to `stream`, which the daemon read at `time`.
+    pub fn push_output(&mut self, stream: OutputStream, bytes: Vec<u8>, time: Option<SystemTime>) {
+        if bytes.is_empty() {
+            return;
+        }
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.extended_by(stream, time) => chunk.len += bytes.len(),
+            _ => self.chunks.push(OutputChunk {
+                stream,
+                len: bytes.len(),
+                time,
+            }),
+        }
+        match stream {
+            OutputStream::Stdout => self.stdout.extend(bytes),
+            OutputStream::Stderr => self.stderr.extend(bytes),
+            OutputStream::Pty => self.pty_output.extend(bytes),
+        }
+    }
+
+    /// The offset in `stream` of the first of its chunks, before which the order of its output
+    /// isn't known.
+    fn chunks_start(&self, stream: OutputStream) -> usize {
+        let chunked: usize = self
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.stream == stream)
+            .map(|chunk| chunk.len)
+            .sum();
+        self.output_len(stream) - chunked
+    }
+
+    /// The command's output in the order it was written.  Output from before that order is known,
+    /// such as older output loaded on demand, comes first, a stream at a time.
+    pub fn timeline<'a>(&'a self) -> Vec<TimelineEntry<'a>> {
+        let mut offsets = OutputOffsets::default();
+        let mut timeline = vec![];
+        for &stream in OUTPUT_STREAMS.iter() {
+            let start = self.chunks_start(stream);
+            if start > 0 {
+                timeline.push(TimelineEntry {
+                    stream,
+                    offset: 0,
+                    time: None,
+                    output: &self.output(stream)[..start],
+                });
+            }
+            *offsets.get_mut(stream) = start;
+        }
+        for chunk in &self.chunks {
+            let offset = offsets.get(chunk.stream);
+            timeline.push(TimelineEntry {
+                stream: chunk.stream,
+                offset,
+                time: chunk.time,
+                output: &self.output(chunk.stream)[offset..offset + chunk.len],
+            });
+            *offsets.get_mut(chunk.stream) += chunk.len;
+        }
+        timeline
+    }
+
+    /// How the output of `stream` looks to be encoded, to show it.
+    pub fn encoding(&self, stream: OutputStream) -> Encoding {
+        Encoding::detect(self.output(stream))
+    }
+
+    pub fn truncation(&self, stream: OutputStream) -> Option<Truncation> {
+        match stream {
+            OutputStream::Stdout => self.stdout_truncated,
+            OutputStream::Stderr => self.stderr_truncated,
+            OutputStream::Pty => self.pty_truncated,
+        }
+    }
+
+    /// Discards `bytes` bytes of output from `stream`, starting at `offset`.
+    pub fn truncate_output(&mut self, stream: OutputStream, offset: usize, bytes: usize) {
+        let len = self.output_len(stream);
+        let (offset, end) = (offset.min(len), (offset + bytes).min(len));
+        let mut start = self.chunks_start(stream);
+        for chunk in self
+            .chunks
+            .iter_mut()
+            .filter(|chunk| chunk.stream == stream)
+        {
+            let chunk_end = start + chunk.len;
+            chunk.len -= chunk_end.min(end).saturating_sub(start.max(offset));
+            start = chunk_end;
+        }
+        self.chunks.retain(|chunk| chunk.len > 0);
+        let truncation = match stream {
+            OutputStream::Stdout => {
+                self.stdout.drain(offset..end);
+                &mut self.stdout_truncated
+            }
+            OutputStream::Stderr => {
+                self.stderr.drain(offset..end);
+                &mut self.stderr_truncated
+            }
+            OutputStream::Pty => {
+                self.pty_output.drain(offset..end);
+                &mut self.pty_truncated
+            }
+        };
+        let truncation = truncation.get_or_insert(Truncation { offset, bytes: 0 });
+        truncation.offset = truncation.offset.min(offset);
+        truncation.bytes += end - offset;
+    }
+
+    /// A copy of this command holding only the last `bytes` bytes of each stream's output, along
+    /// with the offsets those tails start at.
+    pub fn tail(&self, bytes: usize) -> (WeaverCommand, OutputOffsets) {
+        let mut tail = WeaverCommand::new(self.cmd.clone());
+        tail.status = self.status;
+        tail.pty = self.pty;
+        tail.error = self.error.clone();
+        tail.cwd = self.cwd.clone();
+        tail.env = self.env.clone();
+        tail.started = self.started;
+        tail.finished = self.finished;
+        tail.pid = self.pid;
+        tail.rusage = self.rusage;
+        tail.samples = self.samples.clone();
+        tail.stages = self.stages.clone();
+        let mut offsets = OutputOffsets::default();
+        for &stream in OUTPUT_STREAMS.iter() {
+            let len = self.output_len(stream);
+            let (start, data) = self.output_range(stream, len.saturating_sub(bytes), len);
+            tail.prepend_output(stream, &data);
+            if let Some(truncation) = self.truncation(stream) {
+                if truncation.offset >= start {
+                    tail.set_truncation(
+                        stream,
+                        Truncation {
+                            offset: truncation.offset - start,
+                            bytes: truncation.bytes,
+                        },
+                    );
+                }
+            }
+            *offsets.get_mut(stream) = start;
+        }
+        // As much of the order as covers the output in the tail
+        let mut remaining = OutputOffsets::default();
+        for &stream in OUTPUT_STREAMS.iter() {
+            *remaining.get_mut(stream) = tail.output_len(stream);
+        }
+        for chunk in self.chunks.iter().rev() {
+            let len = chunk.len.min(remaining.get(chunk.stream));
+            if len > 0 {
+                tail.chunks.push(OutputChunk { len, ..*chunk });
+                *remaining.get_mut(chunk.stream) -= len;
+            }
+        }
+        tail.chunks.reverse();
+        (tail, offsets)
+    }
+
+    /// The output of `stream` from `start` to `end`, widened to any UTF-8 character boundaries,
+    /// and the offset it actually starts at.
+    pub fn output_range(&self, stream: OutputStream, start: usize, end: usize) -> (usize, Vec<u8>) {
+        let output = self.output(stream);
+        let mut end = end.min(output.len());
+        let mut start = start.min(end);
+        while !encoding::is_char_boundary(output, start) {
+            start -= 1;
+        }
+        while !encoding::is_char_boundary(output, end) {
+            end += 1;
+        }
+        (start, output[start..end].to_vec())
+    }
+
+    /// Adds earlier output to the start of `stream`, as when a client loads more of it.
+    pub fn prepend_output(&mut self, stream: OutputStream, data: &[u8]) {
+        let output = match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+            OutputStream::Pty => &mut self.pty_output,
+        };
+        let rest = mem::replace(output, data.to_vec());
+        output.extend(rest);
+        if let Some(mut truncation) = self.truncation(stream) {
+            truncation.offset += data.len();
+            self.set_truncation(stream, truncation);
+        }
+    }
+
+    pub fn set_truncation(&mut self, stream: OutputStream, truncation: Truncation) {
+        match stream {
+            OutputStream::Stdout => self.stdout_truncated = Some(truncation),
+            OutputStream::Stderr => self.stderr_truncated = Some(truncation),
+            OutputStream::Pty => self.pty_truncated = Some(truncation),
+        }
+    }
+}
+
+/// Where in each of a command's output streams some part of that output starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct OutputOffsets {
+    pub stdout: usize,
+    pub stderr: usize,
+    pub pty: usize,
+}
+
+impl OutputOffsets {
+    pub fn get(&self, stream: OutputStream) -> usize {
+        match stream {
+            OutputStream::Stdout => self.stdout,
+            OutputStream::Stderr => self.stderr,
+            OutputStream::Pty => self.pty,
+        }
+    }
+
+    pub fn get_mut(&mut self, stream: OutputStream) -> &mut usize {
+        match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+            OutputStream::Pty => &mut self.pty,
+        }
+    }
+}
+
+/// Output written to one stream before any was written to another, for at most `CHUNK_SECS`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub len: usize,
+    /// When the daemon read the start of it, if the daemon said.
+    pub time: Option<SystemTime>,
+}
+
+/// How long after a chunk started that more output to the same stream still extends it.
+const CHUNK_SECS: u64 = 1;
+
+impl OutputChunk {
+    fn extended_by(&self, stream: OutputStream, time: Option<SystemTime>) -> bool {
+        let soon = match (self.time, time) {
+            (Some(start), Some(time)) => match time.duration_since(start) {
+                Ok(after) => after < Duration::from_secs(CHUNK_SECS),
+                Err(_) => false,
+            },
+            (None, None) => true,
+            _ => false,
+        };
+        self.stream == stream && soon
+    }
+}
+
+/// Part of a command's output, as listed by `WeaverCommand::timeline`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimelineEntry<'a> {
+    pub stream: OutputStream,
+    /// Where it starts in its stream's output.
+    pub offset: usize,
+    pub time: Option<SystemTime>,
+    pub output: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+    Pty,
+}
+
+pub const OUTPUT_STREAMS: [OutputStream; 3] = [
+    OutputStream::Stdout,
+    OutputStream::Stderr,
+    OutputStream::Pty,
+];
+
+/// Records output discarded by the daemon's retention policy, which is missing at `offset`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Truncation {
+    pub offset: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled(i32),
+    CoreDumped(i32),
+    /// The daemon exited, or lost track of the command, before it finished.
+    Lost,
+    FailedToStart,
+}
+
+/// rmp-serde can't decode an enum wrapped in an `Option`, so such fields are written as a
+/// sequence of at most one instead.
+mod optional_enum {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Serialize,
+        S: Serializer,
+    {
+        let values: Vec<&T> = value.iter().collect();
+        values.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let values: Vec<T> = Vec::deserialize(deserializer)?;
+        Ok(values.into_iter().next())
+    }
+}
+
+/// rmp-serde writes a `Vec<u8>` as an array of integers, so output is written as binary instead.
+/// Either is read back, as is a string, which is how older versions wrote stdout and stderr.
+mod output_bytes {
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("output as bytes or a string")
+        }
+
+        fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+
+        fn visit_str<E: Error>(self, text: &str) -> Result<Vec<u8>, E> {
+            Ok(text.as_bytes().to_vec())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+impl ExitStatus {
+    pub fn from_raw(status: libc::c_int) -> Self {
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            if libc::WCOREDUMP(status) {
+                ExitStatus::CoreDumped(signal)
+            } else {
+                ExitStatus::Signaled(signal)
+            }
+        } else {
+            ExitStatus::Exited(libc::WEXITSTATUS(status))
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Exited(0)
+    }
+
+    /// The status as a shell reports it in `$?`.
+    pub fn code(&self) -> i32 {
+        match *self {
+            ExitStatus::Exited(code) => code,
+            ExitStatus::Signaled(signal) | ExitStatus::CoreDumped(signal) => 128 + signal,
+            ExitStatus::Lost | ExitStatus::FailedToStart => 127,
+        }
+    }
+}
+
+/// Resources used by a command's processes, as reported by the kernel when they were reaped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// Peak resident set size, in kilobytes.
+    pub max_rss: u64,
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+}
+
+impl ResourceUsage {
+    /// Accounts for the resources used by another of the command's processes.
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.user_time += other.user_time;
+        self.system_time += other.system_time;
+        self.max_rss = self.max_rss.max(other.max_rss);
+        self.blocks_read += other.blocks_read;
+        self.blocks_written += other.blocks_written;
+    }
+}
+
+/// How much of each pipeline stage's own stderr is kept, in bytes.
+pub const STAGE_STDERR_LIMIT: usize = 4096;
+
+/// One simple command run by a command, which may be part of a larger pipeline.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PipelineStage {
+    pub cmd: String,
+    /// None for builtins, and for processes that couldn't be started.
+    pub pid: Option<u32>,
+    #[serde(with = "optional_enum")]
+    pub status: Option<ExitStatus>,
+    /// The end of what this stage alone wrote to stderr, which is also part of the command's.
+    pub stderr: String,
+    /// How much this stage has written to the next stage of its pipeline, if there is one.
+    pub bytes_piped: Option<u64>,
+}
+
+impl PipelineStage {
+    pub fn new(cmd: String, pid: Option<u32>, piped: bool) -> Self {
+        PipelineStage {
+            cmd,
+            pid,
             status: None,
+            stderr: String::new(),
+            bytes_piped: match piped {
+                true => Some(0),
+                false => None,
+            },
         }
     }
+
+    /// Whether the stage exited unsuccessfully, other than by being cut off when the next stage
+    /// stopped reading its output.
+    pub fn failed(&self) -> bool {
+        match self.status {
+            Some(ExitStatus::Signaled(libc::SIGPIPE)) | None => false,
+            Some(status) => !status.success(),
+        }
+    }
+
+    fn push_stderr(&mut self, text: &str) {
+        self.stderr.push_str(text);
+        if self.stderr.len() > STAGE_STDERR_LIMIT {
+            let mut start = self.stderr.len() - STAGE_STDERR_LIMIT;
+            while !self.stderr.is_char_boundary(start) {
+                start += 1;
+            }
+            self.stderr.drain(..start);
+        }
+    }
+}
+
+/// How many `ResourceSample`s each command keeps.
+pub const MAX_SAMPLES: usize = 60;
+
+/// A command's process tree, as it was at one moment while it ran.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ResourceSample {
+    pub time: SystemTime,
+    /// CPU time used since the previous sample, as a percentage of one CPU.
+    pub cpu_percent: f32,
+    /// Resident set size, in kilobytes.
+    pub rss: u64,
+    pub threads: u32,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        WindowSize { rows: 24, cols: 80 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+    Kill,
+    Stop,
+    Continue,
+}
+
+impl Signal {
+    pub fn as_raw(&self) -> libc::c_int {
+        use Signal::*;
+        match *self {
+            Interrupt => libc::SIGINT,
+            Terminate => libc::SIGTERM,
+            Kill => libc::SIGKILL,
+            Stop => libc::SIGSTOP,
+            Continue => libc::SIGCONT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum DefinitionKind {
+    Alias,
+    Function,
+}
+
+/// An alias or function, which the daemon expands in every command that uses its name.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Definition {
+    pub kind: DefinitionKind,
+    pub name: String,
+    /// The shell text it stands for.
+    pub body: String,
+}
+
+/// Bumped whenever `ClientMessage` or `ServerMessage` change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 14;
+
+/// Optional capabilities, advertised in `Hello` so that either side can avoid relying on ones
+/// the other lacks.
+pub const FEATURES: &[&str] = &[
+    "pty",
+    "input",
+    "signals",
+    "paged-history",
+    "resource-samples",
+    "pipeline-stages",
+    "definitions",
+    "reload-config",
+    "resync",
+    "replay",
+    "client-queues",
+    "binary-output",
+    "output-timeline",
+];
+
+/// The first message in each direction on a new connection.
+///
+/// Its encoding, and its position in `ClientRequest` and `ServerNotice`, must never change, so
+/// that any two versions can at least tell that they are incompatible.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Hello {
+    pub version: u32,
+    /// What kind of program is connecting, e.g. "tui" or "cli".
+    pub kind: String,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(kind: &str) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            kind: kind.to_owned(),
+            features: FEATURES.iter().map(|&f| f.to_owned()).collect(),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Checks that the client that sent this speaks the daemon's version of the protocol,
+    /// explaining why not if it doesn't.
+    pub fn check_version(&self) -> Result<(), String> {
+        if self.version == PROTOCOL_VERSION {
+            return Ok(());
+        }
+        Err(format!(
+            "weaverd speaks protocol version {}, but this {} client speaks version {}",
+            PROTOCOL_VERSION, self.kind, self.version
+        ))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct RunOptions {
+    /// Run in a pty of this size, rather than with pipes.
+    pub pty: Option<WindowSize>,
+    /// Where to run, relative to the session's working directory.
+    pub cwd: Option<PathBuf>,
+    /// Variables to set on top of the session's environment.
+    pub env: BTreeMap<String, String>,
+    /// Start from an empty environment, rather than the session's.
+    pub clear_env: bool,
+    /// Give the command a pipe for input sent with `SendInput` as its stdin, rather than
+    /// /dev/null.  Either way, a pty is its own input.
+    #[serde(default)]
+    pub input: bool,
+}
+
+/// What a command not running in a pty reads as its stdin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum StdinState {
+    /// /dev/null, so that anything reading it sees the end at once.
+    #[default]
+    Null,
+    /// A pipe that input sent for the command is written to.
+    Open,
+    /// That pipe, after it was closed.
+    Closed,
+}
+
+/// New variants must be added at the end, to keep `Hello` where older versions expect it.
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub enum ClientRequest {
-    RunCommand(String),
+    RunCommand(String, RunOptions),
+    /// Lists up to `limit` commands older than the given one, with the last `bytes` of their output.
+    ListCommands(Option<CommandId>, usize, usize),
+    FetchOutput(CommandId, OutputStream, usize, usize),
+    ResizePty(CommandId, WindowSize),
+    Signal(CommandId, Signal),
+    SendInput(CommandId, Vec<u8>),
+    CloseInput(CommandId),
+    /// Answered by `ServerNotice::Hello` or `ServerNotice::Incompatible` rather than a `Reply`.
+    Hello(Hello),
+    Define(Definition),
+    ListDefinitions,
+    RemoveDefinition(DefinitionKind, String),
+    /// Rereads the daemon's config file, keeping the current config if it has errors.
+    ReloadConfig,
+    /// Catches up a client that reconnected holding the given commands, of which the second list
+    /// were still running, with the last `bytes` of output for any it needs afresh.
+    Resync(Vec<CommandId>, Vec<CommandId>, usize),
+    /// Resends every notice after the given sequence number, failing if the daemon no longer
+    /// has them all.
+    Replay(u64),
+    ListClients,
 }
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
@@ -98,23 +937,634 @@ pub struct ClientMessage {
     pub request: ClientRequest,
 }
 
+/// The result of a request that succeeded.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Response {
+    Done,
+    CommandStarted(CommandId),
+    /// A page of history, newest first, and whether there are older commands.
+    CommandsPage(Vec<(CommandId, WeaverCommand, OutputOffsets)>, bool),
+    /// Output starting at an offset, along with the stream's truncation in the daemon's offsets.
+    OutputRange(
+        CommandId,
+        OutputStream,
+        usize,
+        #[serde(with = "output_bytes")] Vec<u8>,
+        Option<Truncation>,
+    ),
+    Definitions(Vec<Definition>),
+    /// The commands a resyncing client should replace or add, and those it should drop.
+    Resynced(
+        Vec<(CommandId, WeaverCommand, OutputOffsets)>,
+        Vec<CommandId>,
+    ),
+    Clients(Vec<ClientInfo>),
+}
+
+/// A client connected to the daemon.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ClientInfo {
+    pub id: u32,
+    /// The kind it identified itself as.
+    pub kind: String,
+    pub queue: queue::QueueStats,
+}
+
+/// New variants must be added at the end, to keep `Hello` and `Incompatible` where older versions
+/// expect them.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum ServerNotice {
     CommandsBulk(Vec<(CommandId, WeaverCommand)>),
-    CommandStarted(CommandId, String),
-    CommandOutput(CommandId, String),
-    CommandErr(CommandId, String),
-    CommandCompleted(CommandId, i32),
+    CommandStarted(CommandId, String, SystemTime),
+    CommandOutput(CommandId, #[serde(with = "output_bytes")] Vec<u8>),
+    CommandErr(CommandId, #[serde(with = "output_bytes")] Vec<u8>),
+    CommandPtyOutput(CommandId, #[serde(with = "output_bytes")] Vec<u8>),
+    PtyResized(CommandId, WindowSize),
+    OutputTruncated(CommandId, OutputStream, usize, usize),
+    CommandsExpired(Vec<CommandId>),
+    /// The command's exit status, when it was reaped, and what it used if it was a process.
+    CommandCompleted(CommandId, ExitStatus, SystemTime, Option<ResourceUsage>),
+    SignalDelivered(CommandId, Signal),
+    /// Answers the request with the same id.  Every request gets exactly one, sent only to the
+    /// client that made it.
+    Reply(Result<Response, String>),
+    Hello(Hello),
+    /// Sent in place of `Hello` when the daemon can't talk to a client, just before disconnecting.
+    Incompatible(String),
+    SpawnFailed(CommandId, String),
+    /// The working directory and environment a command was started with.
+    CommandEnvironment(CommandId, PathBuf, BTreeMap<String, String>),
+    /// A process was started for the command, which may run several in turn.
+    ProcessSpawned(CommandId, u32),
+    ResourceSampled(CommandId, ResourceSample),
+    /// The command started its next stage, which is described with its expanded words.
+    StageStarted(CommandId, usize, PipelineStage),
+    /// Output written to stderr by one stage, which is also sent as `CommandErr`.
+    StageErr(CommandId, usize, String),
+    /// The total bytes a stage has written to the next one so far.
+    StagePiped(CommandId, usize, u64),
+    StageExited(CommandId, usize, ExitStatus),
+    /// Sent to a client that stopped reading for long enough that notices were dropped, once it
+    /// has read all those queued before them.
+    FellBehind,
+    StdinChanged(CommandId, StdinState),
+}
+
+impl ServerNotice {
+    /// The command this notice is about, if it is about a single command.
+    pub fn command_id(&self) -> Option<CommandId> {
+        use ServerNotice::*;
+        match *self {
+            CommandsBulk(_)
+            | CommandsExpired(_)
+            | Reply(_)
+            | ServerNotice::Hello(_)
+            | Incompatible(_)
+            | FellBehind => None,
+            CommandStarted(i, _, _)
+            | CommandOutput(i, _)
+            | CommandErr(i, _)
+            | CommandPtyOutput(i, _)
+            | PtyResized(i, _)
+            | OutputTruncated(i, _, _, _)
+            | CommandCompleted(i, _, _, _)
+            | ProcessSpawned(i, _)
+            | ResourceSampled(i, _)
+            | StageStarted(i, _, _)
+            | StageErr(i, _, _)
+            | StagePiped(i, _, _)
+            | StageExited(i, _, _)
+            | SpawnFailed(i, _)
+            | CommandEnvironment(i, _, _)
+            | SignalDelivered(i, _)
+            | StdinChanged(i, _) => Some(i),
+        }
+    }
+
+    /// Roughly how many bytes this notice takes up, mostly the output and text it carries.
+    pub fn size(&self) -> usize {
+        use ServerNotice::*;
+        let carried = match *self {
+            CommandOutput(_, ref bytes)
+            | CommandErr(_, ref bytes)
+            | CommandPtyOutput(_, ref bytes) => bytes.len(),
+            CommandStarted(_, ref text, _)
+            | SpawnFailed(_, ref text)
+            | StageErr(_, _, ref text)
+            | Incompatible(ref text) => text.len(),
+            StageStarted(_, _, ref stage) => stage.cmd.len(),
+            CommandEnvironment(_, ref cwd, ref env) => {
+                cwd.as_os_str().len() + env.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+            }
+            CommandsBulk(ref cmds) => cmds
+                .iter()
+                .map(|(_, cmd)| {
+                    cmd.cmd.len()
+                        + OUTPUT_STREAMS
+                            .iter()
+                            .map(|&s| cmd.output_len(s))
+                            .sum::<usize>()
+                })
+                .sum(),
+            CommandsExpired(ref ids) => 8 * ids.len(),
+            _ => 0,
+        };
+        64 + carried
+    }
+
+    /// Whether this notice is sent to every client, rather than to a single one.
+    pub fn is_broadcast(&self) -> bool {
+        use ServerNotice::*;
+        !matches!(
+            *self,
+            Reply(_) | ServerNotice::Hello(_) | Incompatible(_) | FellBehind
+        )
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct ServerMessage {
     pub id: u32,
     pub notice: ServerNotice,
+    /// Numbers every broadcast notice in the order the daemon dispatched it.  Any other message
+    /// carries the number of the last notice sent before it, which is what a snapshot in a reply
+    /// is up to date with.
+    #[serde(default)]
+    pub seq: u64,
+    /// How many notices numbered just before this one were merged into it, for a client that
+    /// fell behind.
+    #[serde(default)]
+    pub coalesced: u64,
+    /// When the daemon dispatched a broadcast notice.
+    #[serde(default)]
+    pub time: Option<SystemTime>,
 }
 
-pub fn weaver_socket_path() -> PathBuf {
-    let mut socketpath = std::env::home_dir().unwrap();
-    socketpath.push(".weaver.socket");
-    socketpath
+impl ServerMessage {
+    /// A message about to be dispatched or sent, which numbers it.
+    pub fn new(id: u32, notice: ServerNotice) -> Self {
+        ServerMessage {
+            id,
+            notice,
+            seq: 0,
+            coalesced: 0,
+            time: None,
+        }
+    }
+}
+
+/// Where weaver keeps its files, which each program resolves once as it starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeaverPaths {
+    pub config: PathBuf,
+    pub history: PathBuf,
+    /// Where the daemon writes its output once detached.
+    pub log: PathBuf,
+    /// Where the daemon listens, according to `$WEAVER_SOCKET` or the config file.
+    pub socket: PathBuf,
+}
+
+impl WeaverPaths {
+    /// Resolves every path from the environment, failing if there is no home directory to keep
+    /// files in.
+    pub fn resolve() -> std::io::Result<WeaverPaths> {
+        WeaverPaths::from_env(
+            std::env::home_dir(),
+            std::env::var_os("XDG_CONFIG_HOME"),
+            std::env::var_os(socket::SOCKET_ENV),
+        )
+    }
+
+    /// Resolves every path from the home directory, `$XDG_CONFIG_HOME` and `$WEAVER_SOCKET`.
+    /// The config file is only read for the socket when `socket_override` is `None`.
+    pub fn from_env(
+        home: Option<PathBuf>,
+        xdg_config_home: Option<OsString>,
+        socket_override: Option<OsString>,
+    ) -> std::io::Result<WeaverPaths> {
+        let home = home.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no home directory to keep weaver's files in; set $HOME",
+            )
+        })?;
+        let config_dir = match xdg_config_home {
+            Some(ref dir) if Path::new(dir).is_absolute() => PathBuf::from(dir),
+            _ => home.join(".config"),
+        };
+        let config = config_dir.join("weaver").join("config.toml");
+        let socket = match socket_override {
+            Some(socket) => PathBuf::from(socket),
+            None => config::Config::load(&config)
+                .unwrap_or_default()
+                .socket_path(),
+        };
+        Ok(WeaverPaths {
+            config,
+            history: home.join(".weaver.history"),
+            log: home.join(".weaver.log"),
+            socket,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmp_serde::{decode, encode};
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use ExitStatus::*;
+
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+        let data = encode::to_vec(value).unwrap();
+        decode::from_read(&data[..]).unwrap()
+    }
+
+    #[test]
+    fn exit_statuses_round_trip() {
+        let statuses = [
+            None,
+            Some(Exited(0)),
+            Some(Exited(3)),
+            Some(Signaled(9)),
+            Some(CoreDumped(11)),
+            Some(Lost),
+            Some(FailedToStart),
+        ];
+        for &status in statuses.iter() {
+            let mut cmd = WeaverCommand::new("true".to_owned());
+            cmd.status = status;
+            assert_eq!(round_trip(&cmd).status, status);
+
+            let mut stage = PipelineStage::new("true".to_owned(), Some(1), false);
+            stage.status = status;
+            assert_eq!(round_trip(&stage).status, status);
+        }
+    }
+
+    #[test]
+    fn exit_statuses_from_wait_statuses() {
+        assert_eq!(ExitStatus::from_raw(3 << 8), Exited(3));
+        assert_eq!(ExitStatus::from_raw(libc::SIGKILL), Signaled(libc::SIGKILL));
+        assert_eq!(
+            ExitStatus::from_raw(libc::SIGSEGV | 0x80),
+            CoreDumped(libc::SIGSEGV)
+        );
+        assert!(Exited(0).success());
+        assert!(!Signaled(libc::SIGINT).success());
+    }
+
+    #[test]
+    fn run_options_from_older_clients_leave_stdin_null() {
+        #[derive(Serialize)]
+        struct OldRunOptions {
+            pty: Option<WindowSize>,
+            cwd: Option<PathBuf>,
+            env: BTreeMap<String, String>,
+            clear_env: bool,
+        }
+        let old = OldRunOptions {
+            pty: None,
+            cwd: None,
+            env: BTreeMap::new(),
+            clear_env: false,
+        };
+        let data = encode::to_vec(&old).unwrap();
+        let options: RunOptions = decode::from_read(&data[..]).unwrap();
+        assert!(!options.input);
+    }
+
+    #[test]
+    fn stdin_is_closed_once_its_command_completes() {
+        let mut history = CommandHistory::new();
+        let now = SystemTime::now();
+        history.do_update(ServerMessage::new(
+            0,
+            ServerNotice::CommandStarted(1, "cat".to_owned(), now),
+        ));
+        assert_eq!(history.commands[&1].stdin, StdinState::Null);
+        history.do_update(ServerMessage::new(
+            0,
+            ServerNotice::StdinChanged(1, StdinState::Open),
+        ));
+        assert_eq!(history.commands[&1].stdin, StdinState::Open);
+        assert!(history.commands[&1].run_options().input);
+        history.do_update(ServerMessage::new(
+            0,
+            ServerNotice::CommandCompleted(1, Exited(0), now, None),
+        ));
+        assert_eq!(history.commands[&1].stdin, StdinState::Closed);
+    }
+
+    #[test]
+    fn notices_about_unknown_commands_are_skipped() {
+        let mut history = CommandHistory::new();
+        let now = SystemTime::now();
+        history.do_update(ServerMessage::new(
+            0,
+            ServerNotice::CommandOutput(1, b"lost".to_vec()),
+        ));
+        history.do_update(ServerMessage::new(
+            0,
+            ServerNotice::CommandCompleted(1, Exited(0), now, None),
+        ));
+        assert!(history.commands.is_empty());
+        assert_eq!(history.next_index(), 1);
+    }
+
+    fn history_of(count: CommandId) -> CommandHistory {
+        let mut history = CommandHistory::new();
+        let now = SystemTime::now();
+        for i in 1..count + 1 {
+            let notices = vec![
+                ServerNotice::CommandStarted(i, format!("echo {}", i), now),
+                ServerNotice::CommandOutput(i, b"0123456789".to_vec()),
+            ];
+            for notice in notices {
+                history.do_update(ServerMessage::new(0, notice));
+            }
+        }
+        history
+    }
+
+    fn page_ids(page: &(Vec<(CommandId, WeaverCommand, OutputOffsets)>, bool)) -> Vec<CommandId> {
+        page.0.iter().map(|&(id, _, _)| id).collect()
+    }
+
+    #[test]
+    fn pages_run_from_newest_to_oldest() {
+        let history = history_of(5);
+        let page = history.page(None, 2, 4);
+        assert_eq!((page_ids(&page), page.1), (vec![5, 4], true));
+        let page = history.page(Some(4), 2, 4);
+        assert_eq!((page_ids(&page), page.1), (vec![3, 2], true));
+        let page = history.page(Some(2), 2, 4);
+        assert_eq!((page_ids(&page), page.1), (vec![1], false));
+        let page = history.page(Some(1), 2, 4);
+        assert_eq!((page_ids(&page), page.1), (vec![], false));
+    }
+
+    #[test]
+    fn pages_hold_the_tail_of_each_commands_output() {
+        let history = history_of(1);
+        let (page, _) = history.page(None, 1, 4);
+        let (_, ref cmd, ref offsets) = page[0];
+        assert_eq!(cmd.stdout, b"6789".to_vec());
+        assert_eq!(offsets.get(OutputStream::Stdout), 6);
+        let (page, _) = history.page(None, 1, usize::MAX);
+        assert_eq!(page[0].1.stdout, b"0123456789".to_vec());
+    }
+
+    #[test]
+    fn pages_are_limited_in_size() {
+        let history = history_of(MAX_PAGE_SIZE as CommandId + 1);
+        let page = history.page(None, usize::MAX, 0);
+        assert_eq!(page.0.len(), MAX_PAGE_SIZE);
+        assert!(page.1);
+        let page = history.page(None, 0, 0);
+        assert_eq!((page_ids(&page), page.1), (vec![], true));
+    }
+
+    #[test]
+    fn hellos_from_other_versions_are_refused() {
+        let mut hello = Hello::new("cli");
+        assert_eq!(hello.check_version(), Ok(()));
+        hello.version = PROTOCOL_VERSION + 1;
+        let reason = hello.check_version().unwrap_err();
+        assert!(reason.contains(&format!("version {}", PROTOCOL_VERSION)));
+        assert!(reason.contains(&format!(
+            "cli client speaks version {}",
+            PROTOCOL_VERSION + 1
+        )));
+    }
+
+    /// How every version must be able to decode the first message in each direction.
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct FrozenHello {
+        version: u32,
+        kind: String,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum FrozenRequest {
+        V0,
+        V1,
+        V2,
+        V3,
+        V4,
+        V5,
+        V6,
+        Hello(FrozenHello),
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum FrozenNotice {
+        V0,
+        V1,
+        V2,
+        V3,
+        V4,
+        V5,
+        V6,
+        V7,
+        V8,
+        V9,
+        V10,
+        Hello(FrozenHello),
+        Incompatible(String),
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct FrozenMessage<T> {
+        id: u32,
+        body: T,
+    }
+
+    #[test]
+    fn hellos_keep_their_encoding() {
+        let frozen = || FrozenHello {
+            version: PROTOCOL_VERSION,
+            kind: "cli".to_owned(),
+        };
+        let request = ClientMessage {
+            id: 1,
+            request: ClientRequest::Hello(Hello::new("cli")),
+        };
+        let data = encode::to_vec(&request).unwrap();
+        let decoded: FrozenMessage<FrozenRequest> = decode::from_read(&data[..]).unwrap();
+        assert_eq!(decoded.body, FrozenRequest::Hello(frozen()));
+
+        let notice = ServerMessage::new(1, ServerNotice::Hello(Hello::new("cli")));
+        let data = encode::to_vec(&notice).unwrap();
+        let decoded: FrozenMessage<FrozenNotice> = decode::from_read(&data[..]).unwrap();
+        assert_eq!(decoded.body, FrozenNotice::Hello(frozen()));
+
+        let notice = ServerMessage::new(1, ServerNotice::Incompatible("no".to_owned()));
+        let data = encode::to_vec(&notice).unwrap();
+        let decoded: FrozenMessage<FrozenNotice> = decode::from_read(&data[..]).unwrap();
+        assert_eq!(decoded.body, FrozenNotice::Incompatible("no".to_owned()));
+    }
+
+    #[test]
+    fn paths_are_resolved_from_the_environment() {
+        let home = Some(PathBuf::from("/home/weaver"));
+        let socket = Some(OsString::from("/run/weaver.socket"));
+        let paths = WeaverPaths::from_env(
+            home.clone(),
+            Some(OsString::from("relative")),
+            socket.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            paths.config,
+            PathBuf::from("/home/weaver/.config/weaver/config.toml")
+        );
+        assert_eq!(paths.history, PathBuf::from("/home/weaver/.weaver.history"));
+        assert_eq!(paths.log, PathBuf::from("/home/weaver/.weaver.log"));
+        assert_eq!(paths.socket, PathBuf::from("/run/weaver.socket"));
+        let paths =
+            WeaverPaths::from_env(home, Some(OsString::from("/etc/xdg")), socket.clone()).unwrap();
+        assert_eq!(paths.config, PathBuf::from("/etc/xdg/weaver/config.toml"));
+        assert!(WeaverPaths::from_env(None, None, socket).is_err());
+    }
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// The streams and output of each entry in `cmd`'s timeline.
+    fn entries(cmd: &WeaverCommand) -> Vec<(OutputStream, &[u8])> {
+        cmd.timeline()
+            .iter()
+            .map(|entry| (entry.stream, entry.output))
+            .collect()
+    }
+
+    #[test]
+    fn output_keeps_the_order_it_was_written_in() {
+        use OutputStream::*;
+        let mut cmd = WeaverCommand::new("make".to_owned());
+        cmd.push_output(Stdout, b"one ".to_vec(), at(10));
+        cmd.push_output(Stderr, b"two ".to_vec(), at(10));
+        cmd.push_output(Stdout, b"three ".to_vec(), at(10));
+        cmd.push_output(Stderr, b"four".to_vec(), at(11));
+        assert_eq!(cmd.stdout, b"one three ");
+        assert_eq!(cmd.stderr, b"two four");
+        let timeline = cmd.timeline();
+        assert_eq!(
+            timeline
+                .iter()
+                .map(|entry| entry.offset)
+                .collect::<Vec<_>>(),
+            vec![0, 0, 4, 4]
+        );
+        assert_eq!(timeline[3].time, at(11));
+        assert_eq!(
+            entries(&cmd),
+            vec![
+                (Stdout, &b"one "[..]),
+                (Stderr, &b"two "[..]),
+                (Stdout, &b"three "[..]),
+                (Stderr, &b"four"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn chunks_span_at_most_a_second() {
+        use OutputStream::*;
+        let mut cmd = WeaverCommand::new("make".to_owned());
+        cmd.push_output(Stdout, b"a".to_vec(), at(10));
+        cmd.push_output(Stdout, b"b".to_vec(), at(10));
+        cmd.push_output(Stdout, b"c".to_vec(), at(11));
+        cmd.push_output(Stdout, vec![], at(20));
+        assert_eq!(
+            cmd.chunks,
+            vec![
+                OutputChunk {
+                    stream: Stdout,
+                    len: 2,
+                    time: at(10),
+                },
+                OutputChunk {
+                    stream: Stdout,
+                    len: 1,
+                    time: at(11),
+                },
+            ]
+        );
+        // Without times from the daemon, only a change of stream starts a chunk
+        let mut cmd = WeaverCommand::new("make".to_owned());
+        cmd.push_output(Pty, b"a".to_vec(), None);
+        cmd.push_output(Pty, b"b".to_vec(), None);
+        assert_eq!(entries(&cmd), vec![(Pty, &b"ab"[..])]);
+    }
+
+    #[test]
+    fn truncating_output_shrinks_its_chunks() {
+        use OutputStream::*;
+        let mut cmd = WeaverCommand::new("make".to_owned());
+        cmd.push_output(Stdout, b"aaaa".to_vec(), at(1));
+        cmd.push_output(Stderr, b"bb".to_vec(), at(1));
+        cmd.push_output(Stdout, b"cccc".to_vec(), at(1));
+        cmd.push_output(Stderr, b"dd".to_vec(), at(1));
+        cmd.truncate_output(Stdout, 2, 4);
+        assert_eq!(
+            entries(&cmd),
+            vec![
+                (Stdout, &b"aa"[..]),
+                (Stderr, &b"bb"[..]),
+                (Stdout, &b"cc"[..]),
+                (Stderr, &b"dd"[..]),
+            ]
+        );
+        cmd.truncate_output(Stderr, 0, 2);
+        assert_eq!(
+            entries(&cmd),
+            vec![
+                (Stdout, &b"aa"[..]),
+                (Stdout, &b"cc"[..]),
+                (Stderr, &b"dd"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn output_of_unknown_order_comes_first() {
+        use OutputStream::*;
+        let mut cmd = WeaverCommand::new("make".to_owned());
+        cmd.push_output(Stderr, b"late".to_vec(), at(1));
+        cmd.push_output(Stdout, b"later".to_vec(), at(1));
+        cmd.prepend_output(Stdout, b"early ");
+        let timeline = cmd.timeline();
+        assert_eq!((timeline[0].offset, timeline[0].time), (0, None));
+        assert_eq!(
+            entries(&cmd),
+            vec![
+                (Stdout, &b"early "[..]),
+                (Stderr, &b"late"[..]),
+                (Stdout, &b"later"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn tails_keep_the_order_of_their_output() {
+        use OutputStream::*;
+        let mut cmd = WeaverCommand::new("make".to_owned());
+        cmd.push_output(Stdout, b"0123".to_vec(), at(1));
+        cmd.push_output(Stderr, b"4567".to_vec(), at(1));
+        cmd.push_output(Stdout, b"89".to_vec(), at(1));
+        let (tail, offsets) = cmd.tail(3);
+        assert_eq!((offsets.stdout, offsets.stderr), (3, 1));
+        assert_eq!(
+            entries(&tail),
+            vec![
+                (Stdout, &b"3"[..]),
+                (Stderr, &b"567"[..]),
+                (Stdout, &b"89"[..]),
+            ]
+        );
+    }
 }
@@ -0,0 +1,153 @@
+//! The daemon's log, which goes to its log file once it detaches.
+//!
+//! Every line starts with when it was written, in UTC, and how much it matters.  Events are
+//! always logged; `-v` adds requests and replies, and `-vv` every notice.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Logs a line at a `Level`, formatted like `println!`.
+#[macro_export]
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::$level, format_args!($($arg)*))
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    /// Requests and replies.
+    Debug,
+    /// Every notice.
+    Trace,
+}
+
+impl Level {
+    /// The verbosity it takes for lines at this level to be logged.
+    fn verbosity(self) -> usize {
+        match self {
+            Level::Error | Level::Warn | Level::Info => 0,
+            Level::Debug => 1,
+            Level::Trace => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_verbosity(verbosity: usize) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level.verbosity()
+}
+
+/// Logs a line, if the verbosity allows; use `log!` rather than calling this directly.
+pub fn write(level: Level, args: fmt::Arguments) {
+    if enabled(level) {
+        println!("{}", line(SystemTime::now(), level, args));
+    }
+}
+
+fn line(time: SystemTime, level: Level, args: fmt::Arguments) -> String {
+    format!("{} {:<5} {}", timestamp(time), level.name(), args)
+}
+
+/// Formats a time like `2018-06-01 12:00:00.000`.
+fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_date((secs / 86_400) as i64);
+    let time_of_day = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        since.subsec_millis()
+    )
+}
+
+/// The Gregorian date some number of days after 1970-01-01.
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    // Counted from 0000-03-01, so that leap days fall at the end of each year
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn at(secs: u64, millis: u32) -> SystemTime {
+        UNIX_EPOCH + Duration::new(secs, millis * 1_000_000)
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(59), (1970, 3, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(11_017), (2000, 3, 1));
+        assert_eq!(civil_date(17_683), (2018, 6, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn lines_have_a_time_and_level() {
+        assert_eq!(
+            line(
+                at(1_527_854_400, 7),
+                Level::Warn,
+                format_args!("{} lost", 3)
+            ),
+            "2018-06-01 12:00:00.007 WARN  3 lost"
+        );
+        assert_eq!(timestamp(at(86_399, 999)), "1970-01-01 23:59:59.999");
+    }
+
+    #[test]
+    fn verbosity_gates_levels() {
+        set_verbosity(0);
+        assert!(enabled(Level::Error) && enabled(Level::Info));
+        assert!(!enabled(Level::Debug));
+        set_verbosity(1);
+        assert!(enabled(Level::Debug) && !enabled(Level::Trace));
+        set_verbosity(2);
+        assert!(enabled(Level::Trace));
+        set_verbosity(0);
+    }
+}
@@ -10,22 +10,30 @@ use super::libc;
 use super::tokio::reactor::PollEvented2 as PollEvented;
 use super::tokio_io::IoFuture;
 use super::tokio_signal::unix::Signal;
+use super::{ExitStatus, ResourceUsage, WindowSize};
 
-use std::io;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
 use std::os::unix::prelude::*;
-use std::process::{self, ExitStatus};
+use std::path::Path;
+use std::process;
+use std::ptr;
+use std::time::Duration;
 
 pub struct Child {
     inner: process::Child,
     reaped: bool,
+    rusage: Option<ResourceUsage>,
     sigchld: FlattenStream<IoFuture<Signal>>,
 }
 
 impl Child {
     pub fn new(inner: process::Child) -> Child {
         Child {
-            inner: inner,
+            inner,
             reaped: false,
+            rusage: None,
             sigchld: Signal::new(libc::SIGCHLD).flatten_stream(),
         }
     }
@@ -34,6 +42,11 @@ impl Child {
         self.inner.id()
     }
 
+    /// The resources used by the child, once it has been reaped.
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.rusage
+    }
+
     pub fn kill(&mut self) -> io::Result<()> {
         if !self.reaped {
             // NB: SIGKILL cannnot be caught, so the process will definitely exit immediately.
@@ -45,11 +58,38 @@ impl Child {
         Ok(())
     }
 
+    pub fn signal(&mut self, signal: libc::c_int) -> io::Result<()> {
+        if self.reaped {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "process has already exited",
+            ));
+        }
+        match unsafe { libc::kill(self.id() as libc::pid_t, signal) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// The process group the child is in, while it hasn't been reaped.
+    pub fn process_group(&self) -> io::Result<u32> {
+        if self.reaped {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "process has already exited",
+            ));
+        }
+        match unsafe { libc::getpgid(self.id() as libc::pid_t) } {
+            -1 => Err(io::Error::last_os_error()),
+            pgid => Ok(pgid as u32),
+        }
+    }
+
     pub fn poll_exit(&mut self) -> Poll<ExitStatus, io::Error> {
         loop {
             // Ensure that once we've successfully waited we won't try to
             // `kill` above.
-            if let Some(e) = try!(self.try_wait(false)) {
+            if let Some(e) = self.try_wait(false)? {
                 return Ok(e.into());
             }
 
@@ -59,7 +99,7 @@ impl Child {
             //
             // As described in `spawn` above, we just indicate that we can
             // next make progress once a SIGCHLD is received.
-            if try!(self.sigchld.poll()).is_not_ready() {
+            if self.sigchld.poll()?.is_not_ready() {
                 return Ok(Async::NotReady);
             }
         }
@@ -67,22 +107,30 @@ impl Child {
 
     fn try_wait(&mut self, block_on_wait: bool) -> io::Result<Option<ExitStatus>> {
         assert!(!self.reaped);
-        let exit = try!(try_wait_process(self.id() as libc::pid_t, block_on_wait));
+        let exit = try_wait_process(self.id() as libc::pid_t, block_on_wait)?;
 
-        if let Some(_) = exit {
+        Ok(exit.map(|(status, rusage)| {
             self.reaped = true;
-        }
-
-        Ok(exit)
+            self.rusage = Some(rusage);
+            status
+        }))
     }
 }
 
-fn try_wait_process(id: libc::pid_t, block_on_wait: bool) -> io::Result<Option<ExitStatus>> {
+fn timeval_duration(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+fn try_wait_process(
+    id: libc::pid_t,
+    block_on_wait: bool,
+) -> io::Result<Option<(ExitStatus, ResourceUsage)>> {
     let wait_flags = if block_on_wait { 0 } else { libc::WNOHANG };
     let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { mem::zeroed() };
 
     loop {
-        match unsafe { libc::waitpid(id, &mut status, wait_flags) } {
+        match unsafe { libc::wait4(id, &mut status, wait_flags, &mut rusage) } {
             0 => return Ok(None),
             n if n < 0 => {
                 let err = io::Error::last_os_error();
@@ -93,7 +141,14 @@ fn try_wait_process(id: libc::pid_t, block_on_wait: bool) -> io::Result<Option<E
             }
             n => {
                 assert_eq!(n, id);
-                return Ok(Some(ExitStatus::from_raw(status)));
+                let usage = ResourceUsage {
+                    user_time: timeval_duration(rusage.ru_utime),
+                    system_time: timeval_duration(rusage.ru_stime),
+                    max_rss: rusage.ru_maxrss as u64,
+                    blocks_read: rusage.ru_inblock as u64,
+                    blocks_written: rusage.ru_oublock as u64,
+                };
+                return Ok(Some((ExitStatus::from_raw(status), usage)));
             }
         }
     }
@@ -127,9 +182,19 @@ impl<T: io::Write> io::Write for Fd<T> {
     }
 }
 
-pub type ChildStdin = PollEvented<Fd<process::ChildStdin>>;
-pub type ChildStdout = PollEvented<Fd<process::ChildStdout>>;
-pub type ChildStderr = PollEvented<Fd<process::ChildStderr>>;
+pub type ChildStdin = PollEvented<Fd<File>>;
+pub type ChildStdout = PollEvented<Fd<File>>;
+pub type ChildStderr = PollEvented<Fd<File>>;
+pub type Pty = PollEvented<Fd<File>>;
+
+impl<T> AsRawFd for Fd<T>
+where
+    T: AsRawFd,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
 
 impl<T> Evented for Fd<T>
 where
@@ -179,3 +244,191 @@ where
     let io = PollEvented::new(Fd(io));
     Ok(io)
 }
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let r = libc::fcntl(fd, libc::F_GETFD);
+        if r == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let r = libc::fcntl(fd, libc::F_SETFD, r | libc::FD_CLOEXEC);
+        if r == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Opens a pipe, returning the (read, write) pair.
+pub fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let read = unsafe { File::from_raw_fd(fds[0]) };
+    let write = unsafe { File::from_raw_fd(fds[1]) };
+    // Children are only given the ends meant for them, dup'd onto their stdio
+    set_cloexec(read.as_raw_fd())?;
+    set_cloexec(write.as_raw_fd())?;
+    Ok((read, write))
+}
+
+fn winsize(size: WindowSize) -> libc::winsize {
+    libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// Opens a new pseudo-terminal, returning the (master, slave) pair.
+pub fn openpty(size: WindowSize) -> io::Result<(File, File)> {
+    let mut master: libc::c_int = -1;
+    let mut slave: libc::c_int = -1;
+    let size = winsize(size);
+    let r = unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &size) };
+    if r == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let master = unsafe { File::from_raw_fd(master) };
+    let slave = unsafe { File::from_raw_fd(slave) };
+    // Neither end should leak into other children; the slave is dup'd onto stdio for its child.
+    set_cloexec(master.as_raw_fd())?;
+    set_cloexec(slave.as_raw_fd())?;
+    Ok((master, slave))
+}
+
+pub fn set_window_size(fd: RawFd, size: WindowSize) -> io::Result<()> {
+    let size = winsize(size);
+    match unsafe { libc::ioctl(fd, libc::TIOCSWINSZ as _, &size) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Runs in the child between fork and exec, making the pty `fd` its controlling terminal.
+pub fn acquire_controlling_terminal(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(fd, libc::TIOCSCTTY as _, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Runs in the child between fork and exec, moving it into the process group `pgid`, or into a
+/// new one of its own if `pgid` is 0 or that group has gone.
+pub fn join_process_group(pgid: u32) -> io::Result<()> {
+    unsafe {
+        if libc::setpgid(0, pgid as libc::pid_t) == -1 && libc::setpgid(0, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Sends `signal` to every process in the group `pgid`.
+pub fn signal_process_group(pgid: u32, signal: libc::c_int) -> io::Result<()> {
+    // Never our own group, which would signal the daemon too
+    if pgid == 0 || pgid == unsafe { libc::getpgrp() } as u32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "process shares the daemon's process group",
+        ));
+    }
+    match unsafe { libc::kill(-(pgid as libc::pid_t), signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Detaches from whoever started us and their terminal, by forking twice with a new session in
+/// between, and sends stdout and stderr to `log`.  Only the final process returns.
+///
+/// Call it before starting any threads, which only the calling thread would survive.
+pub fn detach(log: &Path) -> io::Result<()> {
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(log)?;
+    let null = File::open("/dev/null")?;
+    io::stdout().flush()?;
+    unsafe {
+        for i in 0..2 {
+            match libc::fork() {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {}
+                _ => libc::_exit(0),
+            }
+            // The second fork leaves a process that isn't a session leader, so that opening a
+            // terminal can never make it the controlling one
+            if i == 0 && libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for &(from, to) in &[
+            (null.as_raw_fd(), 0),
+            (log.as_raw_fd(), 1),
+            (log.as_raw_fd(), 2),
+        ] {
+            if libc::dup2(from, to) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::{Command, Stdio};
+
+    /// Whether `pid` has exited, even if nothing has reaped it yet.
+    fn exited(pid: u32) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat.split(") ").nth(1).is_none_or(|s| s.starts_with('Z')),
+            Err(_) => true,
+        }
+    }
+
+    #[test]
+    fn signals_reach_the_whole_process_group() {
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "sleep 30 & echo $!; wait"])
+            .stdout(Stdio::piped());
+        unsafe { command.pre_exec(|| join_process_group(0)) };
+        let mut child = command.spawn().unwrap();
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        let grandchild: u32 = line.trim().parse().unwrap();
+
+        signal_process_group(child.id(), libc::SIGTERM).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+        for _ in 0..50 {
+            if exited(grandchild) {
+                return;
+            }
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("sleep {} outlived the signal", grandchild);
+    }
+
+    #[test]
+    fn never_signals_its_own_process_group() {
+        let own = unsafe { libc::getpgrp() } as u32;
+        assert!(signal_process_group(own, 0).is_err());
+        assert!(signal_process_group(0, 0).is_err());
+    }
+}
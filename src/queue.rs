@@ -0,0 +1,384 @@
+//! The daemon's queue of messages for each client.
+//!
+//! Notices are broadcast to every client as they happen, so a client that stops reading would
+//! otherwise have them pile up in the daemon without limit.  Each queue holds a limited number
+//! of notices, and bytes of them, and the `[clients]` section of the config file says what
+//! happens once a client has that many waiting.  Replies, and notices replayed at a client's
+//! request, are never held back, since a client only gets as many of those as it asks for.
+
+use super::futures::task::{self, Task};
+use super::futures::Async;
+use super::{ServerMessage, ServerNotice};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WhenFull {
+    /// Merge output into the notice queued before it where possible, or else fall behind as
+    /// with `Resync`.
+    Coalesce,
+    /// Drop notices until the client has caught up with those queued, then have it resync.
+    Resync,
+    /// Hang up on the client, which can reconnect and resync.
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct QueuePolicy {
+    /// Notices queued for a single client before it counts as full.
+    pub limit: usize,
+    /// Bytes of notices queued for a single client before it counts as full, however few.
+    pub max_bytes: usize,
+    /// Bytes of output a notice can grow to by being merged with those after it.
+    pub max_merged_bytes: usize,
+    pub when_full: WhenFull,
+}
+
+impl Default for QueuePolicy {
+    fn default() -> Self {
+        QueuePolicy {
+            limit: 1024,
+            max_bytes: 16 * 1024 * 1024,
+            max_merged_bytes: 1024 * 1024,
+            when_full: WhenFull::Coalesce,
+        }
+    }
+}
+
+/// How a client's queue has fared.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct QueueStats {
+    /// Messages currently waiting to be sent.
+    pub queued: usize,
+    /// The most messages that have been waiting at once.
+    pub peak: usize,
+    /// Notices merged into the one before them.
+    pub coalesced: u64,
+    /// Notices dropped while the client was behind.
+    pub dropped: u64,
+    /// How many times the client fell behind.
+    pub fell_behind: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Flow {
+    Flowing,
+    /// Dropping notices, until everything queued before has been sent.
+    Behind,
+    Closed,
+}
+
+struct Queue {
+    /// Each message, along with whether it counts towards the limit.
+    messages: VecDeque<(ServerMessage, bool)>,
+    /// How many of `messages` count towards the limit.
+    notices: usize,
+    /// Roughly how many bytes those take up.
+    bytes: usize,
+    flow: Flow,
+    stats: QueueStats,
+    task: Option<Task>,
+}
+
+/// One client's queue, shared by whatever sends it messages and the connection sending them on.
+#[derive(Clone)]
+pub struct ClientQueue {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl Default for ClientQueue {
+    fn default() -> Self {
+        ClientQueue::new()
+    }
+}
+
+impl ClientQueue {
+    pub fn new() -> Self {
+        let queue = Queue {
+            messages: VecDeque::new(),
+            notices: 0,
+            bytes: 0,
+            flow: Flow::Flowing,
+            stats: QueueStats::default(),
+            task: None,
+        };
+        ClientQueue {
+            queue: Arc::new(Mutex::new(queue)),
+        }
+    }
+
+    /// Queues a broadcast notice, unless the client is already too far behind to take it.
+    pub fn broadcast(&self, msg: ServerMessage, policy: &QueuePolicy) {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.flow {
+            Flow::Flowing => {}
+            Flow::Behind => {
+                queue.stats.dropped += 1;
+                return;
+            }
+            Flow::Closed => return,
+        }
+        let size = msg.notice.size();
+        if queue.notices >= policy.limit || queue.bytes + size > policy.max_bytes {
+            // Merging saves a notice but none of its bytes
+            let room = queue.bytes + size <= policy.max_bytes;
+            match policy.when_full {
+                WhenFull::Coalesce if room && queue.coalesce(&msg, policy.max_merged_bytes) => {
+                    queue.stats.coalesced += 1;
+                    return;
+                }
+                WhenFull::Coalesce | WhenFull::Resync => {
+                    queue.flow = Flow::Behind;
+                    queue.stats.dropped += 1;
+                    queue.stats.fell_behind += 1;
+                    return;
+                }
+                WhenFull::Disconnect => {
+                    queue.flow = Flow::Closed;
+                    queue.notify();
+                    return;
+                }
+            }
+        }
+        queue.notices += 1;
+        queue.bytes += size;
+        queue.push(msg, true);
+    }
+
+    /// Queues a message the client asked for.
+    pub fn send(&self, msg: ServerMessage) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.flow != Flow::Closed {
+            queue.push(msg, false);
+        }
+    }
+
+    /// Takes the next message to send, or `None` once the client is to be hung up on.  A client
+    /// that fell behind is told so once everything queued before has been taken.
+    pub fn poll(&self) -> Async<Option<ServerMessage>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.flow == Flow::Closed {
+            return Async::Ready(None);
+        }
+        match queue.messages.pop_front() {
+            Some((msg, counted)) => {
+                if counted {
+                    queue.notices -= 1;
+                    queue.bytes -= msg.notice.size();
+                }
+                queue.stats.queued -= 1;
+                Async::Ready(Some(msg))
+            }
+            None if queue.flow == Flow::Behind => {
+                queue.flow = Flow::Flowing;
+                Async::Ready(Some(ServerMessage::new(0, ServerNotice::FellBehind)))
+            }
+            None => {
+                queue.task = Some(task::current());
+                Async::NotReady
+            }
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.queue.lock().unwrap().stats
+    }
+}
+
+impl Queue {
+    fn push(&mut self, msg: ServerMessage, counted: bool) {
+        self.messages.push_back((msg, counted));
+        self.stats.queued += 1;
+        self.stats.peak = self.stats.peak.max(self.stats.queued);
+        self.notify();
+    }
+
+    fn notify(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+
+    /// Appends the output in `msg` to the last notice queued, if that is output from the same
+    /// stream, numbered just before it, and the two hold no more than `max_bytes` of output.
+    fn coalesce(&mut self, msg: &ServerMessage, max_bytes: usize) -> bool {
+        use ServerNotice::*;
+        let last = match self.messages.back_mut() {
+            Some(&mut (ref mut last, true)) if last.id == msg.id && last.seq + 1 == msg.seq => last,
+            _ => return false,
+        };
+        match (&mut last.notice, &msg.notice) {
+            (&mut CommandOutput(i, ref mut bytes), &CommandOutput(j, ref more))
+            | (&mut CommandErr(i, ref mut bytes), &CommandErr(j, ref more))
+            | (&mut CommandPtyOutput(i, ref mut bytes), &CommandPtyOutput(j, ref more))
+                if i == j && bytes.len() + more.len() <= max_bytes =>
+            {
+                bytes.extend_from_slice(more);
+                self.bytes += more.len();
+            }
+            _ => return false,
+        }
+        last.seq = msg.seq;
+        last.coalesced += 1 + msg.coalesced;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(seq: u64, bytes: &[u8]) -> ServerMessage {
+        let mut msg = ServerMessage::new(0, ServerNotice::CommandOutput(1, bytes.to_vec()));
+        msg.seq = seq;
+        msg
+    }
+
+    fn policy(limit: usize, when_full: WhenFull) -> QueuePolicy {
+        QueuePolicy {
+            limit,
+            when_full,
+            ..QueuePolicy::default()
+        }
+    }
+
+    /// Takes everything queued, which must not leave the queue waiting on a task.
+    fn drain(queue: &ClientQueue) -> Vec<ServerMessage> {
+        let mut taken = vec![];
+        while queue.stats().queued > 0 {
+            match queue.poll() {
+                Async::Ready(Some(msg)) => taken.push(msg),
+                _ => break,
+            }
+        }
+        taken
+    }
+
+    #[test]
+    fn full_queues_merge_output() {
+        let queue = ClientQueue::new();
+        let policy = policy(2, WhenFull::Coalesce);
+        for seq in 1..6 {
+            queue.broadcast(output(seq, b"ab"), &policy);
+        }
+        let taken = drain(&queue);
+        assert_eq!(taken.len(), 2);
+        assert_eq!(
+            taken[1].notice,
+            ServerNotice::CommandOutput(1, b"abababab".to_vec())
+        );
+        assert_eq!((taken[1].seq, taken[1].coalesced), (5, 3));
+        assert_eq!(queue.stats().coalesced, 3);
+        assert_eq!(queue.stats().fell_behind, 0);
+    }
+
+    #[test]
+    fn merged_output_is_capped() {
+        let queue = ClientQueue::new();
+        let policy = QueuePolicy {
+            max_merged_bytes: 4,
+            ..policy(1, WhenFull::Coalesce)
+        };
+        for seq in 1..5 {
+            queue.broadcast(output(seq, b"ab"), &policy);
+        }
+        let taken = drain(&queue);
+        assert_eq!(taken.len(), 1);
+        assert_eq!(
+            taken[0].notice,
+            ServerNotice::CommandOutput(1, b"abab".to_vec())
+        );
+        let stats = queue.stats();
+        assert_eq!(
+            (stats.coalesced, stats.dropped, stats.fell_behind),
+            (1, 2, 1)
+        );
+        assert_eq!(
+            queue.poll(),
+            Async::Ready(Some(ServerMessage::new(0, ServerNotice::FellBehind)))
+        );
+    }
+
+    #[test]
+    fn queues_are_capped_in_bytes() {
+        let queue = ClientQueue::new();
+        let size = output(1, &[b'x'; 100]).notice.size();
+        let policy = QueuePolicy {
+            max_bytes: 2 * size,
+            ..policy(1000, WhenFull::Coalesce)
+        };
+        queue.broadcast(output(1, &[b'x'; 100]), &policy);
+        queue.broadcast(output(2, &[b'x'; 100]), &policy);
+        // No room for a third, merged or not
+        queue.broadcast(output(3, &[b'x'; 100]), &policy);
+        assert_eq!(queue.stats().fell_behind, 1);
+        assert_eq!(drain(&queue).len(), 2);
+        assert_eq!(
+            queue.poll(),
+            Async::Ready(Some(ServerMessage::new(0, ServerNotice::FellBehind)))
+        );
+        // Taking them all frees their bytes
+        queue.broadcast(output(4, &[b'x'; 100]), &policy);
+        queue.broadcast(output(5, &[b'x'; 100]), &policy);
+        assert_eq!(drain(&queue).len(), 2);
+        assert_eq!(queue.stats().fell_behind, 1);
+    }
+
+    #[test]
+    fn clients_behind_resync_once_caught_up() {
+        let queue = ClientQueue::new();
+        let policy = policy(2, WhenFull::Resync);
+        for seq in 1..6 {
+            queue.broadcast(output(seq, b"ab"), &policy);
+        }
+        let stats = queue.stats();
+        assert_eq!(
+            (stats.coalesced, stats.dropped, stats.fell_behind),
+            (0, 3, 1)
+        );
+        let taken = drain(&queue);
+        assert_eq!(
+            taken.iter().map(|msg| msg.seq).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            queue.poll(),
+            Async::Ready(Some(ServerMessage::new(0, ServerNotice::FellBehind)))
+        );
+        queue.broadcast(output(6, b"ab"), &policy);
+        assert_eq!(drain(&queue)[0].seq, 6);
+    }
+
+    #[test]
+    fn full_queues_can_disconnect() {
+        let queue = ClientQueue::new();
+        let policy = policy(1, WhenFull::Disconnect);
+        queue.broadcast(output(1, b"ab"), &policy);
+        queue.broadcast(output(2, b"ab"), &policy);
+        assert_eq!(queue.poll(), Async::Ready(None));
+        queue.send(output(3, b"ab"));
+        assert_eq!(queue.poll(), Async::Ready(None));
+    }
+
+    #[test]
+    fn replies_are_never_held_back() {
+        let queue = ClientQueue::new();
+        let policy = QueuePolicy {
+            max_bytes: 0,
+            ..policy(0, WhenFull::Resync)
+        };
+        for seq in 1..4 {
+            queue.send(output(seq, b"ab"));
+        }
+        queue.broadcast(output(4, b"ab"), &policy);
+        let taken = drain(&queue);
+        assert_eq!(
+            taken.iter().map(|msg| msg.seq).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(queue.stats().fell_behind, 1);
+    }
+}
@@ -0,0 +1,293 @@
+//! Limits on how much command output the daemon keeps.
+//!
+//! The policy never modifies history directly.  Instead it produces `ServerNotice`s, which the
+//! daemon broadcasts and persists like any other, so clients and the on-disk store discard
+//! exactly the same output.
+
+use super::encoding::is_char_boundary;
+use super::{CommandHistory, CommandId, OutputStream, ServerNotice, WeaverCommand, OUTPUT_STREAMS};
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Bytes of output kept per stream of a single command.
+    pub max_command_output: usize,
+    /// Bytes from the start of a truncated stream kept, the rest of the limit is its tail.
+    pub keep_head: usize,
+    /// Bytes of output kept across all of history, discarding output of the oldest commands first.
+    pub max_history_output: usize,
+    /// Seconds after finishing that a command is removed from history entirely.
+    pub max_age: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_command_output: 1024 * 1024,
+            keep_head: 64 * 1024,
+            max_history_output: 64 * 1024 * 1024,
+            max_age: None,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Truncates the middle of any of `cmd`'s output streams that have grown past the limit.
+    pub fn enforce_command(&self, id: CommandId, cmd: &WeaverCommand) -> Vec<ServerNotice> {
+        let mut notices = vec![];
+        // Allow some slack over the limit, so that output isn't shuffled around on every chunk
+        let threshold = self.max_command_output + self.max_command_output / 4;
+        for &stream in OUTPUT_STREAMS.iter() {
+            let len = cmd.output_len(stream);
+            if len <= threshold {
+                continue;
+            }
+            let offset = match (cmd.truncation(stream), stream) {
+                (Some(truncation), _) => truncation.offset,
+                // Only the end of a terminal's output is needed to show its screen
+                (None, OutputStream::Pty) => 0,
+                (None, _) => self.keep_head.min(self.max_command_output),
+            };
+            let end = offset + len - self.max_command_output;
+            let (start, end) = char_boundaries(cmd.output(stream), offset, end);
+            notices.push(ServerNotice::OutputTruncated(
+                id,
+                stream,
+                start,
+                end - start,
+            ));
+        }
+        notices
+    }
+
+    /// Expires commands that finished too long ago, then discards all output of the oldest
+    /// finished commands until history fits within its budget.
+    pub fn enforce_history(
+        &self,
+        history: &CommandHistory,
+        finished: &HashMap<CommandId, SystemTime>,
+    ) -> Vec<ServerNotice> {
+        let mut notices = vec![];
+        let now = SystemTime::now();
+        let expired: Vec<CommandId> = match self.max_age {
+            Some(max_age) => finished
+                .iter()
+                .filter(|&(_, &time)| match now.duration_since(time) {
+                    Ok(age) => age > Duration::from_secs(max_age),
+                    Err(_) => false,
+                })
+                .map(|(&id, _)| id)
+                .collect(),
+            None => vec![],
+        };
+
+        let mut total: usize = history
+            .iter()
+            .filter(|&(id, _)| !expired.contains(id))
+            .map(|(_, cmd)| {
+                OUTPUT_STREAMS
+                    .iter()
+                    .map(|&s| cmd.output_len(s))
+                    .sum::<usize>()
+            })
+            .sum();
+        for (&id, cmd) in history.iter() {
+            if total <= self.max_history_output {
+                break;
+            }
+            if cmd.status.is_none() || expired.contains(&id) {
+                continue;
+            }
+            for &stream in OUTPUT_STREAMS.iter() {
+                let len = cmd.output_len(stream);
+                if len > 0 {
+                    notices.push(ServerNotice::OutputTruncated(id, stream, 0, len));
+                    total -= len;
+                }
+            }
+        }
+
+        if !expired.is_empty() {
+            notices.push(ServerNotice::CommandsExpired(expired));
+        }
+        notices
+    }
+}
+
+/// Widens `start..end` to the nearest UTF-8 character boundaries in `output`.
+fn char_boundaries(output: &[u8], mut start: usize, mut end: usize) -> (usize, usize) {
+    while !is_char_boundary(output, start) {
+        start -= 1;
+    }
+    while !is_char_boundary(output, end) {
+        end += 1;
+    }
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {ExitStatus, ServerMessage, Truncation};
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy {
+            max_command_output: 100,
+            keep_head: 10,
+            max_history_output: 250,
+            max_age: None,
+        }
+    }
+
+    fn command(stream: OutputStream, output: &[u8]) -> WeaverCommand {
+        let mut cmd = WeaverCommand::new("cmd".to_owned());
+        cmd.push_output(stream, output.to_vec(), None);
+        cmd
+    }
+
+    fn enforce(policy: &RetentionPolicy, cmd: &mut WeaverCommand) {
+        for notice in policy.enforce_command(1, cmd) {
+            if let ServerNotice::OutputTruncated(_, stream, offset, bytes) = notice {
+                cmd.truncate_output(stream, offset, bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn output_within_the_slack_is_kept() {
+        let cmd = command(OutputStream::Stdout, &[b'x'; 125]);
+        assert_eq!(policy().enforce_command(1, &cmd), vec![]);
+    }
+
+    #[test]
+    fn keeps_the_head_and_tail_of_long_output() {
+        let output: Vec<u8> = (0..200).map(|i| b'!' + (i % 90) as u8).collect();
+        let mut cmd = command(OutputStream::Stdout, &output);
+        assert_eq!(
+            policy().enforce_command(1, &cmd),
+            vec![ServerNotice::OutputTruncated(
+                1,
+                OutputStream::Stdout,
+                10,
+                100
+            )]
+        );
+        enforce(&policy(), &mut cmd);
+        assert_eq!(&cmd.stdout[..10], &output[..10]);
+        assert_eq!(&cmd.stdout[10..], &output[110..]);
+
+        // Later output is cut from the same place, keeping the same head
+        cmd.push_output(OutputStream::Stdout, vec![b'x'; 20], None);
+        enforce(&policy(), &mut cmd);
+        assert_eq!(cmd.stdout.len(), 120);
+        cmd.push_output(OutputStream::Stdout, vec![b'y'; 30], None);
+        enforce(&policy(), &mut cmd);
+        assert_eq!(cmd.stdout.len(), 100);
+        assert_eq!(&cmd.stdout[..10], &output[..10]);
+        assert_eq!(
+            cmd.truncation(OutputStream::Stdout),
+            Some(Truncation {
+                offset: 10,
+                bytes: 150,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_only_the_tail_of_a_terminal() {
+        let mut cmd = command(OutputStream::Pty, &[b'x'; 200]);
+        assert_eq!(
+            policy().enforce_command(1, &cmd),
+            vec![ServerNotice::OutputTruncated(1, OutputStream::Pty, 0, 100)]
+        );
+        enforce(&policy(), &mut cmd);
+        assert_eq!(cmd.pty_output.len(), 100);
+    }
+
+    #[test]
+    fn truncates_whole_characters() {
+        let output = "\u{e9}".repeat(100);
+        let cmd = command(OutputStream::Stderr, output.as_bytes());
+        let notices = policy().enforce_command(1, &cmd);
+        assert_eq!(
+            notices,
+            vec![ServerNotice::OutputTruncated(
+                1,
+                OutputStream::Stderr,
+                10,
+                100
+            )]
+        );
+        let cmd = command(OutputStream::Stderr, &output.as_bytes()[1..]);
+        let notices = policy().enforce_command(1, &cmd);
+        assert_eq!(
+            notices,
+            vec![ServerNotice::OutputTruncated(
+                1,
+                OutputStream::Stderr,
+                9,
+                100
+            )]
+        );
+    }
+
+    fn history_of(outputs: &[(CommandId, usize, bool)]) -> CommandHistory {
+        let mut history = CommandHistory::new();
+        let now = SystemTime::now();
+        for &(id, len, done) in outputs {
+            let notices = vec![
+                ServerNotice::CommandStarted(id, "cmd".to_owned(), now),
+                ServerNotice::CommandOutput(id, vec![b'x'; len]),
+            ];
+            for notice in notices {
+                history.do_update(ServerMessage::new(0, notice));
+            }
+            if done {
+                let notice = ServerNotice::CommandCompleted(id, ExitStatus::Exited(0), now, None);
+                history.do_update(ServerMessage::new(0, notice));
+            }
+        }
+        history
+    }
+
+    #[test]
+    fn discards_the_output_of_the_oldest_finished_commands() {
+        let history = history_of(&[
+            (1, 100, false),
+            (2, 100, true),
+            (3, 100, true),
+            (4, 100, true),
+        ]);
+        assert_eq!(
+            policy().enforce_history(&history, &HashMap::new()),
+            vec![
+                ServerNotice::OutputTruncated(2, OutputStream::Stdout, 0, 100),
+                ServerNotice::OutputTruncated(3, OutputStream::Stdout, 0, 100),
+            ]
+        );
+        let history = history_of(&[(1, 100, true), (2, 100, true)]);
+        assert_eq!(policy().enforce_history(&history, &HashMap::new()), vec![]);
+    }
+
+    #[test]
+    fn expires_commands_that_finished_long_ago() {
+        let policy = RetentionPolicy {
+            max_age: Some(60),
+            ..policy()
+        };
+        let history = history_of(&[(1, 200, true), (2, 100, true), (3, 100, true)]);
+        let now = SystemTime::now();
+        let mut finished = HashMap::new();
+        finished.insert(1, now - Duration::from_secs(120));
+        finished.insert(2, now);
+        finished.insert(3, now);
+        // Expired output no longer counts towards the budget
+        assert_eq!(
+            policy.enforce_history(&history, &finished),
+            vec![ServerNotice::CommandsExpired(vec![1])]
+        );
+    }
+}
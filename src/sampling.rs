@@ -0,0 +1,156 @@
+//! Live resource usage of running commands, sampled from /proc.
+//!
+//! Each command is measured along with every process descended from it, since a build's work
+//! mostly happens in its children.
+
+use super::libc;
+use super::{CommandId, ResourceSample};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::time::{Instant, SystemTime};
+
+struct ProcStat {
+    pid: u32,
+    ppid: u32,
+    /// User and system time, in clock ticks.
+    ticks: u64,
+    threads: u64,
+    rss_pages: u64,
+}
+
+fn read_stat(pid: u32) -> io::Result<ProcStat> {
+    let mut stat = String::new();
+    File::open(format!("/proc/{}/stat", pid))?.read_to_string(&mut stat)?;
+    // The command name may contain spaces or parens, so the fields start after the last paren
+    let start = stat.rfind(')').map_or(0, |i| i + 1);
+    let fields: Vec<&str> = stat[start..].split_whitespace().collect();
+    // Numbered from the state, which is the third field in proc(5)
+    let field = |n: usize| -> io::Result<u64> {
+        fields
+            .get(n - 3)
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed stat"))
+    };
+    Ok(ProcStat {
+        pid,
+        ppid: field(4)? as u32,
+        ticks: field(14)? + field(15)?,
+        threads: field(20)?,
+        rss_pages: field(24)?,
+    })
+}
+
+fn read_all_stats() -> io::Result<Vec<ProcStat>> {
+    let mut stats = vec![];
+    for entry in fs::read_dir("/proc")? {
+        let pid = match entry?.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // Processes may exit between listing and reading them
+        if let Ok(stat) = read_stat(pid) {
+            stats.push(stat);
+        }
+    }
+    Ok(stats)
+}
+
+/// How often the daemon samples running commands unless configured otherwise.
+pub const DEFAULT_INTERVAL_MS: u64 = 2000;
+
+pub struct Sampler {
+    clock_ticks: u64,
+    page_kb: u64,
+    previous: HashMap<CommandId, (Instant, u64)>,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Sampler::new()
+    }
+}
+
+impl Sampler {
+    pub fn new() -> Self {
+        let (clock_ticks, page_size) = unsafe {
+            (
+                libc::sysconf(libc::_SC_CLK_TCK),
+                libc::sysconf(libc::_SC_PAGESIZE),
+            )
+        };
+        Sampler {
+            clock_ticks: clock_ticks.max(1) as u64,
+            page_kb: (page_size.max(1024) / 1024) as u64,
+            previous: HashMap::new(),
+        }
+    }
+
+    /// Samples the process trees rooted at each command's running processes.
+    pub fn sample(
+        &mut self,
+        running: &[(CommandId, Vec<u32>)],
+    ) -> Vec<(CommandId, ResourceSample)> {
+        self.previous
+            .retain(|id, _| running.iter().any(|&(r, _)| r == *id));
+        let stats = match read_all_stats() {
+            Ok(stats) => stats,
+            Err(e) => {
+                log!(Error, "Failed to sample running commands: {}", e);
+                return vec![];
+            }
+        };
+        let mut children: HashMap<u32, Vec<usize>> = HashMap::new();
+        let mut by_pid = HashMap::new();
+        for (i, stat) in stats.iter().enumerate() {
+            children.entry(stat.ppid).or_default().push(i);
+            by_pid.insert(stat.pid, i);
+        }
+
+        let now = Instant::now();
+        let mut samples = vec![];
+        for &(id, ref pids) in running {
+            let mut tree: Vec<usize> = pids
+                .iter()
+                .filter_map(|pid| by_pid.get(pid))
+                .cloned()
+                .collect();
+            let mut next = 0;
+            while next < tree.len() {
+                let pid = stats[tree[next]].pid;
+                tree.extend(children.get(&pid).into_iter().flat_map(|c| c.iter()));
+                next += 1;
+            }
+            if tree.is_empty() {
+                continue;
+            }
+
+            let ticks: u64 = tree.iter().map(|&i| stats[i].ticks).sum();
+            // Time used by children that have since exited is no longer counted, so the total
+            // can go down
+            let cpu_percent = match self.previous.insert(id, (now, ticks)) {
+                Some((then, before)) => {
+                    let elapsed = now.duration_since(then);
+                    let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+                    let used = ticks.saturating_sub(before) as f64 / self.clock_ticks as f64;
+                    match elapsed > 0.0 {
+                        true => (100.0 * used / elapsed) as f32,
+                        false => 0.0,
+                    }
+                }
+                None => 0.0,
+            };
+            samples.push((
+                id,
+                ResourceSample {
+                    time: SystemTime::now(),
+                    cpu_percent,
+                    rss: tree.iter().map(|&i| stats[i].rss_pages).sum::<u64>() * self.page_kb,
+                    threads: tree.iter().map(|&i| stats[i].threads).sum::<u64>() as u32,
+                },
+            ));
+        }
+        samples
+    }
+}
@@ -0,0 +1,227 @@
+//! The working directory and environment that commands run with.
+//!
+//! Each command runs in its own process, so `cd` and `export` can't have any lasting effect
+//! there.  Instead the daemon runs them itself, as builtins that update the session.  When they
+//! are part of a larger script, they only affect the rest of that script.
+
+use super::shell;
+use super::RunOptions;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub cwd: PathBuf,
+    pub env: BTreeMap<String, String>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+impl Session {
+    /// A session inheriting the daemon's own directory and environment.
+    pub fn new() -> Self {
+        let cwd = env::current_dir()
+            .ok()
+            .or_else(env::home_dir)
+            .unwrap_or_else(|| PathBuf::from("/"));
+        let env = env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+            .collect();
+        Session { cwd, env }
+    }
+
+    /// The directory and environment to run a command with, given the client's overrides.
+    pub fn resolve(&self, options: &RunOptions) -> (PathBuf, BTreeMap<String, String>) {
+        let cwd = match options.cwd {
+            Some(ref cwd) => self.cwd.join(cwd),
+            None => self.cwd.clone(),
+        };
+        let mut env = match options.clear_env {
+            true => BTreeMap::new(),
+            false => self.env.clone(),
+        };
+        env.extend(options.env.clone());
+        (cwd, env)
+    }
+
+    /// Runs the command `words` if it is a builtin, returning its output or error.
+    pub fn run_builtin(&mut self, words: &[String]) -> Option<Result<String, String>> {
+        let (name, args) = words.split_first()?;
+        match name.as_str() {
+            "cd" => Some(self.cd(args)),
+            "export" => Some(self.export(args)),
+            _ => None,
+        }
+    }
+
+    fn cd(&mut self, args: &[String]) -> Result<String, String> {
+        let target = match args.len() {
+            0 => self.env.get("HOME").cloned().ok_or("cd: HOME not set")?,
+            1 if args[0] == "-" => self
+                .env
+                .get("OLDPWD")
+                .cloned()
+                .ok_or("cd: OLDPWD not set")?,
+            1 => args[0].clone(),
+            _ => return Err("cd: too many arguments".to_owned()),
+        };
+        let tilde = target == "~" || target.starts_with("~/");
+        let target = match (tilde, self.env.get("HOME")) {
+            (true, Some(home)) => format!("{}{}", home, &target[1..]),
+            _ => target,
+        };
+        let cwd = self
+            .cwd
+            .join(&target)
+            .canonicalize()
+            .map_err(|e| format!("cd: {}: {}", target, e))?;
+        if !cwd.is_dir() {
+            return Err(format!("cd: {}: Not a directory", target));
+        }
+        let oldpwd = self.cwd.to_string_lossy().into_owned();
+        self.env.insert("OLDPWD".to_owned(), oldpwd);
+        self.env
+            .insert("PWD".to_owned(), cwd.to_string_lossy().into_owned());
+        self.cwd = cwd;
+        Ok(String::new())
+    }
+
+    fn export(&mut self, args: &[String]) -> Result<String, String> {
+        if args.is_empty() {
+            let vars = self
+                .env
+                .iter()
+                .map(|(k, v)| format!("{}={}\n", k, v))
+                .collect();
+            return Ok(vars);
+        }
+        for arg in args {
+            let (name, value) = match arg.find('=') {
+                Some(eq) => (&arg[..eq], Some(&arg[eq + 1..])),
+                None => (&arg[..], None),
+            };
+            if !shell::is_name(name) {
+                return Err(format!("export: `{}': not a valid identifier", arg));
+            }
+            // Exporting a name without a value is a no-op, since everything is exported
+            if let Some(value) = value {
+                self.env.insert(name.to_owned(), value.to_owned());
+            }
+        }
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use std::process;
+
+    /// A directory holding a subdirectory and a file, with nothing left by a previous run.
+    fn session_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("weaver-session-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("file"), b"").unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    fn session(cwd: &Path) -> Session {
+        let mut env = BTreeMap::new();
+        env.insert("HOME".to_owned(), cwd.to_string_lossy().into_owned());
+        Session {
+            cwd: cwd.to_owned(),
+            env,
+        }
+    }
+
+    fn run(session: &mut Session, line: &str) -> Result<String, String> {
+        let words: Vec<String> = line.split_whitespace().map(|w| w.to_owned()).collect();
+        session.run_builtin(&words).unwrap()
+    }
+
+    #[test]
+    fn cd_dash_returns_to_the_previous_directory() {
+        let dir = session_dir("dash");
+        let mut session = session(&dir);
+        assert_eq!(
+            run(&mut session, "cd -"),
+            Err("cd: OLDPWD not set".to_owned())
+        );
+        run(&mut session, "cd sub").unwrap();
+        assert_eq!(session.cwd, dir.join("sub"));
+        assert_eq!(session.env["OLDPWD"], dir.to_string_lossy());
+        assert_eq!(session.env["PWD"], dir.join("sub").to_string_lossy());
+        run(&mut session, "cd -").unwrap();
+        assert_eq!(session.cwd, dir);
+        assert_eq!(session.env["OLDPWD"], dir.join("sub").to_string_lossy());
+    }
+
+    #[test]
+    fn cd_expands_a_tilde_to_home() {
+        let dir = session_dir("tilde");
+        let mut session = session(&dir.join("sub"));
+        session
+            .env
+            .insert("HOME".to_owned(), dir.to_string_lossy().into_owned());
+        run(&mut session, "cd ~").unwrap();
+        assert_eq!(session.cwd, dir);
+        run(&mut session, "cd ~/sub").unwrap();
+        assert_eq!(session.cwd, dir.join("sub"));
+        run(&mut session, "cd").unwrap();
+        assert_eq!(session.cwd, dir);
+    }
+
+    #[test]
+    fn cd_only_enters_directories() {
+        let dir = session_dir("file");
+        let mut session = session(&dir);
+        assert_eq!(
+            run(&mut session, "cd file"),
+            Err("cd: file: Not a directory".to_owned())
+        );
+        assert!(run(&mut session, "cd missing").is_err());
+        assert_eq!(
+            run(&mut session, "cd sub file"),
+            Err("cd: too many arguments".to_owned())
+        );
+        assert_eq!(session.cwd, dir);
+        assert!(!session.env.contains_key("OLDPWD"));
+    }
+
+    #[test]
+    fn export_sets_valid_names() {
+        let dir = session_dir("export");
+        let mut session = session(&dir);
+        run(&mut session, "export A=1 _b=x=y C").unwrap();
+        assert_eq!(session.env["A"], "1");
+        assert_eq!(session.env["_b"], "x=y");
+        assert!(!session.env.contains_key("C"));
+        for arg in &["1X=a", "A-B=c", "=d", "-e"] {
+            assert_eq!(
+                run(&mut session, &format!("export {}", arg)),
+                Err(format!("export: `{}': not a valid identifier", arg))
+            );
+        }
+        assert_eq!(session.env.len(), 3);
+    }
+
+    #[test]
+    fn export_alone_lists_the_environment() {
+        let dir = session_dir("list");
+        let mut session = session(&dir);
+        run(&mut session, "export A=1").unwrap();
+        assert_eq!(
+            run(&mut session, "export"),
+            Ok(format!("A=1\nHOME={}\n", dir.display()))
+        );
+    }
+}
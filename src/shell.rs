@@ -0,0 +1,1104 @@
+//! The shell language that commands are written in.
+//!
+//! This is a small subset of sh: simple commands with variable assignments and redirections,
+//! joined into pipelines, which are in turn joined by `&&`, `||` and `;`.  Words may be quoted,
+//! and may contain variables and globs.  Unlike sh, the value of a variable is never split into
+//! several words or globbed, and `$@` only stands for several words when it is a word by itself.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WordPart {
+    /// Unquoted text, in which glob characters are special.
+    Literal(String),
+    /// Quoted or escaped text, taken exactly as written.
+    Quoted(String),
+    Variable(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Word(pub Vec<WordPart>);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectOp {
+    /// `<`
+    Read,
+    /// `>`
+    Write,
+    /// `>>`
+    Append,
+    /// `>&` or `<&`, making the fd a copy of another.
+    Duplicate,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redirect {
+    pub fd: u32,
+    pub op: RedirectOp,
+    pub target: Word,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SimpleCommand {
+    pub assignments: Vec<(String, Word)>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+impl SimpleCommand {
+    /// The command's name, if it is written as plain unquoted text.
+    pub fn literal_name(&self) -> Option<String> {
+        let parts = &self.words.first()?.0;
+        match parts.first() {
+            Some(WordPart::Literal(name)) if parts.len() == 1 => Some(name.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pipeline {
+    pub stages: Vec<SimpleCommand>,
+}
+
+/// How a pipeline depends on the exit status of the one before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connector {
+    Always,
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+}
+
+impl Connector {
+    pub fn should_run(&self, previous_success: bool) -> bool {
+        match *self {
+            Connector::Always => true,
+            Connector::And => previous_success,
+            Connector::Or => !previous_success,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    pub pipelines: Vec<(Connector, Pipeline)>,
+}
+
+impl Script {
+    /// A script that runs `words` as a single command, exactly as given.
+    pub fn command(words: Vec<String>) -> Script {
+        let words = words
+            .into_iter()
+            .map(|word| Word(vec![WordPart::Quoted(word)]))
+            .collect();
+        let cmd = SimpleCommand {
+            words,
+            ..SimpleCommand::default()
+        };
+        Script {
+            pipelines: vec![(Connector::Always, Pipeline { stages: vec![cmd] })],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// The character the error was found at.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "syntax error at character {}: {}",
+            self.offset + 1,
+            self.message
+        )
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(Word),
+    Pipe,
+    And,
+    Or,
+    Separator,
+    Redirect(u32, RedirectOp),
+    /// `&>`, sending both stdout and stderr to a file.
+    RedirectBoth,
+}
+
+pub fn parse(input: &str) -> Result<Script, ParseError> {
+    let tokens = Lexer {
+        chars: input.chars().collect(),
+        pos: 0,
+    }
+    .lex()?;
+    Parser {
+        tokens,
+        pos: 0,
+        end: input.chars().count(),
+    }
+    .parse()
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Lexer {
+    fn error<T>(&self, offset: usize, message: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            offset,
+            message: message.to_owned(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next_is(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn lex(mut self) -> Result<Vec<(usize, Token)>, ParseError> {
+        let mut tokens = vec![];
+        loop {
+            while self.peek() == Some(' ') || self.peek() == Some('\t') {
+                self.pos += 1;
+            }
+            let start = self.pos;
+            let c = match self.peek() {
+                Some(c) => c,
+                None => break,
+            };
+            let token = match c {
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                '\n' | ';' => {
+                    self.pos += 1;
+                    Token::Separator
+                }
+                '|' => {
+                    self.pos += 1;
+                    match self.next_is('|') {
+                        true => Token::Or,
+                        false => Token::Pipe,
+                    }
+                }
+                '&' => {
+                    self.pos += 1;
+                    if self.next_is('&') {
+                        Token::And
+                    } else if self.next_is('>') {
+                        Token::RedirectBoth
+                    } else {
+                        return self
+                            .error(start, "running commands in the background is not supported");
+                    }
+                }
+                '<' => self.redirect(0),
+                '>' => self.redirect(1),
+                '(' | ')' => return self.error(start, "subshells are not supported"),
+                _ => {
+                    let word = match self.word()? {
+                        Some(word) => word,
+                        None => continue,
+                    };
+                    // A number immediately before a redirection is the fd it redirects
+                    let fd = match (word.0.len(), word.0.first()) {
+                        (1, Some(WordPart::Literal(text))) => text.parse().ok(),
+                        _ => None,
+                    };
+                    match (fd, self.peek()) {
+                        (Some(fd), Some('<')) | (Some(fd), Some('>')) => self.redirect(fd),
+                        _ => Token::Word(word),
+                    }
+                }
+            };
+            tokens.push((start, token));
+        }
+        Ok(tokens)
+    }
+
+    fn redirect(&mut self, fd: u32) -> Token {
+        let c = self.peek();
+        self.pos += 1;
+        let op = if self.next_is('&') {
+            RedirectOp::Duplicate
+        } else if c == Some('>') && self.next_is('>') {
+            RedirectOp::Append
+        } else if c == Some('>') {
+            // `>|` only differs from `>` when noclobber is set
+            self.next_is('|');
+            RedirectOp::Write
+        } else {
+            RedirectOp::Read
+        };
+        Token::Redirect(fd, op)
+    }
+
+    /// Lexes a word, returning None if it turned out to be nothing but line continuations.
+    fn word(&mut self) -> Result<Option<Word>, ParseError> {
+        let mut parts = vec![];
+        while let Some(c) = self.peek() {
+            let start = self.pos;
+            match c {
+                ' ' | '\t' | '\n' | '|' | '&' | ';' | '<' | '>' | '(' | ')' => break,
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\n') => {}
+                        Some(c) => push_quoted(&mut parts, c),
+                        None => push_quoted(&mut parts, '\\'),
+                    }
+                    self.pos += 1;
+                }
+                '\'' => {
+                    self.pos += 1;
+                    quote_start(&mut parts);
+                    loop {
+                        match self.peek() {
+                            Some('\'') => break,
+                            Some(c) => push_quoted(&mut parts, c),
+                            None => return self.error(start, "unterminated single quote"),
+                        }
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                }
+                '"' => {
+                    self.pos += 1;
+                    quote_start(&mut parts);
+                    self.double_quoted(start, &mut parts)?;
+                }
+                '$' => {
+                    let part = self.variable(false)?;
+                    push_part(&mut parts, part);
+                }
+                '`' => return self.error(start, "command substitution is not supported"),
+                c => {
+                    self.pos += 1;
+                    push_part(&mut parts, WordPart::Literal(c.to_string()));
+                }
+            }
+        }
+        match parts.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(Word(parts))),
+        }
+    }
+
+    fn double_quoted(&mut self, start: usize, parts: &mut Vec<WordPart>) -> Result<(), ParseError> {
+        loop {
+            match self.peek() {
+                Some('"') => break,
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('\n') => {}
+                        Some(c) if "$`\"\\".contains(c) => push_quoted(parts, c),
+                        _ => {
+                            push_quoted(parts, '\\');
+                            continue;
+                        }
+                    }
+                }
+                Some('$') => {
+                    let part = self.variable(true)?;
+                    push_part(parts, part);
+                    continue;
+                }
+                Some('`') => return self.error(self.pos, "command substitution is not supported"),
+                Some(c) => push_quoted(parts, c),
+                None => return self.error(start, "unterminated double quote"),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Lexes a variable reference, or the `$` alone if it doesn't start one.
+    fn variable(&mut self, quoted: bool) -> Result<WordPart, ParseError> {
+        let start = self.pos;
+        self.pos += 1;
+        let name = match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let name_start = self.pos;
+                while self.peek().is_some_and(|c| c != '}') {
+                    self.pos += 1;
+                }
+                if self.peek().is_none() {
+                    return self.error(start, "unterminated variable reference");
+                }
+                let name: String = self.chars[name_start..self.pos].iter().collect();
+                self.pos += 1;
+                if !is_special(&name) && !is_name(&name) {
+                    return self.error(start, "bad variable name");
+                }
+                name
+            }
+            Some(c) if c.is_ascii_digit() || "?#@*".contains(c) => {
+                self.pos += 1;
+                c.to_string()
+            }
+            Some('(') => return self.error(start, "command substitution is not supported"),
+            Some(c) if c == '_' || c.is_alphabetic() => {
+                let name_start = self.pos;
+                while self.peek().is_some_and(|c| c == '_' || c.is_alphanumeric()) {
+                    self.pos += 1;
+                }
+                self.chars[name_start..self.pos].iter().collect()
+            }
+            _ => {
+                return Ok(match quoted {
+                    true => WordPart::Quoted("$".to_owned()),
+                    false => WordPart::Literal("$".to_owned()),
+                })
+            }
+        };
+        Ok(WordPart::Variable(name))
+    }
+}
+
+/// Appends `part` to `parts`, merging it into the last part if they're the same kind of text.
+fn push_part(parts: &mut Vec<WordPart>, part: WordPart) {
+    match (parts.last_mut(), &part) {
+        (Some(&mut WordPart::Literal(ref mut text)), &WordPart::Literal(ref more))
+        | (Some(&mut WordPart::Quoted(ref mut text)), &WordPart::Quoted(ref more)) => {
+            text.push_str(more);
+            return;
+        }
+        _ => {}
+    }
+    parts.push(part);
+}
+
+fn push_quoted(parts: &mut Vec<WordPart>, c: char) {
+    push_part(parts, WordPart::Quoted(c.to_string()));
+}
+
+/// Makes sure a word exists even if the quotes turn out to be empty, as in `''`.
+fn quote_start(parts: &mut Vec<WordPart>) {
+    push_part(parts, WordPart::Quoted(String::new()));
+}
+
+/// Whether `name` is a special parameter, set by the shell rather than the environment.
+fn is_special(name: &str) -> bool {
+    ["?", "#", "@", "*"].contains(&name)
+        || (!name.is_empty() && name.chars().all(|c| c.is_ascii_digit()))
+}
+
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_alphabetic() => chars.all(|c| c == '_' || c.is_alphanumeric()),
+        _ => false,
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end, |&(offset, _)| offset)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            offset: self.offset(),
+            message: message.to_owned(),
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn parse(mut self) -> Result<Script, ParseError> {
+        let mut script = Script::default();
+        let mut connector = Connector::Always;
+        loop {
+            let pipeline = self.pipeline()?;
+            let found = pipeline.is_some();
+            match pipeline {
+                Some(pipeline) => script.pipelines.push((connector, pipeline)),
+                None if connector != Connector::Always => {
+                    return self.error("expected a command after `&&` or `||`")
+                }
+                None => {}
+            }
+            connector = match self.peek() {
+                None => break,
+                Some(&Token::Separator) => Connector::Always,
+                Some(&Token::And) => Connector::And,
+                Some(&Token::Or) => Connector::Or,
+                Some(_) => return self.error("unexpected token"),
+            };
+            if connector != Connector::Always && !found {
+                return self.error("expected a command before `&&` or `||`");
+            }
+            self.pos += 1;
+        }
+        Ok(script)
+    }
+
+    fn pipeline(&mut self) -> Result<Option<Pipeline>, ParseError> {
+        let mut pipeline = Pipeline::default();
+        loop {
+            match self.simple_command()? {
+                Some(stage) => pipeline.stages.push(stage),
+                None if pipeline.stages.is_empty() && self.peek() != Some(&Token::Pipe) => {
+                    return Ok(None)
+                }
+                None => return self.error("expected a command in the pipeline"),
+            }
+            if self.peek() != Some(&Token::Pipe) {
+                return Ok(Some(pipeline));
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn simple_command(&mut self) -> Result<Option<SimpleCommand>, ParseError> {
+        let mut cmd = SimpleCommand::default();
+        loop {
+            let (fd, op) = match self.peek().cloned() {
+                Some(Token::Word(word)) => {
+                    match assignment(&word) {
+                        Some(assignment) if cmd.words.is_empty() => {
+                            cmd.assignments.push(assignment)
+                        }
+                        _ => cmd.words.push(word),
+                    }
+                    self.pos += 1;
+                    continue;
+                }
+                Some(Token::Redirect(fd, op)) => (Some(fd), op),
+                Some(Token::RedirectBoth) => (None, RedirectOp::Write),
+                _ => break,
+            };
+            self.pos += 1;
+            let target = match self.peek() {
+                Some(Token::Word(word)) => word.clone(),
+                _ => return self.error("expected a file name after redirection"),
+            };
+            self.pos += 1;
+            match fd {
+                Some(fd) => cmd.redirects.push(Redirect { fd, op, target }),
+                None => {
+                    cmd.redirects.push(Redirect { fd: 1, op, target });
+                    cmd.redirects.push(Redirect {
+                        fd: 2,
+                        op: RedirectOp::Duplicate,
+                        target: Word(vec![WordPart::Literal("1".to_owned())]),
+                    });
+                }
+            }
+        }
+        match cmd == SimpleCommand::default() {
+            true => Ok(None),
+            false => Ok(Some(cmd)),
+        }
+    }
+}
+
+/// Splits `word` into a variable name and value, if it is an assignment such as `FOO=bar`.
+fn assignment(word: &Word) -> Option<(String, Word)> {
+    let (first, rest) = word.0.split_first()?;
+    let text = match *first {
+        WordPart::Literal(ref text) => text,
+        _ => return None,
+    };
+    let eq = text.find('=')?;
+    if !is_name(&text[..eq]) {
+        return None;
+    }
+    let mut value = vec![];
+    if eq + 1 < text.len() {
+        value.push(WordPart::Literal(text[eq + 1..].to_owned()));
+    }
+    value.extend(rest.iter().cloned());
+    Some((text[..eq].to_owned(), Word(value)))
+}
+
+/// What words are expanded against.
+pub struct Expansion<'a> {
+    pub env: &'a BTreeMap<String, String>,
+    pub cwd: &'a Path,
+    /// The exit status of the last pipeline, for `$?`.
+    pub status: i32,
+    /// The positional parameters, starting with `$0`.
+    pub args: &'a [String],
+}
+
+impl<'a> Expansion<'a> {
+    fn variable(&self, name: &str) -> String {
+        let params = self.args.get(1..).unwrap_or(&[]);
+        match name {
+            "?" => self.status.to_string(),
+            "#" => params.len().to_string(),
+            "@" | "*" => params.join(" "),
+            name if is_special(name) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.args.get(n))
+                .cloned()
+                .unwrap_or_default(),
+            name => self.env.get(name).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Expands `word` as a single string, without globbing.
+    pub fn expand_text(&self, word: &Word) -> String {
+        let mut text = String::new();
+        for (i, part) in word.0.iter().enumerate() {
+            match *part {
+                WordPart::Literal(ref literal) if i == 0 => match self.tilde(literal) {
+                    Some((home, rest)) => {
+                        text.push_str(home);
+                        text.push_str(rest);
+                    }
+                    None => text.push_str(literal),
+                },
+                WordPart::Literal(ref literal) | WordPart::Quoted(ref literal) => {
+                    text.push_str(literal)
+                }
+                WordPart::Variable(ref name) => text.push_str(&self.variable(name)),
+            }
+        }
+        text
+    }
+
+    /// Expands `word` into the arguments it stands for, which are the files it matches if it
+    /// is a glob that matches any.
+    pub fn expand(&self, word: &Word) -> Vec<String> {
+        // Either `$@` or `"$@"`, whose quotes leave an empty part
+        let mut parts = word.0.iter().filter(|&part| match *part {
+            WordPart::Quoted(ref text) => !text.is_empty(),
+            _ => true,
+        });
+        if let (Some(WordPart::Variable(name)), None) = (parts.next(), parts.next()) {
+            if name == "@" {
+                return self.args.get(1..).unwrap_or(&[]).to_vec();
+            }
+        }
+        let mut pattern = String::new();
+        let mut glob = false;
+        for (i, part) in word.0.iter().enumerate() {
+            match *part {
+                WordPart::Literal(ref literal) => {
+                    glob |= literal.contains(['*', '?', '[']);
+                    match self.tilde(literal) {
+                        Some((home, rest)) if i == 0 => {
+                            pattern.push_str(&escape_glob(home));
+                            pattern.push_str(rest);
+                        }
+                        _ => pattern.push_str(literal),
+                    }
+                }
+                WordPart::Quoted(ref text) => pattern.push_str(&escape_glob(text)),
+                WordPart::Variable(ref name) => {
+                    pattern.push_str(&escape_glob(&self.variable(name)))
+                }
+            }
+        }
+        if glob {
+            let matches = glob_paths(&pattern, self.cwd);
+            if !matches.is_empty() {
+                return matches;
+            }
+        }
+        vec![self.expand_text(word)]
+    }
+
+    /// Splits a leading `~` off `literal`, returning the home directory it stands for and the
+    /// rest of the text.
+    fn tilde<'b>(&'b self, literal: &'b str) -> Option<(&'b str, &'b str)> {
+        match self.env.get("HOME") {
+            Some(home) if literal == "~" || literal.starts_with("~/") => {
+                Some((home, &literal[1..]))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn escape_glob(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "*?[]\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unescape_glob(pattern: &str) -> String {
+    let mut text = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.extend(chars.next()),
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+fn is_glob(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// The paths matching `pattern`, relative to `cwd` unless it is absolute, in sorted order.
+fn glob_paths(pattern: &str, cwd: &Path) -> Vec<String> {
+    let mut paths = vec![match pattern.starts_with('/') {
+        true => "/".to_owned(),
+        false => String::new(),
+    }];
+    let components: Vec<&str> = pattern.split('/').collect();
+    for (i, component) in components.iter().enumerate() {
+        if component.is_empty() {
+            // Only a trailing slash matters, restricting matches to directories
+            if i + 1 == components.len() && i > 0 {
+                paths.retain(|path| cwd.join(path).is_dir());
+                for path in &mut paths {
+                    path.push('/');
+                }
+            }
+            continue;
+        }
+        let mut matched = vec![];
+        for path in paths {
+            if !is_glob(component) {
+                matched.push(join_path(&path, &unescape_glob(component)));
+                continue;
+            }
+            let dir = match path.is_empty() {
+                true => cwd.to_path_buf(),
+                false => cwd.join(&path),
+            };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            let pattern: Vec<char> = component.chars().collect();
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                // Hidden files are only matched explicitly
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if glob_match(&pattern, &name.chars().collect::<Vec<_>>()) {
+                    matched.push(join_path(&path, &name));
+                }
+            }
+        }
+        paths = matched;
+    }
+    paths.retain(|path| cwd.join(path).symlink_metadata().is_ok());
+    paths.sort();
+    paths
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_owned(),
+        dir if dir.ends_with('/') => format!("{}{}", dir, name),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+/// Matches all of `name` against `pattern`.  On a mismatch only the latest `*` is retried,
+/// absorbing one more character, as letting an earlier one absorb more could never help.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Just after the latest star, and where in the name the star's match ends
+    let mut star = None;
+    while n < name.len() {
+        let c = name[n];
+        // How much of the pattern matches `c`, if it does
+        let matched = match pattern.get(p) {
+            Some(&'*') => {
+                p += 1;
+                star = Some((p, n));
+                continue;
+            }
+            Some(&'?') => Some(1),
+            Some(&'[') => match match_class(&pattern[p..], Some(c)) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                // Without a closing bracket, it's just a bracket
+                None if c == '[' => Some(1),
+                None => None,
+            },
+            Some(&'\\') if p + 1 < pattern.len() => match pattern[p + 1] == c {
+                true => Some(2),
+                false => None,
+            },
+            Some(&literal) if literal == c => Some(1),
+            _ => None,
+        };
+        match (matched, star) {
+            (Some(len), _) => {
+                p += len;
+                n += 1;
+            }
+            (None, Some((after, end))) => {
+                p = after;
+                n = end + 1;
+                star = Some((after, n));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `c` against the bracket expression at the start of `pattern`, returning whether it
+/// matched and the length of the expression, or None if it is unterminated.
+fn match_class(pattern: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = match pattern.get(i) {
+        Some(&'!') | Some(&'^') => {
+            i += 1;
+            true
+        }
+        _ => false,
+    };
+    let start = i;
+    let mut matched = false;
+    loop {
+        let mut low = *pattern.get(i)?;
+        if low == ']' && i > start {
+            break;
+        }
+        if low == '\\' {
+            i += 1;
+            low = *pattern.get(i)?;
+        }
+        i += 1;
+        let high = match (pattern.get(i), pattern.get(i + 1)) {
+            (Some(&'-'), Some(&high)) if high != ']' => {
+                i += 2;
+                high
+            }
+            _ => low,
+        };
+        matched |= c.is_some_and(|c| low <= c && c <= high);
+    }
+    Some((matched != negated, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn expansion<'a>(env: &'a BTreeMap<String, String>, args: &'a [String]) -> Expansion<'a> {
+        Expansion {
+            env,
+            cwd: Path::new("/"),
+            status: 3,
+            args,
+        }
+    }
+
+    /// The words of the only command in `input`, expanded against `args` and `$X` set to "1".
+    fn expand(input: &str, args: &[&str]) -> Vec<String> {
+        let mut env = BTreeMap::new();
+        env.insert("X".to_owned(), "1".to_owned());
+        let args: Vec<String> = args.iter().map(|&arg| arg.to_owned()).collect();
+        let expansion = expansion(&env, &args);
+        let script = parse(input).unwrap();
+        script.pipelines[0].1.stages[0]
+            .words
+            .iter()
+            .flat_map(|word| expansion.expand(word))
+            .collect()
+    }
+
+    fn error(input: &str) -> (usize, String) {
+        let e = parse(input).unwrap_err();
+        (e.offset, e.message)
+    }
+
+    fn literal(text: &str) -> Word {
+        Word(vec![WordPart::Literal(text.to_owned())])
+    }
+
+    #[test]
+    fn pipelines_and_connectors() {
+        let script = parse("a | b c && d || e; f\ng").unwrap();
+        let shape: Vec<(Connector, usize)> = script
+            .pipelines
+            .iter()
+            .map(|&(connector, ref pipeline)| (connector, pipeline.stages.len()))
+            .collect();
+        assert_eq!(
+            shape,
+            vec![
+                (Connector::Always, 2),
+                (Connector::And, 1),
+                (Connector::Or, 1),
+                (Connector::Always, 1),
+                (Connector::Always, 1),
+            ]
+        );
+        assert_eq!(
+            script.pipelines[0].1.stages[1].words,
+            vec![literal("b"), literal("c")]
+        );
+        assert_eq!(parse("a;; b # c").unwrap().pipelines.len(), 2);
+        assert!(Connector::And.should_run(true) && !Connector::And.should_run(false));
+        assert!(Connector::Or.should_run(false) && !Connector::Or.should_run(true));
+    }
+
+    #[test]
+    fn redirects() {
+        let redirects = |input: &str| -> Vec<(u32, RedirectOp, Word)> {
+            let script = parse(input).unwrap();
+            let cmd = &script.pipelines[0].1.stages[0];
+            cmd.redirects
+                .iter()
+                .map(|r| (r.fd, r.op, r.target.clone()))
+                .collect()
+        };
+        assert_eq!(
+            redirects("cmd < in > out 2>> log"),
+            vec![
+                (0, RedirectOp::Read, literal("in")),
+                (1, RedirectOp::Write, literal("out")),
+                (2, RedirectOp::Append, literal("log")),
+            ]
+        );
+        assert_eq!(
+            redirects("cmd 2>&1"),
+            vec![(2, RedirectOp::Duplicate, literal("1"))]
+        );
+        assert_eq!(
+            redirects("cmd &> all"),
+            vec![
+                (1, RedirectOp::Write, literal("all")),
+                (2, RedirectOp::Duplicate, literal("1")),
+            ]
+        );
+        // Only a number immediately before the operator is its fd
+        assert_eq!(expand("echo 2 >x", &[]), vec!["echo", "2"]);
+        assert_eq!(
+            redirects("echo 2 >x"),
+            vec![(1, RedirectOp::Write, literal("x"))]
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            expand(r#"echo 'a b' "c $X" \d\ e '' "" '$X' "\$X\"""#, &[]),
+            vec!["echo", "a b", "c 1", "d e", "", "", "$X", "$X\""]
+        );
+        assert_eq!(
+            expand(r#"echo "a\b" 'a\b' a"b"'c'"#, &[]),
+            vec!["echo", r"a\b", r"a\b", "abc"]
+        );
+        assert_eq!(expand("echo a\\\nb", &[]), vec!["echo", "ab"]);
+    }
+
+    #[test]
+    fn all_arguments() {
+        let args = &["sh", "x y", "z"];
+        assert_eq!(expand(r#"echo "$@""#, args), vec!["echo", "x y", "z"]);
+        assert_eq!(expand("echo $@", args), vec!["echo", "x y", "z"]);
+        assert_eq!(
+            expand(r#"echo "a$@" "$*""#, args),
+            vec!["echo", "ax y z", "x y z"]
+        );
+        assert_eq!(expand(r#"echo "$@""#, &["sh"]), vec!["echo"]);
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            expand("echo ${X}y $Xy $? $# $1 $9 $ a$", &["sh", "x y"]),
+            vec!["echo", "1y", "", "3", "1", "x y", "", "$", "a$"]
+        );
+        let script = parse("A=1 B=$X C= cmd D=2").unwrap();
+        let cmd = &script.pipelines[0].1.stages[0];
+        let names: Vec<&str> = cmd.assignments.iter().map(|a| &a.0[..]).collect();
+        assert_eq!(names, vec!["A", "B", "C"]);
+        assert_eq!(
+            cmd.assignments[1].1,
+            Word(vec![WordPart::Variable("X".to_owned())])
+        );
+        assert_eq!(cmd.assignments[2].1, Word(vec![]));
+        assert_eq!(cmd.words, vec![literal("cmd"), literal("D=2")]);
+    }
+
+    #[test]
+    fn variable_errors() {
+        assert_eq!(
+            error("echo ${X"),
+            (5, "unterminated variable reference".to_owned())
+        );
+        assert_eq!(error("echo a${1x}"), (6, "bad variable name".to_owned()));
+        assert_eq!(error("echo ${}"), (5, "bad variable name".to_owned()));
+        assert_eq!(
+            error("echo \"$(ls)\""),
+            (6, "command substitution is not supported".to_owned())
+        );
+    }
+
+    #[test]
+    fn error_offsets() {
+        assert_eq!(
+            error("echo 'abc"),
+            (5, "unterminated single quote".to_owned())
+        );
+        assert_eq!(
+            error("echo \"abc"),
+            (5, "unterminated double quote".to_owned())
+        );
+        assert_eq!(
+            error("a && && b"),
+            (5, "expected a command after `&&` or `||`".to_owned())
+        );
+        assert_eq!(
+            error("|| b"),
+            (0, "expected a command before `&&` or `||`".to_owned())
+        );
+        assert_eq!(
+            error("a |"),
+            (3, "expected a command in the pipeline".to_owned())
+        );
+        assert_eq!(
+            error("ls >"),
+            (4, "expected a file name after redirection".to_owned())
+        );
+        assert_eq!(
+            error("sleep 1 &"),
+            (
+                8,
+                "running commands in the background is not supported".to_owned()
+            )
+        );
+        assert_eq!(error("(ls)"), (0, "subshells are not supported".to_owned()));
+        assert_eq!(
+            parse("a |").unwrap_err().to_string(),
+            "syntax error at character 4: expected a command in the pipeline"
+        );
+    }
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        glob_match(&pattern, &name)
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(matches("*.rs", "lib.rs"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.rs", "lib.rs~"));
+        assert!(matches("a*b*c", "aXXbYbc"));
+        assert!(!matches("a*b*c", "aXXbYbd"));
+        assert!(matches("?x", "\u{e9}x"));
+        assert!(!matches("?x", "x"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("a[b", "a[b"));
+        assert!(matches("*[", "x["));
+    }
+
+    #[test]
+    fn glob_matching_takes_linear_time_per_star() {
+        let name = "a".repeat(100);
+        assert!(!matches(&format!("{}b", "a*".repeat(20)), &name));
+        assert!(matches(&"a*".repeat(20), &name));
+    }
+
+    /// A directory holding a few files to glob, with nothing left by a previous run.
+    fn glob_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("weaver-glob-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in &["a.rs", "b.rs", ".hidden.rs", "c.txt", "sub/d.rs", "[x].rs"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn globs_expand_to_matching_paths() {
+        let dir = glob_dir();
+        let env = BTreeMap::new();
+        let mut expansion = expansion(&env, &[]);
+        expansion.cwd = &dir;
+        let glob = |input: &str| -> Vec<String> {
+            let script = parse(input).unwrap();
+            expansion.expand(&script.pipelines[0].1.stages[0].words[0])
+        };
+        assert_eq!(glob("*.rs"), vec!["[x].rs", "a.rs", "b.rs"]);
+        assert_eq!(glob("[ab].rs"), vec!["a.rs", "b.rs"]);
+        assert_eq!(glob("*/*.rs"), vec!["sub/d.rs"]);
+        assert_eq!(glob("*/"), vec!["sub/"]);
+        assert_eq!(glob(".*.rs"), vec![".hidden.rs"]);
+        assert_eq!(glob("'*'.rs"), vec!["*.rs"]);
+        assert_eq!(glob("'[x]'.rs"), vec!["[x].rs"]);
+        assert_eq!(glob("*.md"), vec!["*.md"]);
+        let absolute = format!("{}/s*/*", dir.display());
+        assert_eq!(glob(&absolute), vec![format!("{}/sub/d.rs", dir.display())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
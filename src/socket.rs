@@ -0,0 +1,113 @@
+//! Where the daemon listens, and who it lets connect.
+//!
+//! The socket is only accessible to its owner, and the daemon also checks the uid of every
+//! process that connects.  A lock file next to the socket, holding the daemon's pid, keeps a
+//! second daemon from taking the socket over from one that is still running.
+
+use super::libc;
+use super::tokio_uds::{UnixListener, UnixStream};
+
+use std::env;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Overrides the socket path for both binaries, and any clients run from the daemon's commands.
+pub const SOCKET_ENV: &str = "WEAVER_SOCKET";
+
+/// `$XDG_RUNTIME_DIR/weaver.socket`, falling back on `~/.weaver.socket`.
+pub fn default_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(ref dir) if Path::new(dir).is_absolute() => Path::new(dir).join("weaver.socket"),
+        _ => match env::home_dir() {
+            Some(home) => home.join(".weaver.socket"),
+            None => env::temp_dir().join(format!("weaver-{}.socket", unsafe { libc::geteuid() })),
+        },
+    }
+}
+
+/// Fails unless the process at the other end of `stream` is running as the same user as us.
+pub fn check_peer(stream: &UnixStream) -> io::Result<()> {
+    let uid = unsafe { libc::geteuid() };
+    let cred = stream.peer_cred()?;
+    if cred.uid != uid {
+        let reason = format!("uid {} is not allowed to connect", cred.uid);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+    }
+    Ok(())
+}
+
+/// The right to listen on a socket path, held until dropped.
+pub struct SocketLock {
+    path: PathBuf,
+    // Closing the file releases the lock
+    file: File,
+}
+
+impl SocketLock {
+    /// Locks the socket at `path`, failing if another daemon holds it.  Any socket left there by
+    /// a daemon that has since exited is removed.
+    pub fn acquire(path: &Path) -> io::Result<SocketLock> {
+        if let Some(dir) = path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Another daemon's pid stays until we hold the lock
+            .truncate(false)
+            .mode(0o600)
+            .open(lock_path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err);
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            let reason = format!(
+                "another weaverd is listening on {} (pid {})",
+                path.display(),
+                pid.trim()
+            );
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, reason));
+        }
+        // Holding the lock means that nothing is listening on a socket already there
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let mut lock = SocketLock {
+            path: path.to_path_buf(),
+            file,
+        };
+        lock.record_pid()?;
+        Ok(lock)
+    }
+
+    /// Writes our pid to the lock file, as after detaching into a new process.
+    pub fn record_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", process::id())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Listens on the locked path, allowing only its owner to connect.
+    pub fn bind(&self) -> io::Result<UnixListener> {
+        let listener = UnixListener::bind(&self.path)?;
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+}
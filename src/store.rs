@@ -0,0 +1,370 @@
+//! On-disk persistence of command history.
+//!
+//! The store is a header naming its format, followed by an append-only log of msgpack-encoded
+//! `ServerMessage`s.  Loading it replays every message into a fresh `CommandHistory`, which is
+//! exactly how clients build theirs.  Compacting it replaces the log with a single `CommandsBulk`
+//! snapshot of the current history, numbered like the last message it replaces.  The snapshot
+//! can't show ids given to commands that have since expired, so the header also records the id
+//! the next command will be given, and ids are never reused.
+
+use super::rmp_serde::{decode, encode};
+use super::{CommandHistory, CommandId, ExitStatus, ServerMessage, ServerNotice};
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Starts every store, followed by the version of its format.
+const MAGIC: &[u8] = b"weaver-history\n";
+
+/// Changes whenever a store written by one version of weaverd couldn't be read by another.
+const STORE_VERSION: u8 = 2;
+
+/// The magic, the version, and the next command id as a big-endian `u32`.
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+
+pub struct HistoryStore {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    len: u64,
+    seq: u64,
+}
+
+fn header(next_index: CommandId) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(STORE_VERSION);
+    header.extend_from_slice(&next_index.to_be_bytes());
+    header
+}
+
+/// The next command id recorded in the header of `data`, unless it isn't in this version's format.
+fn read_header(data: &[u8]) -> Option<CommandId> {
+    if data.len() < HEADER_LEN || !data.starts_with(MAGIC) || data[MAGIC.len()] != STORE_VERSION {
+        return None;
+    }
+    let mut next_index = [0; 4];
+    next_index.copy_from_slice(&data[MAGIC.len() + 1..HEADER_LEN]);
+    Some(CommandId::from_be_bytes(next_index))
+}
+
+/// `path` with `suffix` added to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Opens a file only its owner can read, as history includes commands' output and environment.
+fn open_private(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    options.mode(0o600).open(path)
+}
+
+/// Whether decoding failed for want of more data, as when a record was only partially written.
+fn is_incomplete(e: &decode::Error) -> bool {
+    match *e {
+        decode::Error::InvalidMarkerRead(ref e) | decode::Error::InvalidDataRead(ref e) => {
+            e.kind() == io::ErrorKind::UnexpectedEof
+        }
+        _ => false,
+    }
+}
+
+impl HistoryStore {
+    /// Opens the store at `path`, creating it if needed, and returns it along with the history
+    /// replayed from it.
+    ///
+    /// A record left partially written by a crash is discarded, so new records are appended after
+    /// the last complete one.  A store that is corrupt, or in a format this version can't read,
+    /// is first copied aside to `<path>.bak`; a corrupt one keeps the history before the
+    /// corruption, while one in another format is started afresh.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(HistoryStore, CommandHistory)> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = open_private(
+            &path,
+            OpenOptions::new().read(true).append(true).create(true),
+        )?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut history = CommandHistory::new();
+        if let Some(next_index) = read_header(&data) {
+            history.next_index = next_index;
+        } else {
+            if !data.is_empty() {
+                let backup = with_suffix(&path, ".bak");
+                log!(
+                    Warn,
+                    "History isn't in a format this version can read; starting afresh and keeping \
+                     a copy in {}",
+                    backup.display()
+                );
+                fs::copy(&path, &backup)?;
+                file.set_len(0)?;
+            }
+            data = header(history.next_index);
+            file.write_all(&data)?;
+        }
+        let len = data.len() as u64;
+
+        let mut reader = Cursor::new(data);
+        reader.set_position(HEADER_LEN as u64);
+        let mut valid = reader.position();
+        let mut seq = 0;
+        while valid < len {
+            match decode::from_read::<_, ServerMessage>(&mut reader) {
+                Ok(msg) => {
+                    seq = seq.max(msg.seq);
+                    history.do_update(msg);
+                    valid = reader.position();
+                }
+                Err(ref e) if is_incomplete(e) => {
+                    log!(
+                        Warn,
+                        "Discarding incomplete history record at byte {}",
+                        valid
+                    );
+                    file.set_len(valid)?;
+                    break;
+                }
+                Err(e) => {
+                    let backup = with_suffix(&path, ".bak");
+                    log!(
+                        Warn,
+                        "Discarding corrupt history after byte {}: {}; kept a copy in {}",
+                        valid,
+                        e,
+                        backup.display()
+                    );
+                    fs::copy(&path, &backup)?;
+                    file.set_len(valid)?;
+                    break;
+                }
+            }
+        }
+
+        // Anything still running when the previous daemon exited was never reaped by us.
+        for cmd in history.commands.values_mut() {
+            if cmd.status.is_none() {
+                cmd.status = Some(ExitStatus::Lost);
+            }
+        }
+
+        let writer = BufWriter::new(file);
+        let store = HistoryStore {
+            path,
+            writer,
+            len: valid,
+            seq,
+        };
+        Ok((store, history))
+    }
+
+    /// The size of the store on disk, including buffered writes.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// The sequence number of the last message in the store.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn append(&mut self, msg: &ServerMessage) -> io::Result<()> {
+        let data = encode::to_vec(msg).map_err(io::Error::other)?;
+        self.writer.write_all(&data)?;
+        self.len += data.len() as u64;
+        self.seq = msg.seq;
+        Ok(())
+    }
+
+    /// Replaces everything in the store with a snapshot of `history`.
+    pub fn compact(&mut self, history: &CommandHistory) -> io::Result<()> {
+        self.writer.flush()?;
+        let mut snapshot = ServerMessage::new(
+            0,
+            ServerNotice::CommandsBulk(history.clone().into_iter().collect()),
+        );
+        snapshot.seq = self.seq;
+        let mut data = header(history.next_index);
+        encode::write(&mut data, &snapshot).map_err(io::Error::other)?;
+
+        let tmp_path = with_suffix(&self.path, ".tmp");
+        let mut tmp = open_private(
+            &tmp_path,
+            OpenOptions::new().write(true).create(true).truncate(true),
+        )?;
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.len = data.len() as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+    use std::time::SystemTime;
+    use ExitStatus::*;
+
+    /// A path for a test's store, with nothing left there by a previous run.
+    fn store_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("weaver-store-{}-{}", process::id(), name));
+        for suffix in &["", ".bak", ".tmp"] {
+            let _ = fs::remove_file(with_suffix(&path, suffix));
+        }
+        path
+    }
+
+    fn message(seq: u64, notice: ServerNotice) -> ServerMessage {
+        let mut msg = ServerMessage::new(0, notice);
+        msg.seq = seq;
+        msg
+    }
+
+    /// Writes two commands, the second of which never completes, and returns the store's size
+    /// after the first.
+    fn write_history(path: &Path) -> u64 {
+        let now = SystemTime::now();
+        let (mut store, _) = HistoryStore::open(path).unwrap();
+        store
+            .append(&message(
+                1,
+                ServerNotice::CommandStarted(1, "true".to_owned(), now),
+            ))
+            .unwrap();
+        store
+            .append(&message(
+                2,
+                ServerNotice::CommandCompleted(1, Exited(0), now, None),
+            ))
+            .unwrap();
+        let len = store.size();
+        store
+            .append(&message(
+                3,
+                ServerNotice::CommandStarted(2, "sleep 1".to_owned(), now),
+            ))
+            .unwrap();
+        store.flush().unwrap();
+        len
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn replays_the_history_written_to_it() {
+        let path = store_path("replay");
+        write_history(&path);
+        let (store, history) = HistoryStore::open(&path).unwrap();
+        assert_eq!(store.seq(), 3);
+        assert_eq!(store.size(), fs::metadata(&path).unwrap().len());
+        assert_eq!(history.commands[&1].status, Some(Exited(0)));
+        assert_eq!(history.commands[&2].status, Some(Lost));
+        assert_eq!(mode(&path), 0o600);
+        assert!(!with_suffix(&path, ".bak").exists());
+    }
+
+    #[test]
+    fn discards_a_partially_written_record() {
+        let path = store_path("partial");
+        let complete = write_history(&path);
+        let len = fs::metadata(&path).unwrap().len();
+        for end in complete + 1..len {
+            File::create(&path).unwrap();
+            write_history(&path);
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_len(end)
+                .unwrap();
+            let (store, history) = HistoryStore::open(&path).unwrap();
+            assert_eq!(store.size(), complete);
+            assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+            assert_eq!(history.commands.keys().collect::<Vec<_>>(), vec![&1]);
+            assert!(!with_suffix(&path, ".bak").exists());
+        }
+    }
+
+    #[test]
+    fn keeps_a_copy_of_a_corrupt_store() {
+        let path = store_path("corrupt");
+        let complete = write_history(&path);
+        let mut data = fs::read(&path).unwrap();
+        data[complete as usize] = 0xc1;
+        fs::write(&path, &data).unwrap();
+        let (mut store, history) = HistoryStore::open(&path).unwrap();
+        assert_eq!(fs::read(with_suffix(&path, ".bak")).unwrap(), data);
+        assert_eq!(store.size(), complete);
+        assert_eq!(history.commands.keys().collect::<Vec<_>>(), vec![&1]);
+
+        let now = SystemTime::now();
+        store
+            .append(&message(
+                4,
+                ServerNotice::CommandStarted(3, "ls".to_owned(), now),
+            ))
+            .unwrap();
+        store.flush().unwrap();
+        let (_, history) = HistoryStore::open(&path).unwrap();
+        assert_eq!(history.commands.keys().collect::<Vec<_>>(), vec![&1, &3]);
+    }
+
+    #[test]
+    fn starts_afresh_from_a_store_in_another_format() {
+        let path = store_path("format");
+        let mut data = header(1);
+        data[MAGIC.len()] = STORE_VERSION + 1;
+        data.extend_from_slice(b"from the future");
+        fs::write(&path, &data).unwrap();
+        let (store, history) = HistoryStore::open(&path).unwrap();
+        assert!(history.commands.is_empty());
+        assert_eq!(store.size(), HEADER_LEN as u64);
+        assert_eq!(fs::read(&path).unwrap(), header(1));
+        assert_eq!(fs::read(with_suffix(&path, ".bak")).unwrap(), data);
+    }
+
+    #[test]
+    fn compacting_keeps_the_history() {
+        let path = store_path("compact");
+        write_history(&path);
+        let (mut store, history) = HistoryStore::open(&path).unwrap();
+        store.compact(&history).unwrap();
+        assert_eq!(store.size(), fs::metadata(&path).unwrap().len());
+        assert_eq!(mode(&path), 0o600);
+        drop(store);
+        let (store, compacted) = HistoryStore::open(&path).unwrap();
+        assert_eq!(store.seq(), 3);
+        assert_eq!(compacted.commands, history.commands);
+    }
+
+    #[test]
+    fn ids_of_expired_commands_are_not_reused_after_compacting() {
+        let path = store_path("expired");
+        write_history(&path);
+        let (mut store, mut history) = HistoryStore::open(&path).unwrap();
+        let expired = message(4, ServerNotice::CommandsExpired(vec![2]));
+        store.append(&expired).unwrap();
+        history.do_update(expired);
+        store.compact(&history).unwrap();
+        drop(store);
+        let (_, mut compacted) = HistoryStore::open(&path).unwrap();
+        assert_eq!(compacted.commands.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(compacted.next_index(), 3);
+    }
+}
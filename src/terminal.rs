@@ -0,0 +1,441 @@
+//! A minimal VT100-ish terminal emulator, for displaying the output of commands run in a pty.
+//!
+//! Only the subset of control sequences needed to lay out text is understood: cursor motion,
+//! erasing, line insertion/deletion and scrolling.  Attributes such as colors are discarded.
+
+use super::encoding::Utf8Decoder;
+use super::WindowSize;
+
+use std::cmp::min;
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    Charset,
+    Csi(String),
+    Osc,
+    OscEscape,
+}
+
+#[derive(Clone, Debug)]
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+    state: State,
+}
+
+impl Screen {
+    pub fn new(size: WindowSize) -> Self {
+        let rows = (size.rows as usize).max(1);
+        let cols = (size.cols as usize).max(1);
+        Screen {
+            rows,
+            cols,
+            cells: vec![vec![' '; cols]; rows],
+            row: 0,
+            col: 0,
+            state: State::Ground,
+        }
+    }
+
+    /// The screen contents, with trailing blanks and trailing empty rows removed.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .cells
+            .iter()
+            .map(|row| row.iter().collect::<String>().trim_end().to_owned())
+            .collect();
+        while lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        lines
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    /// Changes the size of the screen, keeping what's on it.  Like most terminals, rows are
+    /// dropped from the top when it shrinks, so that the cursor stays on the screen.
+    pub fn resize(&mut self, size: WindowSize) {
+        let rows = (size.rows as usize).max(1);
+        let cols = (size.cols as usize).max(1);
+        for line in &mut self.cells {
+            line.resize(cols, ' ');
+        }
+        let dropped = (self.row + 1).saturating_sub(rows);
+        self.cells.drain(..dropped);
+        self.cells.resize(rows, vec![' '; cols]);
+        self.rows = rows;
+        self.cols = cols;
+        self.row -= dropped;
+        self.col = min(self.col, cols);
+    }
+
+    pub fn process(&mut self, text: &str) {
+        for c in text.chars() {
+            let state = ::std::mem::replace(&mut self.state, State::Ground);
+            self.state = match state {
+                State::Ground => self.ground(c),
+                State::Escape => self.escape(c),
+                State::Charset => State::Ground,
+                State::Csi(mut params) => match c {
+                    c if ('\x40'..='\x7e').contains(&c) => {
+                        self.csi(&params, c);
+                        State::Ground
+                    }
+                    '\x18' | '\x1a' => State::Ground,
+                    '\x1b' => State::Escape,
+                    _ => {
+                        params.push(c);
+                        State::Csi(params)
+                    }
+                },
+                State::Osc => match c {
+                    '\x07' => State::Ground,
+                    '\x1b' => State::OscEscape,
+                    _ => State::Osc,
+                },
+                State::OscEscape => match c {
+                    '\\' => State::Ground,
+                    _ => State::Osc,
+                },
+            };
+        }
+    }
+
+    fn ground(&mut self, c: char) -> State {
+        match c {
+            '\x1b' => return State::Escape,
+            '\r' => self.col = 0,
+            '\n' | '\x0b' | '\x0c' => self.line_feed(),
+            '\x08' => self.col = self.col.saturating_sub(1),
+            '\t' => self.col = min((self.col / 8 + 1) * 8, self.cols - 1),
+            c if c < ' ' || c == '\x7f' => {}
+            c => self.put(c),
+        }
+        State::Ground
+    }
+
+    fn escape(&mut self, c: char) -> State {
+        match c {
+            '[' => return State::Csi(String::new()),
+            ']' => return State::Osc,
+            '(' | ')' | '*' | '+' => return State::Charset,
+            'D' => self.line_feed(),
+            'E' => {
+                self.col = 0;
+                self.line_feed();
+            }
+            'M' => self.reverse_line_feed(),
+            'c' => {
+                *self = Screen::new(WindowSize {
+                    rows: self.rows as u16,
+                    cols: self.cols as u16,
+                })
+            }
+            _ => {}
+        }
+        State::Ground
+    }
+
+    fn put(&mut self, c: char) {
+        if self.col >= self.cols {
+            self.col = 0;
+            self.line_feed();
+        }
+        self.cells[self.row][self.col] = c;
+        self.col += 1;
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 >= self.rows {
+            self.cells.remove(0);
+            self.cells.push(vec![' '; self.cols]);
+        } else {
+            self.row += 1;
+        }
+    }
+
+    fn reverse_line_feed(&mut self) {
+        if self.row == 0 {
+            self.cells.pop();
+            self.cells.insert(0, vec![' '; self.cols]);
+        } else {
+            self.row -= 1;
+        }
+    }
+
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
+        let to = min(to, self.cols);
+        for cell in &mut self.cells[row][from.min(to)..to] {
+            *cell = ' ';
+        }
+    }
+
+    fn csi(&mut self, params: &str, action: char) {
+        // Private mode sequences such as `?25l` are prefixed, and none of them affect layout
+        // except for the alternate screen, which is treated as a fresh screen.
+        if let Some(private) = params.strip_prefix('?') {
+            if private.split(';').any(|p| p == "1049" || p == "47") {
+                self.csi("2", 'J');
+                self.row = 0;
+                self.col = 0;
+            }
+            return;
+        }
+        let args: Vec<usize> = params.split(';').map(|p| p.parse().unwrap_or(0)).collect();
+        let arg = |i: usize, default: usize| match args.get(i) {
+            Some(&0) | None => default,
+            Some(&n) => n,
+        };
+        let (rows, cols) = (self.rows, self.cols);
+        match action {
+            'A' => self.row = self.row.saturating_sub(arg(0, 1)),
+            'B' | 'e' => self.row = min(self.row + arg(0, 1), rows - 1),
+            'C' | 'a' => self.col = min(self.col + arg(0, 1), cols - 1),
+            'D' => self.col = min(self.col, cols - 1).saturating_sub(arg(0, 1)),
+            'E' => {
+                self.row = min(self.row + arg(0, 1), rows - 1);
+                self.col = 0;
+            }
+            'F' => {
+                self.row = self.row.saturating_sub(arg(0, 1));
+                self.col = 0;
+            }
+            'G' | '`' => self.col = min(arg(0, 1), cols) - 1,
+            'd' => self.row = min(arg(0, 1), rows) - 1,
+            'H' | 'f' => {
+                self.row = min(arg(0, 1), rows) - 1;
+                self.col = min(arg(1, 1), cols) - 1;
+            }
+            'J' => {
+                let (row, col) = (self.row, self.col);
+                match args.first().cloned().unwrap_or(0) {
+                    0 => {
+                        self.erase(row, col, cols);
+                        for r in row + 1..rows {
+                            self.erase(r, 0, cols);
+                        }
+                    }
+                    1 => {
+                        for r in 0..row {
+                            self.erase(r, 0, cols);
+                        }
+                        self.erase(row, 0, col + 1);
+                    }
+                    _ => {
+                        for r in 0..rows {
+                            self.erase(r, 0, cols);
+                        }
+                    }
+                }
+            }
+            'K' => {
+                let (row, col) = (self.row, self.col);
+                match args.first().cloned().unwrap_or(0) {
+                    0 => self.erase(row, col, cols),
+                    1 => self.erase(row, 0, col + 1),
+                    _ => self.erase(row, 0, cols),
+                }
+            }
+            'L' => {
+                for _ in 0..min(arg(0, 1), rows - self.row) {
+                    self.cells.pop();
+                    self.cells.insert(self.row, vec![' '; cols]);
+                }
+            }
+            'M' => {
+                for _ in 0..min(arg(0, 1), rows - self.row) {
+                    self.cells.remove(self.row);
+                    self.cells.push(vec![' '; cols]);
+                }
+            }
+            'S' => {
+                for _ in 0..min(arg(0, 1), rows) {
+                    self.cells.remove(0);
+                    self.cells.push(vec![' '; cols]);
+                }
+            }
+            'T' => {
+                for _ in 0..min(arg(0, 1), rows) {
+                    self.cells.pop();
+                    self.cells.insert(0, vec![' '; cols]);
+                }
+            }
+            'P' => {
+                let col = min(self.col, cols - 1);
+                let line = &mut self.cells[self.row];
+                for _ in 0..min(arg(0, 1), cols - col) {
+                    line.remove(col);
+                    line.push(' ');
+                }
+            }
+            '@' => {
+                let col = min(self.col, cols - 1);
+                let line = &mut self.cells[self.row];
+                for _ in 0..min(arg(0, 1), cols - col) {
+                    line.pop();
+                    line.insert(col, ' ');
+                }
+            }
+            'X' => {
+                let (row, col) = (self.row, self.col);
+                self.erase(row, col, col + arg(0, 1));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A screen kept up to date with a stream of output as it grows, processing only what's new each
+/// time.
+#[derive(Debug)]
+pub struct Terminal {
+    screen: Screen,
+    decoder: Utf8Decoder,
+    processed: usize,
+}
+
+impl Terminal {
+    pub fn new(size: WindowSize) -> Self {
+        Terminal {
+            screen: Screen::new(size),
+            decoder: Utf8Decoder::default(),
+            processed: 0,
+        }
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    /// How many bytes of output have been processed.
+    pub fn processed(&self) -> usize {
+        self.processed
+    }
+
+    pub fn resize(&mut self, size: WindowSize) {
+        if size.rows as usize != self.screen.rows || size.cols as usize != self.screen.cols {
+            self.screen.resize(size);
+        }
+    }
+
+    /// Processes the rest of `output`, which starts with all of the output processed so far.
+    pub fn update(&mut self, output: &[u8]) {
+        if output.len() <= self.processed {
+            return;
+        }
+        let text = self.decoder.push(output[self.processed..].to_vec());
+        self.screen.process(&String::from_utf8_lossy(&text));
+        self.processed = output.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(rows: u16, cols: u16) -> WindowSize {
+        WindowSize { rows, cols }
+    }
+
+    fn process(rows: u16, cols: u16, text: &str) -> Screen {
+        let mut screen = Screen::new(size(rows, cols));
+        screen.process(text);
+        screen
+    }
+
+    #[test]
+    fn lines_wrap_at_the_edge_of_the_screen() {
+        let screen = process(3, 4, "abcdef\r\nxy");
+        assert_eq!(screen.lines(), vec!["abcd", "ef", "xy"]);
+        assert_eq!(screen.cursor(), (2, 2));
+    }
+
+    #[test]
+    fn carriage_returns_overwrite_the_line() {
+        let screen = process(2, 10, "hello\rj\n\rbye");
+        assert_eq!(screen.lines(), vec!["jello", "bye"]);
+    }
+
+    #[test]
+    fn line_feeds_scroll_at_the_bottom() {
+        let screen = process(2, 5, "one\r\ntwo\r\nthree");
+        assert_eq!(screen.lines(), vec!["two", "three"]);
+        assert_eq!(screen.cursor(), (1, 5));
+    }
+
+    #[test]
+    fn cursor_positioning_and_erasing() {
+        let screen = process(3, 5, "aaaaa\r\nbbbbb\r\nccccc\x1b[2;3H\x1b[K");
+        assert_eq!(screen.lines(), vec!["aaaaa", "bb", "ccccc"]);
+        let screen = process(3, 5, "aaaaa\r\nbbbbb\r\nccccc\x1b[2;3H\x1b[J");
+        assert_eq!(screen.lines(), vec!["aaaaa", "bb"]);
+        let screen = process(3, 5, "aaaaa\r\nbbbbb\r\nccccc\x1b[2;3H\x1b[1J");
+        assert_eq!(screen.lines(), vec!["", "   bb", "ccccc"]);
+        let screen = process(3, 5, "aaaaa\r\nbbbbb\x1b[2J\x1b[Hx");
+        assert_eq!(screen.lines(), vec!["x"]);
+    }
+
+    #[test]
+    fn scrolling_and_line_insertion() {
+        let screen = process(3, 5, "a\r\nb\r\nc\x1b[S");
+        assert_eq!(screen.lines(), vec!["b", "c"]);
+        let screen = process(3, 5, "a\r\nb\r\nc\x1b[T");
+        assert_eq!(screen.lines(), vec!["", "a", "b"]);
+        let screen = process(3, 5, "a\r\nb\r\nc\x1b[2H\x1b[L");
+        assert_eq!(screen.lines(), vec!["a", "", "b"]);
+        let screen = process(3, 5, "a\r\nb\r\nc\x1b[2H\x1b[M");
+        assert_eq!(screen.lines(), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn attributes_and_titles_are_discarded() {
+        let screen = process(2, 10, "\x1b]0;title\x07\x1b[1;31mred\x1b[0m\x1b(B!");
+        assert_eq!(screen.lines(), vec!["red!"]);
+    }
+
+    #[test]
+    fn the_alternate_screen_starts_empty() {
+        let screen = process(3, 10, "shell\r\n$ \x1b[?1049hmenu");
+        assert_eq!(screen.lines(), vec!["menu"]);
+    }
+
+    #[test]
+    fn resizing_keeps_the_cursor_on_the_screen() {
+        let mut screen = process(3, 5, "a\r\nb\r\nc");
+        screen.resize(size(2, 3));
+        assert_eq!(screen.lines(), vec!["b", "c"]);
+        assert_eq!(screen.cursor(), (1, 1));
+        screen.resize(size(4, 8));
+        screen.process("\r\nlonger");
+        assert_eq!(screen.lines(), vec!["b", "c", "longer"]);
+        screen.resize(size(4, 2));
+        assert_eq!(screen.lines(), vec!["b", "c", "lo"]);
+        assert_eq!(screen.cursor(), (2, 2));
+    }
+
+    #[test]
+    fn terminals_process_only_new_output() {
+        let output = "caf\u{e9} \x1b[1mbold\x1b[0m\r\n\u{2603}".as_bytes();
+        let mut whole = Screen::new(size(3, 10));
+        whole.process(&String::from_utf8_lossy(output));
+        let mut terminal = Terminal::new(size(3, 10));
+        for end in 1..output.len() + 1 {
+            terminal.update(&output[..end]);
+            assert_eq!(terminal.processed(), end);
+        }
+        assert_eq!(terminal.screen().lines(), whole.lines());
+        assert_eq!(
+            terminal.screen().lines(),
+            vec!["caf\u{e9} bold", "\u{2603}"]
+        );
+        terminal.update(output);
+        assert_eq!(terminal.screen().lines(), whole.lines());
+    }
+}